@@ -0,0 +1,394 @@
+use std::io::ErrorKind;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+use software_defined_hive::state::traits::{HumiditySensor, PressureSensor, SensorError, TemperatureSensor};
+
+use crate::drivers::bus_error;
+
+/// I2C address with SDO pulled low, 0x77 when it is pulled high
+pub const BME280_DEFAULT_ADDRESS: u8 = 0x76;
+
+const CHIP_ID: u8 = 0x60;
+
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_CALIBRATION_TP: u8 = 0x88;
+const REG_CALIBRATION_H: u8 = 0xE1;
+const REG_CTRL_HUM: u8 = 0xF2;
+const REG_STATUS: u8 = 0xF3;
+const REG_CTRL_MEAS: u8 = 0xF4;
+const REG_DATA: u8 = 0xF7;
+
+/// Humidity oversampling x1
+const CTRL_HUM_OVERSAMPLING_X1: u8 = 0x01;
+/// Temperature and pressure oversampling x1, forced mode (one measurement, then sleep)
+const CTRL_MEAS_FORCED_X1: u8 = 0b0010_0101;
+const STATUS_MEASURING: u8 = 0x08;
+
+/// Typical measurement time with x1 oversampling of all three quantities
+const MEASUREMENT_TIME_MS: u32 = 10;
+const MAX_STATUS_POLLS: u8 = 10;
+
+/// The ADC value reported for a quantity that was not measured
+const SKIPPED_20_BIT: i32 = 0x80000;
+const SKIPPED_16_BIT: i32 = 0x8000;
+
+/// Factory trimming parameters stored in the sensor's NVM
+#[derive(Debug, Clone, Copy, Default)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+    h1: u8,
+    h2: i16,
+    h3: u8,
+    h4: i16,
+    h5: i16,
+    h6: i8,
+}
+
+/// A compensated measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bme280Measurement {
+    /// °C * 10
+    pub temperature_x10: i16,
+    /// % * 10
+    pub humidity_x10: u16,
+    /// Pascals
+    pub pressure_pa: u32,
+}
+
+/// BME280 I2C temperature, humidity and pressure sensor. The BME280 has no CRC, instead the chip ID is validated on creation
+/// and measurements the sensor skipped are rejected.
+///
+/// To use one chip for several quantities (temperature, humidity, pressure) of `SensorDataAggregator`, give one instance per quantity
+/// an `I2c` implementation that locks the same bus, e.g. behind a `Mutex` (the aggregator needs `Send` drivers)
+pub struct Bme280<I2C, Delay> {
+    i2c: I2C,
+    address: u8,
+    delay: Delay,
+    calibration: Calibration,
+}
+
+impl<I2C, Delay> Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    /// Validates the chip ID and loads the calibration, fails if the device is not a BME280 (e.g. a BMP280 which has no humidity sensor)
+    pub fn new(i2c: I2C, address: u8, delay: Delay) -> Result<Self, SensorError> {
+        let mut bme280 = Self {
+            i2c,
+            address,
+            delay,
+            calibration: Calibration::default(),
+        };
+
+        let mut chip_id = [0u8; 1];
+        bme280.read_registers(REG_CHIP_ID, &mut chip_id)?;
+        if chip_id[0] != CHIP_ID {
+            return Err(SensorError::new(
+                ErrorKind::InvalidData,
+                format!("Unexpected BME280 chip ID 0x{:02X}", chip_id[0]),
+            ));
+        }
+
+        bme280.calibration = bme280.read_calibration()?;
+        Ok(bme280)
+    }
+
+    pub fn measure(&mut self) -> Result<Bme280Measurement, SensorError> {
+        self.write_register(REG_CTRL_HUM, CTRL_HUM_OVERSAMPLING_X1)?;
+        self.write_register(REG_CTRL_MEAS, CTRL_MEAS_FORCED_X1)?;
+        self.delay.delay_ms(MEASUREMENT_TIME_MS);
+
+        let mut polls = 0;
+        loop {
+            let mut status = [0u8; 1];
+            self.read_registers(REG_STATUS, &mut status)?;
+
+            if status[0] & STATUS_MEASURING == 0 {
+                break;
+            }
+
+            polls += 1;
+            if polls >= MAX_STATUS_POLLS {
+                return Err(SensorError::new(ErrorKind::TimedOut, "BME280 measurement timed out"));
+            }
+            self.delay.delay_ms(1);
+        }
+
+        let mut data = [0u8; 8];
+        self.read_registers(REG_DATA, &mut data)?;
+
+        let adc_p = ((data[0] as i32) << 12) | ((data[1] as i32) << 4) | ((data[2] as i32) >> 4);
+        let adc_t = ((data[3] as i32) << 12) | ((data[4] as i32) << 4) | ((data[5] as i32) >> 4);
+        let adc_h = ((data[6] as i32) << 8) | data[7] as i32;
+
+        if adc_t == SKIPPED_20_BIT || adc_p == SKIPPED_20_BIT || adc_h == SKIPPED_16_BIT {
+            return Err(SensorError::new(ErrorKind::InvalidData, "BME280 skipped a measurement"));
+        }
+
+        let t_fine = self.t_fine(adc_t);
+        // 0.01 °C
+        let temperature_x100 = (t_fine * 5 + 128) >> 8;
+
+        Ok(Bme280Measurement {
+            temperature_x10: (temperature_x100 / 10) as i16,
+            // %RH in Q22.10
+            humidity_x10: (self.compensate_humidity(adc_h, t_fine) as u64 * 10 / 1024) as u16,
+            // Pa in Q24.8
+            pressure_pa: self.compensate_pressure(adc_p, t_fine) / 256,
+        })
+    }
+
+    fn read_calibration(&mut self) -> Result<Calibration, SensorError> {
+        let mut tp = [0u8; 26];
+        self.read_registers(REG_CALIBRATION_TP, &mut tp)?;
+        let mut h = [0u8; 7];
+        self.read_registers(REG_CALIBRATION_H, &mut h)?;
+
+        let u16_at = |bytes: &[u8], index: usize| u16::from_le_bytes([bytes[index], bytes[index + 1]]);
+        let i16_at = |bytes: &[u8], index: usize| i16::from_le_bytes([bytes[index], bytes[index + 1]]);
+
+        Ok(Calibration {
+            t1: u16_at(&tp, 0),
+            t2: i16_at(&tp, 2),
+            t3: i16_at(&tp, 4),
+            p1: u16_at(&tp, 6),
+            p2: i16_at(&tp, 8),
+            p3: i16_at(&tp, 10),
+            p4: i16_at(&tp, 12),
+            p5: i16_at(&tp, 14),
+            p6: i16_at(&tp, 16),
+            p7: i16_at(&tp, 18),
+            p8: i16_at(&tp, 20),
+            p9: i16_at(&tp, 22),
+            h1: tp[25],
+            h2: i16_at(&h, 0),
+            h3: h[2],
+            // H4 and H5 are 12-bit values sharing a nibble in 0xE5
+            h4: ((h[3] as i8 as i16) << 4) | (h[4] & 0x0F) as i16,
+            h5: ((h[5] as i8 as i16) << 4) | (h[4] >> 4) as i16,
+            h6: h[6] as i8,
+        })
+    }
+
+    // The compensation formulas are the integer versions from the BME280 datasheet (section 4.2.3)
+
+    fn t_fine(&self, adc_t: i32) -> i32 {
+        let c = &self.calibration;
+        let var1 = (((adc_t >> 3) - ((c.t1 as i32) << 1)) * c.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - c.t1 as i32) * ((adc_t >> 4) - c.t1 as i32)) >> 12) * c.t3 as i32) >> 14;
+        var1 + var2
+    }
+
+    fn compensate_pressure(&self, adc_p: i32, t_fine: i32) -> u32 {
+        let c = &self.calibration;
+        let mut var1 = t_fine as i64 - 128000;
+        let mut var2 = var1 * var1 * c.p6 as i64;
+        var2 += (var1 * c.p5 as i64) << 17;
+        var2 += (c.p4 as i64) << 35;
+        var1 = ((var1 * var1 * c.p3 as i64) >> 8) + ((var1 * c.p2 as i64) << 12);
+        var1 = (((1i64 << 47) + var1) * c.p1 as i64) >> 33;
+
+        if var1 == 0 {
+            // Avoid a division by zero
+            return 0;
+        }
+
+        let mut p = 1048576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        let var1 = (c.p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        let var2 = (c.p8 as i64 * p) >> 19;
+        (((p + var1 + var2) >> 8) + ((c.p7 as i64) << 4)) as u32
+    }
+
+    fn compensate_humidity(&self, adc_h: i32, t_fine: i32) -> u32 {
+        let c = &self.calibration;
+        let mut v = t_fine - 76800;
+        v = ((((adc_h << 14) - ((c.h4 as i32) << 20) - (c.h5 as i32 * v)) + 16384) >> 15)
+            * (((((((v * c.h6 as i32) >> 10) * (((v * c.h3 as i32) >> 11) + 32768)) >> 10) + 2097152)
+                * c.h2 as i32
+                + 8192)
+                >> 14);
+        v -= ((((v >> 15) * (v >> 15)) >> 7) * c.h1 as i32) >> 4;
+        (v.clamp(0, 419430400) >> 12) as u32
+    }
+
+    fn read_registers(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), SensorError> {
+        self.i2c
+            .write_read(self.address, &[register], buffer)
+            .map_err(bus_error)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), SensorError> {
+        self.i2c.write(self.address, &[register, value]).map_err(bus_error)
+    }
+}
+
+impl<I2C, Delay> TemperatureSensor for Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn read_celsius_x10(&mut self) -> Result<i16, SensorError> {
+        Ok(self.measure()?.temperature_x10)
+    }
+}
+
+impl<I2C, Delay> HumiditySensor for Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn read_percent_x10(&mut self) -> Result<u16, SensorError> {
+        Ok(self.measure()?.humidity_x10)
+    }
+}
+
+impl<I2C, Delay> PressureSensor for Bme280<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn read_hpa_x10(&mut self) -> Result<u16, SensorError> {
+        // 1 hPa = 100 Pa
+        Ok((self.measure()?.pressure_pa / 10) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::i2c::{Mock as I2cMock, Transaction};
+
+    use super::*;
+
+    const ADDRESS: u8 = BME280_DEFAULT_ADDRESS;
+
+    // Temperature and pressure trimming of the datasheet's example (BMP280 datasheet, section 3.12)
+    const T: [i32; 3] = [27504, 26435, -1000];
+    const P: [i32; 9] = [36477, -10685, 3024, 2855, 140, -7, 15500, -14600, 6000];
+    // Humidity trimming of a sensor in use: H1, H2, H3, H4, H5, H6
+    const H: [i32; 6] = [75, 362, 0, 313, 50, 30];
+
+    fn calibration_registers() -> (Vec<u8>, Vec<u8>) {
+        let mut tp: Vec<u8> = T.iter().chain(P.iter()).flat_map(|&value| (value as u16).to_le_bytes()).collect();
+        tp.extend([0, H[0] as u8]);
+
+        let [h2_lsb, h2_msb] = (H[1] as i16).to_le_bytes();
+        let h = vec![
+            h2_lsb,
+            h2_msb,
+            H[2] as u8,
+            (H[3] >> 4) as u8,
+            ((H[3] & 0x0F) | ((H[4] & 0x0F) << 4)) as u8,
+            (H[4] >> 4) as u8,
+            H[5] as u8,
+        ];
+        (tp, h)
+    }
+
+    fn creation() -> Vec<Transaction> {
+        let (tp, h) = calibration_registers();
+        vec![
+            Transaction::write_read(ADDRESS, vec![REG_CHIP_ID], vec![CHIP_ID]),
+            Transaction::write_read(ADDRESS, vec![REG_CALIBRATION_TP], tp),
+            Transaction::write_read(ADDRESS, vec![REG_CALIBRATION_H], h),
+        ]
+    }
+
+    fn measurement(adc_p: i32, adc_t: i32, adc_h: i32, busy_polls: usize) -> Vec<Transaction> {
+        let data = vec![
+            (adc_p >> 12) as u8,
+            (adc_p >> 4) as u8,
+            ((adc_p & 0x0F) << 4) as u8,
+            (adc_t >> 12) as u8,
+            (adc_t >> 4) as u8,
+            ((adc_t & 0x0F) << 4) as u8,
+            (adc_h >> 8) as u8,
+            adc_h as u8,
+        ];
+
+        let mut transactions = vec![
+            Transaction::write(ADDRESS, vec![REG_CTRL_HUM, CTRL_HUM_OVERSAMPLING_X1]),
+            Transaction::write(ADDRESS, vec![REG_CTRL_MEAS, CTRL_MEAS_FORCED_X1]),
+        ];
+        transactions.extend((0..busy_polls).map(|_| Transaction::write_read(ADDRESS, vec![REG_STATUS], vec![STATUS_MEASURING])));
+        transactions.push(Transaction::write_read(ADDRESS, vec![REG_STATUS], vec![0x00]));
+        transactions.push(Transaction::write_read(ADDRESS, vec![REG_DATA], data));
+        transactions
+    }
+
+    fn measure(transactions: &[Transaction]) -> Result<Bme280Measurement, SensorError> {
+        let mut i2c = I2cMock::new(transactions);
+        let measurement = Bme280::new(i2c.clone(), ADDRESS, NoopDelay::new()).and_then(|mut bme280| bme280.measure());
+        i2c.done();
+        measurement
+    }
+
+    /// The floating point humidity compensation of the datasheet (section 4.2.3)
+    fn reference_humidity_x10(adc_h: i32, t_fine: i32) -> f64 {
+        let [h1, h2, h3, h4, h5, h6] = H.map(f64::from);
+        let v = t_fine as f64 - 76800.0;
+        let v = (adc_h as f64 - (h4 * 64.0 + h5 / 16384.0 * v))
+            * (h2 / 65536.0 * (1.0 + h6 / 67108864.0 * v * (1.0 + h3 / 67108864.0 * v)));
+        let v = v * (1.0 - h1 * v / 524288.0);
+        v.clamp(0.0, 100.0) * 10.0
+    }
+
+    #[test]
+    fn compensates_the_datasheet_example() {
+        let measurement = measure(&[creation(), measurement(415148, 519888, 27000, 0)].concat()).unwrap();
+
+        // 25.08 °C and 100653.27 Pa
+        assert_eq!(measurement.temperature_x10, 250);
+        assert_eq!(measurement.pressure_pa, 100653);
+
+        // t_fine of the example is 128422
+        let expected = reference_humidity_x10(27000, 128422);
+        assert!(expected > 100.0 && expected < 900.0);
+        assert!((measurement.humidity_x10 as f64 - expected).abs() <= 1.0, "{} vs {}", measurement.humidity_x10, expected);
+    }
+
+    #[test]
+    fn waits_for_the_measurement() {
+        let measurement = measure(&[creation(), measurement(415148, 519888, 27000, 3)].concat()).unwrap();
+        assert_eq!(measurement.temperature_x10, 250);
+    }
+
+    #[test]
+    fn times_out_when_the_sensor_stays_busy() {
+        let mut transactions = creation();
+        transactions.push(Transaction::write(ADDRESS, vec![REG_CTRL_HUM, CTRL_HUM_OVERSAMPLING_X1]));
+        transactions.push(Transaction::write(ADDRESS, vec![REG_CTRL_MEAS, CTRL_MEAS_FORCED_X1]));
+        transactions.extend((0..MAX_STATUS_POLLS).map(|_| Transaction::write_read(ADDRESS, vec![REG_STATUS], vec![STATUS_MEASURING])));
+
+        assert_eq!(measure(&transactions).unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn rejects_another_chip() {
+        // A BMP280 has no humidity sensor
+        let transactions = [Transaction::write_read(ADDRESS, vec![REG_CHIP_ID], vec![0x58])];
+        assert_eq!(measure(&transactions).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_skipped_measurements() {
+        for (adc_p, adc_t, adc_h) in [(SKIPPED_20_BIT, 519888, 27000), (415148, SKIPPED_20_BIT, 27000), (415148, 519888, SKIPPED_16_BIT)] {
+            let transactions = [creation(), measurement(adc_p, adc_t, adc_h, 0)].concat();
+            assert_eq!(measure(&transactions).unwrap_err().kind(), ErrorKind::InvalidData);
+        }
+    }
+}
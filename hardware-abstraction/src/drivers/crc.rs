@@ -0,0 +1,44 @@
+/// CRC-8/MAXIM (Dallas 1-Wire) - polynomial x^8 + x^5 + x^4 + 1, reflected, initial value 0x00. Used by the DS18B20
+pub fn crc8_maxim(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x01 != 0 {
+                (crc >> 1) ^ 0x8C
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// CRC-8/NRSC-5 (Sensirion) - polynomial 0x31, initial value 0xFF. Used by the SHT3x
+pub fn crc8_sensirion(data: &[u8]) -> u8 {
+    data.iter().fold(0xFFu8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maxim_check_value() {
+        assert_eq!(crc8_maxim(b"123456789"), 0xA1);
+        // ROM code example of the Maxim application note 27, the CRC of a valid code is in its last byte
+        assert_eq!(crc8_maxim(&[0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00]), 0xA2);
+    }
+
+    #[test]
+    fn sensirion_check_value() {
+        assert_eq!(crc8_sensirion(b"123456789"), 0xF7);
+        // Example of the SHT3x datasheet
+        assert_eq!(crc8_sensirion(&[0xBE, 0xEF]), 0x92);
+    }
+}
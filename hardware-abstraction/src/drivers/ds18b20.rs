@@ -0,0 +1,182 @@
+use std::io::ErrorKind;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use software_defined_hive::state::traits::{SensorError, TemperatureSensor};
+
+use crate::drivers::crc::crc8_maxim;
+use crate::drivers::one_wire::OneWireBus;
+
+const SKIP_ROM: u8 = 0xCC;
+const MATCH_ROM: u8 = 0x55;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+
+/// Worst case conversion time at the default 12-bit resolution
+const CONVERSION_TIME_MS: u32 = 750;
+
+/// The temperature register holds 85 °C until a conversion completes, e.g. after a brown-out during the conversion
+const POWER_ON_RESET_RAW: i16 = 0x0550;
+
+/// DS18B20 1-Wire temperature probe e.g. for the internal (brood) and external temperature.
+///
+/// `rom` is the 64-bit ROM code of the probe, it is required when several probes share the same bus. With `None` the probe has to be alone on its bus
+pub struct Ds18b20<Pin, Delay> {
+    bus: OneWireBus<Pin, Delay>,
+    rom: Option<[u8; 8]>,
+}
+
+impl<Pin, Delay> Ds18b20<Pin, Delay>
+where
+    Pin: InputPin + OutputPin,
+    Delay: DelayNs,
+{
+    pub fn new(bus: OneWireBus<Pin, Delay>, rom: Option<[u8; 8]>) -> Self {
+        Self { bus, rom }
+    }
+
+    fn select(&mut self) -> Result<(), SensorError> {
+        self.bus.reset()?;
+
+        match self.rom {
+            Some(rom) => {
+                self.bus.write_byte(MATCH_ROM)?;
+                for byte in rom {
+                    self.bus.write_byte(byte)?;
+                }
+            }
+            None => self.bus.write_byte(SKIP_ROM)?,
+        }
+        Ok(())
+    }
+
+    fn read_scratchpad(&mut self) -> Result<[u8; 9], SensorError> {
+        self.select()?;
+        self.bus.write_byte(READ_SCRATCHPAD)?;
+
+        let mut scratchpad = [0u8; 9];
+        for byte in scratchpad.iter_mut() {
+            *byte = self.bus.read_byte()?;
+        }
+
+        if crc8_maxim(&scratchpad[..8]) != scratchpad[8] {
+            return Err(SensorError::new(ErrorKind::InvalidData, "DS18B20 scratchpad CRC mismatch"));
+        }
+        Ok(scratchpad)
+    }
+}
+
+impl<Pin, Delay> TemperatureSensor for Ds18b20<Pin, Delay>
+where
+    Pin: InputPin + OutputPin,
+    Delay: DelayNs,
+{
+    fn read_celsius_x10(&mut self) -> Result<i16, SensorError> {
+        self.select()?;
+        self.bus.write_byte(CONVERT_T)?;
+        self.bus.delay_ms(CONVERSION_TIME_MS);
+
+        let scratchpad = self.read_scratchpad()?;
+
+        // Two's complement in 1/16 °C
+        let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+        if raw == POWER_ON_RESET_RAW {
+            return Err(SensorError::new(ErrorKind::InvalidData, "DS18B20 reported its power-on reset value, the conversion did not run"));
+        }
+        Ok(sixteenths_to_x10(raw))
+    }
+}
+
+fn sixteenths_to_x10(raw: i16) -> i16 {
+    (raw as i32 * 10 / 16) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction};
+
+    use super::*;
+
+    fn reset(present: bool) -> Vec<Transaction> {
+        let presence = if present { State::Low } else { State::High };
+        vec![Transaction::set(State::Low), Transaction::set(State::High), Transaction::get(presence)]
+    }
+
+    /// The bits written only differ in their timing, which the mock does not check
+    fn write(_byte: u8) -> Vec<Transaction> {
+        (0..8).flat_map(|_| [Transaction::set(State::Low), Transaction::set(State::High)]).collect()
+    }
+
+    fn read(byte: u8) -> Vec<Transaction> {
+        (0..8)
+            .flat_map(|bit| {
+                let state = if byte & (1 << bit) != 0 { State::High } else { State::Low };
+                [Transaction::set(State::Low), Transaction::set(State::High), Transaction::get(state)]
+            })
+            .collect()
+    }
+
+    /// A conversion of a probe alone on its bus, answering with `scratchpad`
+    fn conversion(scratchpad: [u8; 9]) -> Vec<Transaction> {
+        let mut transactions = [reset(true), write(SKIP_ROM), write(CONVERT_T), reset(true), write(SKIP_ROM), write(READ_SCRATCHPAD)].concat();
+        transactions.extend(scratchpad.iter().flat_map(|&byte| read(byte)));
+        transactions
+    }
+
+    fn scratchpad(raw: i16) -> [u8; 9] {
+        let [lsb, msb] = raw.to_le_bytes();
+        let mut scratchpad = [lsb, msb, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, 0];
+        scratchpad[8] = crc8_maxim(&scratchpad[..8]);
+        scratchpad
+    }
+
+    fn read_celsius_x10(transactions: &[Transaction]) -> Result<i16, SensorError> {
+        let mut pin = PinMock::new(transactions);
+        let result = Ds18b20::new(OneWireBus::new(pin.clone(), NoopDelay::new()), None).read_celsius_x10();
+        pin.done();
+        result
+    }
+
+    #[test]
+    fn scales_sixteenths_to_tenths() {
+        // Examples of the datasheet
+        assert_eq!(read_celsius_x10(&conversion(scratchpad(0x0191))).unwrap(), 250);
+        assert_eq!(read_celsius_x10(&conversion(scratchpad(0xFF5Eu16 as i16))).unwrap(), -101);
+        assert_eq!(read_celsius_x10(&conversion(scratchpad(0x0008))).unwrap(), 5);
+    }
+
+    #[test]
+    fn rejects_a_scratchpad_crc_mismatch() {
+        let mut corrupted = scratchpad(0x0191);
+        corrupted[8] ^= 0x01;
+
+        let error = read_celsius_x10(&conversion(corrupted)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_the_power_on_reset_value() {
+        let error = read_celsius_x10(&conversion(scratchpad(POWER_ON_RESET_RAW))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fails_without_a_presence_pulse() {
+        let error = read_celsius_x10(&reset(false)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn addresses_the_probe_by_its_rom_code() {
+        let rom = [0x28, 0xFF, 0x4C, 0x60, 0x91, 0x16, 0x04, 0x5A];
+        let select = [reset(true), write(MATCH_ROM), rom.iter().flat_map(|&byte| write(byte)).collect()].concat();
+        let mut transactions = [select.clone(), write(CONVERT_T), select, write(READ_SCRATCHPAD)].concat();
+        transactions.extend(scratchpad(0x0191).iter().flat_map(|&byte| read(byte)));
+
+        let mut pin = PinMock::new(&transactions);
+        let celsius_x10 = Ds18b20::new(OneWireBus::new(pin.clone(), NoopDelay::new()), Some(rom)).read_celsius_x10();
+        pin.done();
+        assert_eq!(celsius_x10.unwrap(), 250);
+    }
+}
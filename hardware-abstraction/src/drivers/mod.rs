@@ -0,0 +1,14 @@
+pub mod crc;
+pub mod one_wire;
+pub mod ds18b20;
+pub mod sht3x;
+pub mod bme280;
+
+use std::fmt::Debug;
+
+use software_defined_hive::state::traits::SensorError;
+
+/// Wraps a bus (I2C, GPIO) error into the crate's sensor error
+pub(crate) fn bus_error<E: Debug>(error: E) -> SensorError {
+    SensorError::other(format!("Bus error: {:?}", error))
+}
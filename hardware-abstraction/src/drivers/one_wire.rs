@@ -0,0 +1,84 @@
+use std::io::ErrorKind;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::{InputPin, OutputPin};
+use software_defined_hive::state::traits::SensorError;
+
+use crate::drivers::bus_error;
+
+/// Bit-banged 1-Wire bus master (standard speed).
+///
+/// `pin` has to be an open-drain pin with a pull-up (4.7kΩ) i.e. setting it high releases the bus
+pub struct OneWireBus<Pin, Delay> {
+    pin: Pin,
+    delay: Delay,
+}
+
+impl<Pin, Delay> OneWireBus<Pin, Delay>
+where
+    Pin: InputPin + OutputPin,
+    Delay: DelayNs,
+{
+    pub fn new(pin: Pin, delay: Delay) -> Self {
+        Self { pin, delay }
+    }
+
+    pub fn delay_ms(&mut self, ms: u32) {
+        self.delay.delay_ms(ms);
+    }
+
+    /// Resets the bus, fails when no device answers with a presence pulse
+    pub fn reset(&mut self) -> Result<(), SensorError> {
+        self.pin.set_low().map_err(bus_error)?;
+        self.delay.delay_us(480);
+        self.pin.set_high().map_err(bus_error)?;
+        self.delay.delay_us(70);
+
+        let present = self.pin.is_low().map_err(bus_error)?;
+        self.delay.delay_us(410);
+
+        if present {
+            Ok(())
+        } else {
+            Err(SensorError::new(ErrorKind::NotConnected, "No 1-Wire device present"))
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) -> Result<(), SensorError> {
+        for bit in 0..8 {
+            self.write_bit(byte & (1 << bit) != 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, SensorError> {
+        let mut byte = 0u8;
+        for bit in 0..8 {
+            if self.read_bit()? {
+                byte |= 1 << bit;
+            }
+        }
+        Ok(byte)
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), SensorError> {
+        let (low_us, release_us) = if bit { (6, 64) } else { (60, 10) };
+
+        self.pin.set_low().map_err(bus_error)?;
+        self.delay.delay_us(low_us);
+        self.pin.set_high().map_err(bus_error)?;
+        self.delay.delay_us(release_us);
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, SensorError> {
+        self.pin.set_low().map_err(bus_error)?;
+        self.delay.delay_us(6);
+        self.pin.set_high().map_err(bus_error)?;
+        self.delay.delay_us(9);
+
+        let bit = self.pin.is_high().map_err(bus_error)?;
+        self.delay.delay_us(55);
+        Ok(bit)
+    }
+}
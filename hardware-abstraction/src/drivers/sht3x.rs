@@ -0,0 +1,161 @@
+use std::io::ErrorKind;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+use software_defined_hive::state::traits::{HumiditySensor, SensorError, TemperatureSensor};
+
+use crate::drivers::bus_error;
+use crate::drivers::crc::crc8_sensirion;
+
+/// I2C address with the ADDR pin pulled low, 0x45 when it is pulled high
+pub const SHT3X_DEFAULT_ADDRESS: u8 = 0x44;
+
+/// Single shot measurement, high repeatability, clock stretching disabled
+const MEASURE_HIGH_REPEATABILITY: [u8; 2] = [0x24, 0x00];
+
+/// Maximum measurement duration at high repeatability
+const MEASUREMENT_TIME_MS: u32 = 16;
+
+/// A temperature (°C * 10) and relative humidity (% * 10) measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sht3xMeasurement {
+    pub temperature_x10: i16,
+    pub humidity_x10: u16,
+}
+
+/// SHT3x (SHT30/31/35) I2C temperature and humidity sensor.
+///
+/// To use one chip as both the temperature and the humidity sensor of `SensorDataAggregator`, give two instances
+/// an `I2c` implementation that locks the same bus, e.g. behind a `Mutex` (the aggregator needs `Send` drivers)
+pub struct Sht3x<I2C, Delay> {
+    i2c: I2C,
+    address: u8,
+    delay: Delay,
+}
+
+impl<I2C, Delay> Sht3x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    pub fn new(i2c: I2C, address: u8, delay: Delay) -> Self {
+        Self { i2c, address, delay }
+    }
+
+    pub fn measure(&mut self) -> Result<Sht3xMeasurement, SensorError> {
+        self.i2c
+            .write(self.address, &MEASURE_HIGH_REPEATABILITY)
+            .map_err(bus_error)?;
+        self.delay.delay_ms(MEASUREMENT_TIME_MS);
+
+        let mut data = [0u8; 6];
+        self.i2c.read(self.address, &mut data).map_err(bus_error)?;
+
+        let raw_temperature = checked_word(&data[0..3])?;
+        let raw_humidity = checked_word(&data[3..6])?;
+
+        Ok(Sht3xMeasurement {
+            // T = -45 + 175 * raw / (2^16 - 1)
+            temperature_x10: (-450 + 1750 * raw_temperature as i32 / 65535) as i16,
+            // RH = 100 * raw / (2^16 - 1)
+            humidity_x10: (1000 * raw_humidity as u32 / 65535) as u16,
+        })
+    }
+}
+
+/// A 16-bit word followed by its CRC
+fn checked_word(data: &[u8]) -> Result<u16, SensorError> {
+    if crc8_sensirion(&data[..2]) != data[2] {
+        return Err(SensorError::new(ErrorKind::InvalidData, "SHT3x CRC mismatch"));
+    }
+    Ok(u16::from_be_bytes([data[0], data[1]]))
+}
+
+impl<I2C, Delay> TemperatureSensor for Sht3x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn read_celsius_x10(&mut self) -> Result<i16, SensorError> {
+        Ok(self.measure()?.temperature_x10)
+    }
+}
+
+impl<I2C, Delay> HumiditySensor for Sht3x<I2C, Delay>
+where
+    I2C: I2c,
+    Delay: DelayNs,
+{
+    fn read_percent_x10(&mut self) -> Result<u16, SensorError> {
+        Ok(self.measure()?.humidity_x10)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::i2c::{Mock as I2cMock, Transaction};
+
+    use super::*;
+
+    fn word(raw: u16) -> [u8; 3] {
+        let [msb, lsb] = raw.to_be_bytes();
+        [msb, lsb, crc8_sensirion(&[msb, lsb])]
+    }
+
+    fn measure(data: [u8; 6]) -> Result<Sht3xMeasurement, SensorError> {
+        let transactions = [
+            Transaction::write(SHT3X_DEFAULT_ADDRESS, MEASURE_HIGH_REPEATABILITY.to_vec()),
+            Transaction::read(SHT3X_DEFAULT_ADDRESS, data.to_vec()),
+        ];
+        let mut i2c = I2cMock::new(&transactions);
+        let measurement = Sht3x::new(i2c.clone(), SHT3X_DEFAULT_ADDRESS, NoopDelay::new()).measure();
+        i2c.done();
+        measurement
+    }
+
+    fn data(raw_temperature: u16, raw_humidity: u16) -> [u8; 6] {
+        let mut data = [0u8; 6];
+        data[..3].copy_from_slice(&word(raw_temperature));
+        data[3..].copy_from_slice(&word(raw_humidity));
+        data
+    }
+
+    #[test]
+    fn scales_the_raw_words() {
+        assert_eq!(
+            measure(data(0x6666, 0x8000)).unwrap(),
+            Sht3xMeasurement { temperature_x10: 250, humidity_x10: 500 }
+        );
+        // Both ends of the range
+        assert_eq!(
+            measure(data(0x0000, 0xFFFF)).unwrap(),
+            Sht3xMeasurement { temperature_x10: -450, humidity_x10: 1000 }
+        );
+        assert_eq!(measure(data(0xFFFF, 0x0000)).unwrap().temperature_x10, 1300);
+    }
+
+    #[test]
+    fn rejects_a_temperature_crc_mismatch() {
+        let mut corrupted = data(0x6666, 0x8000);
+        corrupted[2] ^= 0x01;
+        assert_eq!(measure(corrupted).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_a_humidity_crc_mismatch() {
+        let mut corrupted = data(0x6666, 0x8000);
+        corrupted[4] ^= 0x80;
+        assert_eq!(measure(corrupted).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reports_bus_errors() {
+        let transactions = [Transaction::write(SHT3X_DEFAULT_ADDRESS, MEASURE_HIGH_REPEATABILITY.to_vec())
+            .with_error(embedded_hal::i2c::ErrorKind::NoAcknowledge(embedded_hal::i2c::NoAcknowledgeSource::Address))];
+        let mut i2c = I2cMock::new(&transactions);
+        let error = Sht3x::new(i2c.clone(), SHT3X_DEFAULT_ADDRESS, NoopDelay::new()).read_celsius_x10().unwrap_err();
+        i2c.done();
+        assert!(error.to_string().contains("Bus error"));
+    }
+}
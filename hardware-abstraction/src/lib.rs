@@ -1 +1,3 @@
-pub mod mcus;
\ No newline at end of file
+#[cfg(target_os = "espidf")]
+pub mod mcus;
+pub mod drivers;
\ No newline at end of file
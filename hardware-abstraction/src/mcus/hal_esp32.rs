@@ -31,6 +31,11 @@ impl HoneyCellDisplacer for Esp32Actuator<'_> {
             HoneyCellDisplacerCommand::Stop => self.stop(),
         }
     }
+
+    fn cells_closed(&mut self) -> Result<bool, HoneyCellDisplacerFault> {
+        // The limit switches are active low
+        Ok(self.limit_top.is_low())
+    }
 }
 
 impl<'actuator_lifetime> Esp32Actuator<'actuator_lifetime> {
@@ -1,3 +1,31 @@
+use std::path::PathBuf;
+
+/// PEM files that can be embedded at build time: (env variable with the path, file name in OUT_DIR)
+const CERTIFICATES: [(&str, &str); 3] = [
+    ("MQTT_CA_CERT_PATH", "mqtt_ca_cert.pem"),
+    ("MQTT_CLIENT_CERT_PATH", "mqtt_client_cert.pem"),
+    ("MQTT_CLIENT_KEY_PATH", "mqtt_client_key.pem"),
+];
+
 fn main() {
     embuild::espidf::sysenv::output();
-}
\ No newline at end of file
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+
+    // mbedTLS parses PEM from a NUL terminated buffer, a missing file is embedded as an empty one
+    for (env, file_name) in CERTIFICATES {
+        println!("cargo:rerun-if-env-changed={}", env);
+
+        let pem = match std::env::var(env) {
+            Ok(path) => {
+                println!("cargo:rerun-if-changed={}", path);
+                let mut pem = std::fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {} ({}): {}", env, path, e));
+                pem.push(0);
+                pem
+            }
+            Err(_) => Vec::new(),
+        };
+
+        std::fs::write(out_dir.join(file_name), pem).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+pub mod security;
+pub mod server;
+
+use std::sync::{Arc, Mutex};
+
+use esp_idf_hal::modem::BluetoothModemPeripheral;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+
+use log::*;
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::controller::dispatcher::{dispatch, reply_to_json, CommandReply};
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use crate::ble::server::{BleServer, CommandExecutor};
+use crate::event_loop::notifications::Notifier;
+
+/// A thin adapter over the dispatcher, every reply is sent back - acknowledgements and errors included
+fn command_executor<H: HoneyCellDisplacer + Send + 'static>(
+    controller: Arc<Mutex<HiveController<H>>>,
+    notifier: Notifier,
+) -> CommandExecutor {
+    Box::new(move |payload| {
+        let mut ctrl = controller.lock().unwrap();
+        let mut json = String::new();
+        let outcome = dispatch(&mut ctrl, payload, &mut |reply: &CommandReply| json = reply_to_json(reply));
+        notifier.notify_command(&mut ctrl, &outcome.events);
+        json
+    })
+}
+
+/// Longest name that fits in the advertisement
+const MAX_NAME_LEN: usize = 26;
+
+/// Starts the GATT server, advertised as `SmartHive-{hive_id}`. `None` when BLE fails to start
+pub fn start_ble<H: HoneyCellDisplacer + Send + 'static>(
+    modem: impl Peripheral<P = impl BluetoothModemPeripheral> + 'static,
+    nvs: &EspDefaultNvsPartition,
+    hive_id: Option<&str>,
+    controller: Arc<Mutex<HiveController<H>>>,
+    notifier: Notifier,
+) -> Option<BleServer> {
+    let mut name = format!("SmartHive-{}", hive_id.unwrap_or("new"));
+    while name.len() > MAX_NAME_LEN {
+        name.pop();
+    }
+
+    match BleServer::start(modem, nvs, &name, command_executor(controller, notifier)) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            error!("Failed to start BLE: {}", e);
+            None
+        }
+    }
+}
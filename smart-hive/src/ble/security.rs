@@ -0,0 +1,96 @@
+use esp_idf_svc::bt::BdAddr;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::{
+    esp, esp_ble_bond_dev_t, esp_ble_gap_set_security_param, esp_ble_get_bond_device_list, esp_ble_get_bond_device_num,
+    esp_ble_remove_bond_device, esp_ble_sm_param_t, esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE,
+    esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE, esp_ble_sm_param_t_ESP_BLE_SM_MAX_KEY_SIZE,
+    esp_ble_sm_param_t_ESP_BLE_SM_ONLY_ACCEPT_SPECIFIED_SEC_AUTH, esp_ble_sm_param_t_ESP_BLE_SM_SET_INIT_KEY,
+    esp_ble_sm_param_t_ESP_BLE_SM_SET_RSP_KEY, esp_ble_sm_param_t_ESP_BLE_SM_SET_STATIC_PASSKEY, esp_random, EspError,
+    ESP_BLE_ENC_KEY_MASK, ESP_BLE_ID_KEY_MASK, ESP_BLE_ONLY_ACCEPT_SPECIFIED_AUTH_ENABLE, ESP_IO_CAP_OUT,
+    ESP_LE_AUTH_REQ_SC_MITM_BOND,
+};
+
+use log::*;
+
+/// NVS namespace of the passkey, generated on the first boot so that every hive has its own
+const BLE_NVS_NAMESPACE: &str = "ble";
+const NVS_PASSKEY: &str = "passkey";
+
+const PASSKEYS: u32 = 1_000_000;
+
+/// The passkey of this hive, `None` until BLE has started once
+pub fn stored_passkey(nvs: &EspDefaultNvsPartition) -> Result<Option<u32>, EspError> {
+    EspNvs::new(nvs.clone(), BLE_NVS_NAMESPACE, true)?.get_u32(NVS_PASSKEY)
+}
+
+/// The passkey of this hive, generated and stored the first time. The radio must be on, the RNG is only truly random then
+pub fn load_or_create_passkey(nvs: &EspDefaultNvsPartition) -> Result<u32, EspError> {
+    let mut stored = EspNvs::new(nvs.clone(), BLE_NVS_NAMESPACE, true)?;
+    if let Some(passkey) = stored.get_u32(NVS_PASSKEY)?.filter(|passkey| *passkey < PASSKEYS) {
+        return Ok(passkey);
+    }
+
+    let passkey = unsafe { esp_random() } % PASSKEYS;
+    stored.set_u32(NVS_PASSKEY, passkey)?;
+    info!("Generated the BLE passkey of this hive");
+    Ok(passkey)
+}
+
+/// Secure Connections with MITM protection and bonding, authenticated with a static passkey since the hive has no display
+pub fn set_security(passkey: u32) -> Result<(), EspError> {
+    fn set<T>(param: esp_ble_sm_param_t, mut value: T) -> Result<(), EspError> {
+        esp!(unsafe { esp_ble_gap_set_security_param(param, &mut value as *mut T as *mut _, size_of::<T>() as u8) })
+    }
+
+    set(esp_ble_sm_param_t_ESP_BLE_SM_SET_STATIC_PASSKEY, passkey)?;
+    set(esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE, ESP_LE_AUTH_REQ_SC_MITM_BOND as u8)?;
+    // The passkey is "displayed" (read on the console), the phone enters it
+    set(esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE, ESP_IO_CAP_OUT as u8)?;
+    set(esp_ble_sm_param_t_ESP_BLE_SM_MAX_KEY_SIZE, 16u8)?;
+    set(esp_ble_sm_param_t_ESP_BLE_SM_ONLY_ACCEPT_SPECIFIED_SEC_AUTH, ESP_BLE_ONLY_ACCEPT_SPECIFIED_AUTH_ENABLE as u8)?;
+    set(esp_ble_sm_param_t_ESP_BLE_SM_SET_INIT_KEY, (ESP_BLE_ENC_KEY_MASK | ESP_BLE_ID_KEY_MASK) as u8)?;
+    set(esp_ble_sm_param_t_ESP_BLE_SM_SET_RSP_KEY, (ESP_BLE_ENC_KEY_MASK | ESP_BLE_ID_KEY_MASK) as u8)?;
+
+    Ok(())
+}
+
+/// The phones bonded with the hive, empty while BLE is off
+pub fn bonded_devices() -> Result<Vec<BdAddr>, EspError> {
+    let mut count = unsafe { esp_ble_get_bond_device_num() };
+    if count <= 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut devices = vec![unsafe { std::mem::zeroed::<esp_ble_bond_dev_t>() }; count as usize];
+    esp!(unsafe { esp_ble_get_bond_device_list(&mut count, devices.as_mut_ptr()) })?;
+    devices.truncate(count.max(0) as usize);
+
+    Ok(devices.into_iter().map(|device| BdAddr::from_bytes(device.bd_addr)).collect())
+}
+
+/// Forgets a phone, it has to pair with the passkey again
+pub fn remove_bond(addr: BdAddr) -> Result<(), EspError> {
+    let mut raw = addr.raw();
+    esp!(unsafe { esp_ble_remove_bond_device(raw.as_mut_ptr()) })
+}
+
+/// Forgets every phone, e.g. once one is lost
+pub fn clear_bonds() -> Result<usize, EspError> {
+    let devices = bonded_devices()?;
+    for addr in &devices {
+        remove_bond(*addr)?;
+    }
+
+    Ok(devices.len())
+}
+
+/// `aa:bb:cc:dd:ee:ff`, as the addresses are printed
+pub fn parse_addr(text: &str) -> Option<BdAddr> {
+    let mut bytes = [0u8; 6];
+    let mut parts = text.split(':');
+    for byte in &mut bytes {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+
+    parts.next().is_none().then(|| BdAddr::from_bytes(bytes))
+}
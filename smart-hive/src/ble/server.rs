@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_hal::modem::BluetoothModemPeripheral;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::reset::restart;
+use esp_idf_svc::bt::ble::gap::{AdvConfiguration, BleGapEvent, EspBleGap};
+use esp_idf_svc::bt::ble::gatt::server::{ConnectionId, EspGatts, GattsEvent, TransferId};
+use esp_idf_svc::bt::ble::gatt::{
+    AutoResponse, GattCharacteristic, GattDescriptor, GattId, GattInterface, GattResponse, GattServiceId, GattStatus,
+    Handle, Permission, Property,
+};
+use esp_idf_svc::bt::{Ble, BtDriver, BtStatus, BtUuid};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{
+    esp, esp_ble_gap_security_rsp, esp_ble_sec_act_t_ESP_BLE_SEC_ENCRYPT_MITM, esp_ble_set_encryption, EspError, ESP_FAIL,
+};
+
+use log::*;
+use software_defined_hive::provisioning::form::ProvisioningForm;
+use crate::ble::security::{load_or_create_passkey, set_security};
+use crate::provisioning::settings::store;
+
+const APP_ID: u16 = 0;
+
+/// Smart Hive service, a phone writes a JSON `HiveCommand` to the command characteristic and
+/// receives the reply as indications of the response characteristic
+pub const SERVICE_UUID: u128 = 0x5a1b_0001_7e4d_4c6b_9b1e_6f2a_8c3d_b001;
+/// `HiveCommand` JSON, same schema as the MQTT commands topic
+pub const COMMAND_UUID: u128 = 0x5a1b_0002_7e4d_4c6b_9b1e_6f2a_8c3d_b001;
+/// Replies to the commands and to the provisioning, each one is JSON terminated by a newline
+pub const RESPONSE_UUID: u128 = 0x5a1b_0003_7e4d_4c6b_9b1e_6f2a_8c3d_b001;
+/// The fields of the provisioning page as JSON, the hive restarts with them
+pub const PROVISIONING_UUID: u128 = 0x5a1b_0004_7e4d_4c6b_9b1e_6f2a_8c3d_b001;
+
+/// Client Characteristic Configuration, where the phone enables the indications
+const CCCD_UUID: u16 = 0x2902;
+
+/// Longest command accepted (a policy update is the longest), written with long writes when over the MTU
+const MAX_REQUEST_LEN: usize = 2048;
+
+const DEFAULT_MTU: u16 = 23;
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time for the provisioning reply to reach the phone before the hive restarts
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+/// Executes a command received over BLE and returns the JSON reply, the same dispatch as MQTT
+pub type CommandExecutor = Box<dyn FnMut(&str) -> String + Send>;
+
+type HiveBtDriver = BtDriver<'static, Ble>;
+type HiveBleGap = Arc<EspBleGap<'static, Ble, Arc<HiveBtDriver>>>;
+type HiveGatts = Arc<EspGatts<'static, Ble, Arc<HiveBtDriver>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    Command,
+    Provisioning,
+}
+
+#[derive(Debug)]
+struct Connection {
+    mtu: u16,
+    indications: bool,
+    /// Long write in progress: (characteristic, value so far)
+    pending: Option<(Request, Vec<u8>)>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    gatt_if: Option<GattInterface>,
+    command_handle: Option<Handle>,
+    response_handle: Option<Handle>,
+    response_cccd_handle: Option<Handle>,
+    provisioning_handle: Option<Handle>,
+    connections: HashMap<ConnectionId, Connection>,
+}
+
+/// GATT server exposing the hive to a phone. Commands and provisioning can only be written over an
+/// encrypted link paired with the passkey (MITM protected), the phone is bonded so it pairs once
+#[derive(Clone)]
+pub struct BleServer {
+    gap: HiveBleGap,
+    gatts: HiveGatts,
+    name: String,
+    state: Arc<Mutex<State>>,
+    requests: Sender<(ConnectionId, Request, Vec<u8>)>,
+    confirms: Arc<Mutex<Receiver<ConnectionId>>>,
+    confirms_sender: Sender<ConnectionId>,
+}
+
+impl BleServer {
+    /// Starts advertising as `name` (at most 26 bytes), paired with the passkey of this hive, and handles the requests
+    /// on a worker thread, so that the replies can wait for the indications to be confirmed
+    pub fn start(
+        modem: impl Peripheral<P = impl BluetoothModemPeripheral> + 'static,
+        nvs: &EspDefaultNvsPartition,
+        name: &str,
+        execute: CommandExecutor,
+    ) -> Result<Self, EspError> {
+        let bt = Arc::new(BtDriver::new(modem, Some(nvs.clone()))?);
+        let gap = Arc::new(EspBleGap::new(bt.clone())?);
+        let gatts = Arc::new(EspGatts::new(bt)?);
+
+        let (requests, requests_receiver) = channel();
+        let (confirms_sender, confirms) = channel();
+        let server = Self {
+            gap,
+            gatts,
+            name: name.to_string(),
+            state: Arc::new(Mutex::new(State::default())),
+            requests,
+            confirms: Arc::new(Mutex::new(confirms)),
+            confirms_sender,
+        };
+
+        set_security(load_or_create_passkey(nvs)?)?;
+
+        let gap_server = server.clone();
+        server.gap.subscribe(move |event| {
+            if let Err(e) = gap_server.on_gap_event(event) {
+                warn!("BLE GAP event failed: {}", e);
+            }
+        })?;
+
+        let gatts_server = server.clone();
+        server.gatts.subscribe(move |(gatt_if, event)| {
+            if let Err(e) = gatts_server.on_gatts_event(gatt_if, event) {
+                warn!("BLE GATT event failed: {}", e);
+            }
+        })?;
+
+        let worker = server.clone();
+        let nvs = nvs.clone();
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || worker.serve(requests_receiver, execute, nvs))
+            .map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+
+        server.gatts.register_app(APP_ID)?;
+        info!("BLE advertising as \"{}\"", name);
+
+        Ok(server)
+    }
+
+    fn on_gap_event(&self, event: BleGapEvent) -> Result<(), EspError> {
+        match event {
+            // Configured last, see create_service
+            BleGapEvent::ScanResponseConfigured(status) => {
+                check_bt_status(status)?;
+                self.gap.start_advertising()?;
+            }
+            // The phone asks to pair
+            BleGapEvent::SecurityRequest(addr) => {
+                let mut raw = addr.raw();
+                esp!(unsafe { esp_ble_gap_security_rsp(raw.as_mut_ptr(), true) })?;
+            }
+            BleGapEvent::AuthenticationComplete { bd_addr, status, .. } => match check_bt_status(status) {
+                Ok(()) => info!("BLE peer {} paired", bd_addr),
+                Err(_) => warn!("BLE peer {} failed to pair: {:?}", bd_addr, status),
+            },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn on_gatts_event(&self, gatt_if: GattInterface, event: GattsEvent) -> Result<(), EspError> {
+        match event {
+            GattsEvent::ServiceRegistered { status, app_id } if app_id == APP_ID => {
+                check_gatt_status(status)?;
+                self.create_service(gatt_if)?;
+            }
+            GattsEvent::ServiceCreated { status, service_handle, .. } => {
+                check_gatt_status(status)?;
+                self.gatts.start_service(service_handle)?;
+                self.add_characteristic(service_handle, COMMAND_UUID, Permission::WriteEncryptedMitm.into(), Property::Write.into())?;
+            }
+            // One attribute at a time, a descriptor belongs to the characteristic added last
+            GattsEvent::CharacteristicAdded { status, attr_handle, service_handle, char_uuid } => {
+                check_gatt_status(status)?;
+                let mut state = self.state.lock().unwrap();
+
+                if char_uuid == BtUuid::uuid128(COMMAND_UUID) {
+                    state.command_handle = Some(attr_handle);
+                    drop(state);
+                    self.add_characteristic(service_handle, RESPONSE_UUID, Permission::ReadEncryptedMitm.into(), Property::Indicate.into())?;
+                } else if char_uuid == BtUuid::uuid128(RESPONSE_UUID) {
+                    state.response_handle = Some(attr_handle);
+                    drop(state);
+                    self.gatts.add_descriptor(
+                        service_handle,
+                        &GattDescriptor {
+                            uuid: BtUuid::uuid16(CCCD_UUID),
+                            permissions: Permission::Read | Permission::WriteEncryptedMitm,
+                        },
+                    )?;
+                } else if char_uuid == BtUuid::uuid128(PROVISIONING_UUID) {
+                    state.provisioning_handle = Some(attr_handle);
+                }
+            }
+            GattsEvent::DescriptorAdded { status, attr_handle, service_handle, .. } => {
+                check_gatt_status(status)?;
+                self.state.lock().unwrap().response_cccd_handle = Some(attr_handle);
+                self.add_characteristic(service_handle, PROVISIONING_UUID, Permission::WriteEncryptedMitm.into(), Property::Write.into())?;
+            }
+            GattsEvent::PeerConnected { conn_id, addr, .. } => {
+                info!("BLE peer {} connected", addr);
+                self.state.lock().unwrap().connections.insert(
+                    conn_id,
+                    Connection {
+                        mtu: DEFAULT_MTU,
+                        indications: false,
+                        pending: None,
+                    },
+                );
+
+                // Pair (or resume the bond) right away rather than on the first protected write
+                let mut raw = addr.raw();
+                esp!(unsafe { esp_ble_set_encryption(raw.as_mut_ptr(), esp_ble_sec_act_t_ESP_BLE_SEC_ENCRYPT_MITM) })?;
+            }
+            GattsEvent::PeerDisconnected { conn_id, addr, .. } => {
+                info!("BLE peer {} disconnected", addr);
+                self.state.lock().unwrap().connections.remove(&conn_id);
+                // Advertising stops on connection
+                self.gap.start_advertising()?;
+            }
+            GattsEvent::Mtu { conn_id, mtu } => {
+                if let Some(connection) = self.state.lock().unwrap().connections.get_mut(&conn_id) {
+                    connection.mtu = mtu;
+                }
+            }
+            GattsEvent::Read { conn_id, trans_id, handle, need_rsp: true, .. } => {
+                let state = self.state.lock().unwrap();
+                let indications = state.connections.get(&conn_id).is_some_and(|connection| connection.indications);
+                let value = if Some(handle) == state.response_cccd_handle { [u8::from(indications) << 1, 0] } else { [0, 0] };
+                drop(state);
+
+                let mut response = GattResponse::new();
+                response.attr_handle(handle).auth_req(0).offset(0).value(&value).map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+                self.gatts.send_response(gatt_if, conn_id, trans_id, GattStatus::Ok, Some(&response))?;
+            }
+            GattsEvent::Write { conn_id, trans_id, handle, offset, need_rsp, is_prep, value, .. } => {
+                let status = self.on_write(conn_id, handle, offset, is_prep, value);
+                if need_rsp {
+                    self.send_write_response(gatt_if, conn_id, trans_id, handle, offset, is_prep, value, status)?;
+                }
+            }
+            GattsEvent::ExecWrite { conn_id, trans_id, canceled, .. } => {
+                let pending = self.state.lock().unwrap().connections.get_mut(&conn_id).and_then(|connection| connection.pending.take());
+                if let (false, Some((request, value))) = (canceled, pending) {
+                    let _ = self.requests.send((conn_id, request, value));
+                }
+                self.gatts.send_response(gatt_if, conn_id, trans_id, GattStatus::Ok, None)?;
+            }
+            GattsEvent::Confirm { conn_id, .. } => {
+                let _ = self.confirms_sender.send(conn_id);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn create_service(&self, gatt_if: GattInterface) -> Result<(), EspError> {
+        self.state.lock().unwrap().gatt_if = Some(gatt_if);
+
+        // The name and the 128-bit service UUID do not fit together in the 31 bytes of the advertisement
+        self.gap.set_device_name(&self.name)?;
+        self.gap.set_adv_conf(&AdvConfiguration {
+            include_name: true,
+            flag: 2,
+            ..Default::default()
+        })?;
+        self.gap.set_adv_conf(&AdvConfiguration {
+            set_scan_rsp: true,
+            service_uuid: Some(BtUuid::uuid128(SERVICE_UUID)),
+            ..Default::default()
+        })?;
+
+        self.gatts.create_service(
+            gatt_if,
+            &GattServiceId {
+                id: GattId {
+                    uuid: BtUuid::uuid128(SERVICE_UUID),
+                    inst_id: 0,
+                },
+                is_primary: true,
+            },
+            // The service, 3 characteristics with their values and the descriptor
+            8,
+        )?;
+
+        Ok(())
+    }
+
+    fn add_characteristic(
+        &self,
+        service_handle: Handle,
+        uuid: u128,
+        permissions: enumset::EnumSet<Permission>,
+        properties: enumset::EnumSet<Property>,
+    ) -> Result<(), EspError> {
+        self.gatts.add_characteristic(
+            service_handle,
+            &GattCharacteristic {
+                uuid: BtUuid::uuid128(uuid),
+                permissions,
+                properties,
+                max_len: MAX_REQUEST_LEN,
+                auto_rsp: AutoResponse::ByApp,
+            },
+            &[],
+        )?;
+
+        Ok(())
+    }
+
+    /// Collects the written value, a complete one is handed to the worker
+    fn on_write(&self, conn_id: ConnectionId, handle: Handle, offset: u16, is_prep: bool, value: &[u8]) -> GattStatus {
+        let mut state = self.state.lock().unwrap();
+        let request = if Some(handle) == state.command_handle {
+            Some(Request::Command)
+        } else if Some(handle) == state.provisioning_handle {
+            Some(Request::Provisioning)
+        } else {
+            None
+        };
+        let cccd = Some(handle) == state.response_cccd_handle;
+
+        let Some(connection) = state.connections.get_mut(&conn_id) else {
+            return GattStatus::InvalidHandle;
+        };
+
+        match request {
+            None if cccd => {
+                connection.indications = value.first().is_some_and(|flags| flags & 0x02 != 0);
+                GattStatus::Ok
+            }
+            None => GattStatus::InvalidHandle,
+            Some(request) if is_prep => {
+                let (_, buffer) = connection.pending.get_or_insert_with(|| (request, Vec::new()));
+                let offset = offset as usize;
+                if offset != buffer.len() || offset + value.len() > MAX_REQUEST_LEN {
+                    connection.pending = None;
+                    return GattStatus::InvalidOffset;
+                }
+
+                buffer.extend_from_slice(value);
+                GattStatus::Ok
+            }
+            Some(request) => {
+                let _ = self.requests.send((conn_id, request, value.to_vec()));
+                GattStatus::Ok
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send_write_response(
+        &self,
+        gatt_if: GattInterface,
+        conn_id: ConnectionId,
+        trans_id: TransferId,
+        handle: Handle,
+        offset: u16,
+        is_prep: bool,
+        value: &[u8],
+        status: GattStatus,
+    ) -> Result<(), EspError> {
+        if is_prep && status == GattStatus::Ok {
+            // A prepared write is acknowledged by echoing it
+            let mut response = GattResponse::new();
+            response.attr_handle(handle).auth_req(0).offset(offset).value(value).map_err(|_| EspError::from_infallible::<ESP_FAIL>())?;
+            self.gatts.send_response(gatt_if, conn_id, trans_id, status, Some(&response))?;
+        } else {
+            self.gatts.send_response(gatt_if, conn_id, trans_id, status, None)?;
+        }
+
+        Ok(())
+    }
+
+    fn serve(&self, requests: Receiver<(ConnectionId, Request, Vec<u8>)>, mut execute: CommandExecutor, nvs: EspDefaultNvsPartition) {
+        for (conn_id, request, value) in requests {
+            let payload = String::from_utf8_lossy(&value);
+
+            let reply = match request {
+                Request::Command => execute(&payload),
+                Request::Provisioning => provision(&payload, &nvs),
+            };
+
+            if let Err(e) = self.reply(conn_id, &reply) {
+                warn!("Failed to send the BLE reply: {}", e);
+            }
+        }
+    }
+
+    /// Sends the reply as indications of the MTU, the phone reassembles them up to the newline
+    fn reply(&self, conn_id: ConnectionId, reply: &str) -> Result<(), EspError> {
+        let (gatt_if, handle, mtu) = {
+            let state = self.state.lock().unwrap();
+            let connection = state.connections.get(&conn_id).filter(|connection| connection.indications);
+            match (state.gatt_if, state.response_handle, connection) {
+                (Some(gatt_if), Some(handle), Some(connection)) => (gatt_if, handle, connection.mtu),
+                _ => {
+                    warn!("BLE peer has not enabled the indications, the reply is dropped");
+                    return Ok(());
+                }
+            }
+        };
+
+        let confirms = self.confirms.lock().unwrap();
+        // Confirmations left over from an earlier reply
+        while confirms.try_recv().is_ok() {}
+
+        let framed = format!("{}\n", reply);
+        for chunk in framed.as_bytes().chunks((mtu - 3) as usize) {
+            self.gatts.indicate(gatt_if, conn_id, handle, chunk)?;
+
+            // One indication at a time
+            loop {
+                match confirms.recv_timeout(CONFIRM_TIMEOUT) {
+                    Ok(confirmed) if confirmed == conn_id => break,
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                        return Err(EspError::from_infallible::<ESP_FAIL>());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The same fields and validation as the provisioning page, the hive restarts with the settings once they are stored
+fn provision(payload: &str, nvs: &EspDefaultNvsPartition) -> String {
+    let form = match serde_json::from_str::<ProvisioningForm>(payload) {
+        Ok(form) => form,
+        Err(e) => return serde_json::json!({ "status": "error", "message": e.to_string() }).to_string(),
+    };
+
+    let settings = match form.validate() {
+        Ok(settings) => settings,
+        Err(errors) => return serde_json::json!({ "status": "error", "errors": errors }).to_string(),
+    };
+
+    if let Err(e) = store(nvs, &settings) {
+        error!("Failed to store the provisioning settings: {}", e);
+        return serde_json::json!({ "status": "error", "message": e.to_string() }).to_string();
+    }
+
+    std::thread::spawn(|| {
+        std::thread::sleep(RESTART_DELAY);
+        restart();
+    });
+
+    serde_json::json!({ "status": "success" }).to_string()
+}
+
+fn check_bt_status(status: BtStatus) -> Result<(), EspError> {
+    if status == BtStatus::Success { Ok(()) } else { Err(EspError::from_infallible::<ESP_FAIL>()) }
+}
+
+fn check_gatt_status(status: GattStatus) -> Result<(), EspError> {
+    if status == GattStatus::Ok { Ok(()) } else { Err(EspError::from_infallible::<ESP_FAIL>()) }
+}
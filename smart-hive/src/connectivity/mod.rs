@@ -0,0 +1 @@
+pub mod supervisor;
\ No newline at end of file
@@ -0,0 +1,270 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttConnection};
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+use software_defined_hive::utils::backoff::ExponentialBackoff;
+use software_defined_hive::utils::telemetry::ConnectivityStatus;
+use crate::event_loop::event_loop::{spawn_listener, ConnectionEvent, MessageHandler};
+use crate::mqtt::credentials::MqttCredentials;
+use crate::mqtt::mqtt::mqtt_create;
+use crate::mqtt::publisher::Publisher;
+use crate::mqtt::topics::MqttTopic;
+use crate::wi_fi::wi_fi::WifiManager;
+
+/// How often the connections are checked when nothing happens
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Latest connectivity state, published with the telemetry
+static CONNECTIVITY: Mutex<ConnectivityStatus> = Mutex::new(ConnectivityStatus {
+    wifi_connected: false,
+    mqtt_connected: false,
+    wifi_reconnects: 0,
+    mqtt_reconnects: 0,
+    last_outage_s: None,
+});
+
+pub fn connectivity_status() -> ConnectivityStatus {
+    *CONNECTIVITY.lock().unwrap()
+}
+
+/// What is needed to (re)create the MQTT client
+pub struct MqttSettings {
+    pub url: String,
+    pub client_id: String,
+    pub credentials: MqttCredentials,
+    /// Where the last will goes
+    pub availability_topic: String,
+}
+
+/// Keeps the hive connected: reconnects Wi-Fi, recreates the MQTT client when its connection is closed and
+/// re-subscribes after every (re)connection, retrying each with exponential backoff. The messages published
+/// while offline are replayed once the hive is back online
+pub struct ConnectivitySupervisor {
+    wifi: WifiManager,
+    mqtt: MqttSettings,
+    client: Arc<Mutex<EspMqttClient<'static>>>,
+    publisher: Publisher,
+    subscriptions: Vec<MqttTopic>,
+    on_message: MessageHandler,
+    events_sender: Sender<ConnectionEvent>,
+    events: Receiver<ConnectionEvent>,
+    wifi_backoff: ExponentialBackoff,
+    mqtt_backoff: ExponentialBackoff,
+    next_wifi_attempt: Instant,
+    next_mqtt_attempt: Instant,
+    wifi_ever_connected: bool,
+    mqtt_ever_connected: bool,
+    subscribed: bool,
+    connection_closed: bool,
+    offline_since: Option<Instant>,
+    status: ConnectivityStatus,
+}
+
+impl ConnectivitySupervisor {
+    /// Starts listening on the connection of the client, `client` is the one the message handlers publish with
+    pub fn new(
+        wifi: WifiManager,
+        mqtt: MqttSettings,
+        client: Arc<Mutex<EspMqttClient<'static>>>,
+        publisher: Publisher,
+        connection: EspMqttConnection,
+        subscriptions: Vec<MqttTopic>,
+        on_message: MessageHandler,
+    ) -> std::io::Result<Self> {
+        let (events_sender, events) = channel();
+        spawn_listener(connection, on_message.clone(), events_sender.clone())?;
+
+        let now = Instant::now();
+        let wifi_ever_connected = wifi.is_connected();
+
+        Ok(Self {
+            wifi,
+            mqtt,
+            client,
+            publisher,
+            subscriptions,
+            on_message,
+            events_sender,
+            events,
+            wifi_backoff: ExponentialBackoff::new(INITIAL_BACKOFF, MAX_BACKOFF),
+            mqtt_backoff: ExponentialBackoff::new(INITIAL_BACKOFF, MAX_BACKOFF),
+            next_wifi_attempt: now,
+            next_mqtt_attempt: now,
+            wifi_ever_connected,
+            mqtt_ever_connected: false,
+            subscribed: false,
+            connection_closed: false,
+            offline_since: Some(now),
+            status: ConnectivityStatus::default(),
+        })
+    }
+
+    /// Supervises the connections forever
+    pub fn run(mut self) -> ! {
+        loop {
+            self.poll();
+        }
+    }
+
+    /// Waits up to `POLL_INTERVAL` for a connection event, then repairs whatever is down
+    fn poll(&mut self) {
+        match self.events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => self.on_connection_event(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {}
+        }
+        while let Ok(event) = self.events.try_recv() {
+            self.on_connection_event(event);
+        }
+
+        let now = Instant::now();
+        let wifi_connected = self.supervise_wifi(now);
+
+        if wifi_connected && now >= self.next_mqtt_attempt {
+            if self.connection_closed {
+                self.recreate_client(now);
+            } else if self.status.mqtt_connected && !self.subscribed {
+                self.subscribe(now);
+            }
+        }
+
+        self.update_status(wifi_connected, now);
+    }
+
+    fn on_connection_event(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Connected => {
+                if self.mqtt_ever_connected {
+                    self.status.mqtt_reconnects += 1;
+                }
+                self.mqtt_ever_connected = true;
+                self.status.mqtt_connected = true;
+                // The session is clean, the broker forgot the subscriptions
+                self.subscribed = false;
+                self.next_mqtt_attempt = Instant::now();
+            }
+            ConnectionEvent::Disconnected => {
+                // The client reconnects by itself, and sends the messages it holds again
+                self.status.mqtt_connected = false;
+                self.subscribed = false;
+            }
+            ConnectionEvent::Published(msg_id) => self.publisher.acknowledge(msg_id),
+            ConnectionEvent::Deleted(msg_id) => self.publisher.requeue(msg_id),
+            ConnectionEvent::Closed => {
+                self.status.mqtt_connected = false;
+                self.subscribed = false;
+                self.connection_closed = true;
+                // Lost with the client
+                self.publisher.requeue_in_flight();
+            }
+        }
+    }
+
+    /// Reconnects Wi-Fi when it is down and the backoff allows it, returns whether it is connected
+    fn supervise_wifi(&mut self, now: Instant) -> bool {
+        if self.wifi.is_connected() {
+            return true;
+        }
+
+        if now < self.next_wifi_attempt {
+            return false;
+        }
+
+        warn!("Wi-Fi is down, reconnecting (attempt {})", self.wifi_backoff.attempts() + 1);
+        match self.wifi.connect() {
+            Ok(()) => {
+                if self.wifi_ever_connected {
+                    self.status.wifi_reconnects += 1;
+                }
+                self.wifi_ever_connected = true;
+                self.wifi_backoff.reset();
+                true
+            }
+            Err(e) => {
+                let delay = self.wifi_backoff.next_delay();
+                warn!("Wi-Fi reconnect failed: {}, retrying in {:?}", e, delay);
+                self.next_wifi_attempt = now + delay;
+                false
+            }
+        }
+    }
+
+    fn subscribe(&mut self, now: Instant) {
+        if let Err(e) = self.try_subscribe() {
+            let delay = self.mqtt_backoff.next_delay();
+            error!("Failed to subscribe: {:?}, retrying in {:?}", e, delay);
+            self.next_mqtt_attempt = now + delay;
+            return;
+        }
+
+        for mqtt_topic in &self.subscriptions {
+            info!("Subscribed to topic \"{}\"", mqtt_topic.topic);
+        }
+        self.subscribed = true;
+        self.mqtt_backoff.reset();
+    }
+
+    fn try_subscribe(&self) -> Result<(), EspError> {
+        for mqtt_topic in &self.subscriptions {
+            // Only locked while subscribing so that the message handlers can publish with it
+            self.client.lock().unwrap().subscribe(&mqtt_topic.topic, mqtt_topic.qos)?;
+        }
+
+        Ok(())
+    }
+
+    fn recreate_client(&mut self, now: Instant) {
+        info!("Recreating the MQTT client (attempt {})", self.mqtt_backoff.attempts() + 1);
+
+        let created = mqtt_create(&self.mqtt.url, &self.mqtt.client_id, &self.mqtt.credentials, &self.mqtt.availability_topic)
+            .map_err(|e| e.to_string())
+            .and_then(|(client, connection)| {
+                *self.client.lock().unwrap() = client;
+                spawn_listener(connection, self.on_message.clone(), self.events_sender.clone()).map_err(|e| e.to_string())
+            });
+
+        match created {
+            Ok(_) => {
+                self.connection_closed = false;
+                self.mqtt_backoff.reset();
+            }
+            Err(e) => {
+                let delay = self.mqtt_backoff.next_delay();
+                error!("Failed to recreate the MQTT client: {}, retrying in {:?}", e, delay);
+                self.next_mqtt_attempt = now + delay;
+            }
+        }
+    }
+
+    fn update_status(&mut self, wifi_connected: bool, now: Instant) {
+        self.status.wifi_connected = wifi_connected;
+        let online = wifi_connected && self.status.mqtt_connected && self.subscribed;
+
+        match (online, self.offline_since) {
+            (false, None) => {
+                warn!("The hive is offline, the FSM keeps running on its own");
+                self.offline_since = Some(now);
+            }
+            (true, Some(since)) => {
+                let outage_s = now.duration_since(since).as_secs();
+                info!("The hive is online after {}s", outage_s);
+                // The boot is not an outage
+                if self.status.wifi_reconnects + self.status.mqtt_reconnects > 0 {
+                    self.status.last_outage_s = Some(outage_s);
+                }
+                self.offline_since = None;
+            }
+            _ => {}
+        }
+
+        // Online once subscribed, the outbox is replayed then
+        self.publisher.set_online(online);
+        *CONNECTIVITY.lock().unwrap() = self.status;
+    }
+}
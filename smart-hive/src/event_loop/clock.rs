@@ -0,0 +1,38 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use software_defined_hive::utils::clock::ReadingClock;
+use crate::event_loop::notifications::Notifier;
+
+/// How often the controller is ticked
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ticks the controller on its own thread so the harvest timeouts are enforced even while the hive is offline.
+/// The transitions and the events of a tick are queued for the publisher like those of a reading
+pub fn spawn_ticker<H: HoneyCellDisplacer + Send + 'static>(
+    controller: Arc<Mutex<HiveController<H>>>,
+    notifier: Notifier,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            let mut clock = ReadingClock::default();
+
+            loop {
+                std::thread::sleep(TICK_INTERVAL);
+
+                let mut ctrl = controller.lock().unwrap();
+                clock.observe(ctrl.last_reading_at());
+
+                if let Some(now_s) = clock.now_s() {
+                    let previous_state = ctrl.state();
+                    ctrl.tick(now_s);
+                    notifier.notify(&mut ctrl, previous_state, now_s);
+                }
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use esp_idf_hal::delay::{BLOCK, NON_BLOCK};
+use esp_idf_hal::uart::UartDriver;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+
+use log::*;
+use software_defined_hive::controller::console::execute_line;
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use software_defined_hive::utils::clock::ReadingClock;
+use crate::event_loop::notifications::Notifier;
+#[cfg(feature = "ble")]
+use crate::ble::security::{bonded_devices, clear_bonds, parse_addr, remove_bond, stored_passkey};
+
+/// The UART as `std::io`, echoing what is typed since serial monitors do not
+struct UartConsole(Arc<UartDriver<'static>>);
+
+impl io::Read for UartConsole {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Wait for a byte, then take whatever else has arrived
+        let mut len = self.0.read(&mut buf[..1], BLOCK).map_err(io::Error::other)?;
+        len += self.0.read(&mut buf[len..], NON_BLOCK).map_err(io::Error::other)?;
+
+        for byte in &mut buf[..len] {
+            // Terminals send a carriage return for Enter
+            if *byte == b'\r' {
+                *byte = b'\n';
+            }
+            let echo: &[u8] = if *byte == b'\n' { b"\r\n" } else { std::slice::from_ref(byte) };
+            self.0.write(echo).map_err(io::Error::other)?;
+        }
+
+        Ok(len)
+    }
+}
+
+impl io::Write for UartConsole {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf).map_err(io::Error::other)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serves the bench console on its own thread: JSON `HiveCommand`s and shorthands such as
+/// `status`, `slide up` or `inject weight 5200`, the responses and the state transitions are printed back.
+/// With BLE, `ble passkey`, `ble bonds` and `ble forget <address|all>` manage the paired phones
+pub fn spawn_console<H: HoneyCellDisplacer + Send + 'static>(
+    uart: UartDriver<'static>,
+    controller: Arc<Mutex<HiveController<H>>>,
+    nvs: EspDefaultNvsPartition,
+    notifier: Notifier,
+) -> io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || {
+            // Reads and writes both take the driver by reference
+            let uart = Arc::new(uart);
+            let input = BufReader::new(UartConsole(Arc::clone(&uart)));
+            let output = UartConsole(uart);
+
+            // Injected readings follow the clock of the real ones, or the uptime before any
+            let mut clock = ReadingClock::default();
+            let booted = Instant::now();
+            let now_s = move |last_reading_at| {
+                clock.observe(last_reading_at);
+                clock.now_s().unwrap_or_else(|| booted.elapsed().as_secs())
+            };
+
+            if let Err(e) = run_console(&controller, &nvs, &notifier, input, output, now_s) {
+                error!("Console stopped: {}", e);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// The lines of the controller and, with BLE, the `ble` lines, until the input ends
+#[cfg_attr(not(feature = "ble"), allow(unused_variables))]
+fn run_console<H: HoneyCellDisplacer>(
+    controller: &Mutex<HiveController<H>>,
+    nvs: &EspDefaultNvsPartition,
+    notifier: &Notifier,
+    input: impl BufRead,
+    mut output: impl Write,
+    mut now_s: impl FnMut(Option<u64>) -> u64,
+) -> io::Result<()> {
+    write!(output, "> ")?;
+    for line in input.lines() {
+        let line = line?;
+
+        #[cfg(feature = "ble")]
+        let printed = execute_ble_line(&line, nvs);
+        #[cfg(not(feature = "ble"))]
+        let printed = None;
+
+        // The controller is only locked while a line runs. What it changed, by a command or an injected reading, is notified
+        let printed = printed.unwrap_or_else(|| {
+            let mut ctrl = controller.lock().unwrap();
+            let previous_state = ctrl.state();
+            let printed = execute_line(&mut ctrl, &line, &mut now_s);
+
+            let timestamp_s = ctrl.last_reading_at().unwrap_or_default();
+            notifier.notify(&mut ctrl, previous_state, timestamp_s);
+            printed
+        });
+        for text in printed {
+            writeln!(output, "{}", text)?;
+        }
+        write!(output, "> ")?;
+    }
+
+    Ok(())
+}
+
+/// The `ble` lines, `None` for the ones of the controller
+#[cfg(feature = "ble")]
+fn execute_ble_line(line: &str, nvs: &EspDefaultNvsPartition) -> Option<Vec<String>> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let printed = match words.as_slice() {
+        ["ble", "passkey"] => match stored_passkey(nvs) {
+            Ok(Some(passkey)) => vec![format!("passkey: {:06}", passkey)],
+            Ok(None) => vec!["BLE has not started yet".to_string()],
+            Err(e) => vec![format!("error: {}", e)],
+        },
+        ["ble", "bonds"] => match bonded_devices() {
+            Ok(devices) if devices.is_empty() => vec!["no bonded phones".to_string()],
+            Ok(devices) => devices.iter().map(ToString::to_string).collect(),
+            Err(e) => vec![format!("error: {}", e)],
+        },
+        ["ble", "forget", "all"] => match clear_bonds() {
+            Ok(count) => vec![format!("forgot {} phones", count)],
+            Err(e) => vec![format!("error: {}", e)],
+        },
+        ["ble", "forget", addr] => match parse_addr(addr).map(|addr| (addr, remove_bond(addr))) {
+            Some((addr, Ok(()))) => vec![format!("forgot {}", addr)],
+            Some((_, Err(e))) => vec![format!("error: {}", e)],
+            None => vec![format!("error: Invalid address \"{}\", expected aa:bb:cc:dd:ee:ff", addr)],
+        },
+        ["ble", ..] => vec!["usage: ble passkey | ble bonds | ble forget <address|all>".to_string()],
+        _ => return None,
+    };
+
+    Some(printed)
+}
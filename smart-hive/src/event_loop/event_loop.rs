@@ -1,83 +1,78 @@
-use core::time::Duration;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 
 use esp_idf_svc::mqtt::client::*;
-use esp_idf_svc::sys::EspError;
 
 use log::*;
-use crate::MqttTopic;
 
-pub fn create_event_loop<F>(
-    client: &mut EspMqttClient<'_>,
-    connection: &mut EspMqttConnection,
-    mqtt_topics: &Vec<MqttTopic>,
-    mut on_message: F,
-) -> Result<(), EspError>
-where
-    F: FnMut(&str, &str) + Send + 'static,  // (topic, payload)
-{
-    std::thread::scope(|s| {
-        info!("About to start the MQTT client");
+/// Routes every received message (topic, payload). Shared so that a new listener can take over when the connection is recreated
+pub type MessageHandler = Arc<Mutex<dyn FnMut(&str, &str) + Send>>;
 
-        std::thread::Builder::new()
-            .stack_size(8192)
-            .spawn_scoped(s, move || {
-                info!("MQTT Listening for messages");
+/// What the listener reports to the connectivity supervisor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected,
+    /// The broker acknowledged a message (QoS 1 and 2)
+    Published(MessageId),
+    /// The client dropped a message it could not deliver in time
+    Deleted(MessageId),
+    /// The connection is gone for good, a new client has to be created
+    Closed,
+}
 
-                while let Ok(event) = connection.next() {
-                    match event.payload() {
-                        EventPayload::Received { topic, data, .. } => {
-                            if let Ok(payload) = std::str::from_utf8(data) {
-                                if let Some(topic_str) = topic {
-                                    info!("[{}] Received: {}", topic_str, payload);
-                                    on_message(topic_str, payload);
-                                } else {
-                                    warn!("Received message with no topic");
-                                }
+/// Listens for MQTT events on its own thread. Subscribing is left to the supervisor - the client
+/// cannot be used from this thread while an event is being handled
+pub fn spawn_listener(
+    mut connection: EspMqttConnection,
+    on_message: MessageHandler,
+    events: Sender<ConnectionEvent>,
+) -> std::io::Result<JoinHandle<()>> {
+    info!("About to start the MQTT client");
+
+    std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || {
+            info!("MQTT Listening for messages");
+
+            while let Ok(event) = connection.next() {
+                match event.payload() {
+                    EventPayload::Received { topic, data, .. } => {
+                        if let Ok(payload) = std::str::from_utf8(data) {
+                            if let Some(topic_str) = topic {
+                                info!("[{}] Received: {}", topic_str, payload);
+                                (on_message.lock().unwrap())(topic_str, payload);
                             } else {
-                                warn!("Received non-UTF8 payload on topic: {:?}", topic);
+                                warn!("Received message with no topic");
                             }
+                        } else {
+                            warn!("Received non-UTF8 payload on topic: {:?}", topic);
                         }
-                        EventPayload::Connected(_) => {
-                            info!("MQTT Connected");
-                        }
-                        EventPayload::Disconnected => {
-                            warn!("MQTT Disconnected");
-                        }
-                        EventPayload::Error(e) => {
-                            error!("MQTT Error: {:?}", e);
-                        }
-                        _ => {}
                     }
-                }
-
-                warn!("Connection closed - this shouldn't happen!");
-            })
-            .unwrap();
-
-        // Outer loop: Retry subscriptions until all succeed
-        loop {
-            let mut all_subscribed = true;
-
-            for mqtt_topic in mqtt_topics {
-                if let Err(e) = client.subscribe(mqtt_topic.topic, mqtt_topic.qos) {
-                    error!("Failed to subscribe to topic \"{}\": {:?}, retrying...", mqtt_topic.topic, e);
-                    all_subscribed = false;
-                    std::thread::sleep(Duration::from_millis(500));
-                    break;
+                    EventPayload::Connected(_) => {
+                        info!("MQTT Connected");
+                        let _ = events.send(ConnectionEvent::Connected);
+                    }
+                    EventPayload::Disconnected => {
+                        warn!("MQTT Disconnected");
+                        let _ = events.send(ConnectionEvent::Disconnected);
+                    }
+                    EventPayload::Published(msg_id) => {
+                        let _ = events.send(ConnectionEvent::Published(msg_id));
+                    }
+                    EventPayload::Deleted(msg_id) => {
+                        warn!("MQTT message {} expired before the broker acknowledged it", msg_id);
+                        let _ = events.send(ConnectionEvent::Deleted(msg_id));
+                    }
+                    EventPayload::Error(e) => {
+                        error!("MQTT Error: {:?}", e);
+                    }
+                    _ => {}
                 }
             }
 
-            if all_subscribed {
-                for mqtt_topic in mqtt_topics {
-                    info!("Subscribed to topic \"{}\"", mqtt_topic.topic);
-                }
-                std::thread::sleep(Duration::from_millis(500));
-
-                // Inner loop: Keep the main thread alive
-                loop {
-                    std::thread::sleep(Duration::from_millis(500));
-                }
-            }
-        }
-    })
-}
\ No newline at end of file
+            warn!("MQTT connection closed");
+            let _ = events.send(ConnectionEvent::Closed);
+        })
+}
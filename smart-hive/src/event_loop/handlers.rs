@@ -1,95 +1,65 @@
 use std::sync::{Arc, Mutex};
-use esp_idf_svc::mqtt::client::{EspMqttClient, QoS};
+use esp_idf_svc::mqtt::client::QoS;
 use log::*;
-use serde::Serialize;
-use software_defined_hive::controller::controller::{HiveCommand, HiveController};
+use software_defined_hive::analytics::anomaly::AnomalyDetector;
+use software_defined_hive::analytics::colony_health::ColonyHealthAnalyser;
+use software_defined_hive::controller::controller::{CommandResponse, HiveController};
+use software_defined_hive::controller::dispatcher::{dispatch, reply_to_json, CommandError, CommandReply};
 use software_defined_hive::state::actuators::HoneyCellDisplacer;
-use software_defined_hive::state::hive::HiveState;
 use software_defined_hive::state::sensors::SensorReadings;
+use software_defined_hive::utils::reading_merger::{Batch, HumiditySample, SensorReadingMerger, TemperatureSample};
+use software_defined_hive::utils::telemetry::TelemetryPublisher;
+use crate::event_loop::notifications::{collect, collect_command, publish};
+use crate::mqtt::publisher::Publisher;
+use crate::mqtt::topics::{OutboundTopic, TopicSchema};
 
-/// Handler for all hive commands received as MQTT messages
+/// Handler for all hive commands received as MQTT messages, addressed to this hive or broadcast, a thin adapter over the dispatcher
 /// qos is the quality of service (QoS)
+/// topics is the schema of this hive's topics, responses always go to the hive's own responses topic
 pub fn handle_command<H: HoneyCellDisplacer>(
     payload: &str,
     controller: &Arc<Mutex<HiveController<H>>>,
-    client: &Arc<Mutex<EspMqttClient<'_>>>,
+    publisher: &Publisher,
+    topics: &TopicSchema,
     qos: &QoS,
 ) {
-    match serde_json::from_str::<HiveCommand>(payload) {
-        Ok(command) => {
-            info!("Received command: {:?}", command);
-
-            let mut ctrl = controller.lock().unwrap();
-            match ctrl.process_command(command) {
-                Ok(response) => {
-                    info!("Command processed successfully. New state: {:?}", ctrl.state());
-
-                    if let Some(resp) = response {
-                        publish_message(client, "smart-hive/responses", &resp, qos);
-                    }
-                }
-                Err(e) => {
-                    error!("Command failed: {}", e);
-
-                    let error_response = format!(r#"{{"status":"error","message":"{}"}}"#, e);
-                    publish_message(client, "smart-hive/responses", &error_response, &QoS::AtLeastOnce); // AtLeastOnce because duplicates won't hurt - it's just an error message
-                }
-            }
-        }
-        Err(e) => {
-            error!("Failed to parse command: {}", e);
+    let responses_topic = topics.outbound(OutboundTopic::Responses);
+
+    let mut ctrl = controller.lock().unwrap();
+    let outcome = dispatch(&mut ctrl, payload, &mut |reply: &CommandReply| match reply {
+        // Nobody waits for an acknowledgement on MQTT and nobody can be told about an unparsable payload, both are only logged
+        Ok(CommandResponse::Ack(_)) | Err(CommandError::Invalid(_)) => {}
+        Ok(_) => publisher.send(&responses_topic, &reply_to_json(reply), *qos),
+        Err(CommandError::Failed(_)) => {
+            publisher.send(&responses_topic, &reply_to_json(reply), QoS::AtLeastOnce); // AtLeastOnce because duplicates won't hurt - it's just an error message
         }
+    });
+
+    for notification in collect_command(&mut ctrl, &outcome.events) {
+        publish(publisher, &notification);
     }
 }
 
-/// Handler for sensor readings
-/// qos the quality of service (QoS)
+/// Handler for sensor readings, the payload is a single `SensorReadings` or an array of them
+/// publisher sends the notifications, or keeps them until the hive is back online
+/// merger fills in the temperature and humidity reported on their own topics
+/// anomaly_detector runs on every reading to raise swarming/theft/robbing alerts, with the thresholds of the policy
+/// colony_health evaluates the brood temperature to raise colony health warnings, with the thresholds of the policy
+/// telemetry collects the readings, the status reporter publishes them at the telemetry interval
 pub fn handle_sensor_reading<H: HoneyCellDisplacer>(
     payload: &str,
     controller: &Arc<Mutex<HiveController<H>>>,
-    client: &Arc<Mutex<EspMqttClient<'_>>>,
-    qos: &QoS,
+    publisher: &Publisher,
+    merger: &mut SensorReadingMerger,
+    anomaly_detector: &mut dyn AnomalyDetector,
+    colony_health: &mut ColonyHealthAnalyser,
+    telemetry: &Mutex<TelemetryPublisher>,
 ) {
-    match serde_json::from_str::<SensorReadings>(payload) {
-        Ok(reading) => {
-            let mut ctrl = controller.lock().unwrap();
-            let previous_state = ctrl.state();
-
-            // Update controller with sensor reading
-            ctrl.update(reading);
-
-            let new_state = ctrl.state();
-
-            // Notify on state changes
-            if previous_state != new_state {
-                info!("State transition: {:?} -> {:?}", previous_state, new_state);
-
-                // Publish state change notification
-                let notification = StateChangeNotification {
-                    previous_state,
-                    new_state,
-                    weight_g: reading.weight_g,
-                    timestamp_s: reading.timestamp_s,
-                };
-
-                if let Ok(json) = serde_json::to_string(&notification) {
-                    publish_message(client, "smart-hive/notifications/state-change", &json, qos);
-                }
-
-                // Special notification when harvest is ready
-                if new_state == HiveState::Ready {
-                    info!("The Hive is harvest-ready! Net Weight: {}g", reading.weight_g);
-
-                    let ready_notification = HarvestReadyNotification {
-                        message: "Harvest is ready for authorization".to_string(),
-                        weight_g: reading.weight_g,
-                        timestamp_s: reading.timestamp_s,
-                    };
-
-                    if let Ok(json) = serde_json::to_string(&ready_notification) {
-                        publish_message(client, "smart-hive/notifications/harvest-ready", &json, &QoS::AtLeastOnce); // AtLeastOnce because duplicates won't hurt - it's just an error message
-                    }
-                }
+    match serde_json::from_str::<Batch<SensorReadings>>(payload) {
+        Ok(batch) => {
+            // Oldest first, the FSM and the analysers expect readings in order
+            for reading in merger.merge(batch.into_vec()) {
+                process_sensor_reading(reading, controller, publisher, anomaly_detector, colony_health, telemetry);
             }
         }
         Err(e) => {
@@ -98,36 +68,58 @@ pub fn handle_sensor_reading<H: HoneyCellDisplacer>(
     }
 }
 
-/// Helper function to publish messages (this logic is repetitive)
-/// qos is the quality of service (QoS)
-fn publish_message(
-    client: &Arc<Mutex<EspMqttClient<'_>>>,
-    topic: &str,
-    message: &str,
-    qos: &QoS,
-) {
-    let mut mqtt_client = client.lock().unwrap();
-    if let Err(e) = mqtt_client.enqueue(
-        topic,
-        qos.to_owned(),
-        false,
-        message.as_bytes()
-    ) {
-        error!("Failed to publish to {}: {}", topic, e);
+/// Handler for temperature samples (single or batched) reported by external sensor nodes, they are merged into the next weight readings
+pub fn handle_temperature_samples(payload: &str, merger: &mut SensorReadingMerger) {
+    match serde_json::from_str::<Batch<TemperatureSample>>(payload) {
+        Ok(batch) => merger.push_temperatures(batch.into_vec()),
+        Err(e) => error!("Failed to parse temperature samples: {}", e),
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct StateChangeNotification {
-    pub previous_state: HiveState,
-    pub new_state: HiveState,
-    pub weight_g: u32,
-    pub timestamp_s: u64,
+/// Handler for humidity samples (single or batched) reported by external sensor nodes, they are merged into the next weight readings
+pub fn handle_humidity_samples(payload: &str, merger: &mut SensorReadingMerger) {
+    match serde_json::from_str::<Batch<HumiditySample>>(payload) {
+        Ok(batch) => merger.push_humidities(batch.into_vec()),
+        Err(e) => error!("Failed to parse humidity samples: {}", e),
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct HarvestReadyNotification {
-    pub message: String,
-    pub weight_g: u32,
-    pub timestamp_s: u64,
-}
\ No newline at end of file
+fn process_sensor_reading<H: HoneyCellDisplacer>(
+    reading: SensorReadings,
+    controller: &Arc<Mutex<HiveController<H>>>,
+    publisher: &Publisher,
+    anomaly_detector: &mut dyn AnomalyDetector,
+    colony_health: &mut ColonyHealthAnalyser,
+    telemetry: &Mutex<TelemetryPublisher>,
+) {
+    let mut ctrl = controller.lock().unwrap();
+    let previous_state = ctrl.state();
+
+    // Update controller with sensor reading
+    ctrl.update(reading);
+
+    // The anomaly detector sees the weight without the temperature drift of the load cells, a cold night is not a theft
+    let compensated = SensorReadings { weight_g: ctrl.last_compensated_weight_g().unwrap_or(reading.weight_g), ..reading };
+    anomaly_detector.configure(&ctrl.policy().anomaly);
+    for alert in anomaly_detector.observe(&compensated, previous_state) {
+        warn!("Anomaly detected: {:?} ({:?}), {}g lost in {}s", alert.kind, alert.severity, alert.weight_loss_g, alert.window_s);
+
+        let id = format!("anomaly/{:?}/{}", alert.kind, alert.timestamp_s);
+        publisher.publish_event(OutboundTopic::AnomalyAlert, id, &alert, QoS::AtLeastOnce, alert.timestamp_s); // AtLeastOnce because a missed theft alert is worse than a duplicate
+    }
+
+    colony_health.configure(&ctrl.policy().colony_health);
+    for warning in colony_health.observe(&reading) {
+        warn!("Colony health warning: {:?} ({:?}), mean brood temperature {}", warning.kind, warning.severity, warning.mean_temperature_x10);
+
+        let id = format!("colony-health/{:?}/{}", warning.kind, warning.timestamp_s);
+        publisher.publish_event(OutboundTopic::ColonyHealthAlert, id, &warning, QoS::AtLeastOnce, warning.timestamp_s);
+    }
+
+    // Notify on state changes and publish the events emitted by the controller while handling this reading
+    for notification in collect(&mut ctrl, previous_state, reading.timestamp_s) {
+        publish(publisher, &notification);
+    }
+
+    telemetry.lock().unwrap().observe(&reading);
+}
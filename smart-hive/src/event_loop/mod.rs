@@ -1,2 +1,6 @@
 pub mod event_loop;
-pub mod handlers;
\ No newline at end of file
+pub mod handlers;
+pub mod clock;
+pub mod console;
+pub mod status;
+pub mod notifications;
\ No newline at end of file
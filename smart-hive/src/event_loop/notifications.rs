@@ -0,0 +1,173 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use esp_idf_svc::mqtt::client::QoS;
+use log::*;
+use serde::Serialize;
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::controller::dispatcher::CommandEvent;
+use software_defined_hive::controller::events::HiveEvent;
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use software_defined_hive::state::hive::HiveState;
+use crate::mqtt::publisher::Publisher;
+use crate::mqtt::topics::OutboundTopic;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StateChangeNotification {
+    pub previous_state: HiveState,
+    pub new_state: HiveState,
+    /// Filtered weight (grams)
+    pub weight_g: u32,
+    /// Weight as reported by the sensor (grams)
+    pub raw_weight_g: u32,
+    pub timestamp_s: u64,
+}
+
+impl StateChangeNotification {
+    /// The hive enters a state once at a given time
+    fn event_id(&self) -> String {
+        format!("state-change/{}/{:?}", self.timestamp_s, self.new_state)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HarvestReadyNotification {
+    pub message: String,
+    pub weight_g: u32,
+    pub timestamp_s: u64,
+}
+
+/// What the hive tells the broker after the controller changed, whatever changed it: a reading, a tick or a command
+#[derive(Debug, Clone)]
+pub enum Notification {
+    StateChange(StateChangeNotification),
+    HarvestReady(HarvestReadyNotification),
+    Event(HiveEvent),
+}
+
+/// The notifications of a transition from `previous_state` at `timestamp_s`, and of the events the controller emitted
+pub fn collect<H: HoneyCellDisplacer>(
+    ctrl: &mut HiveController<H>,
+    previous_state: HiveState,
+    timestamp_s: u64,
+) -> Vec<Notification> {
+    let mut notifications = Vec::new();
+    let new_state = ctrl.state();
+
+    if previous_state != new_state {
+        info!("State transition: {:?} -> {:?}", previous_state, new_state);
+
+        let status = ctrl.get_status();
+        let weight_g = status.last_weight_g.unwrap_or_default();
+        notifications.push(Notification::StateChange(StateChangeNotification {
+            previous_state,
+            new_state,
+            weight_g,
+            raw_weight_g: status.last_raw_weight_g.unwrap_or_default(),
+            timestamp_s,
+        }));
+
+        // Special notification when harvest is ready
+        if new_state == HiveState::Ready {
+            info!("The Hive is harvest-ready! Net Weight: {}g", weight_g);
+
+            notifications.push(Notification::HarvestReady(HarvestReadyNotification {
+                message: "Harvest is ready for authorization".to_string(),
+                weight_g,
+                timestamp_s,
+            }));
+        }
+    }
+
+    notifications.extend(ctrl.take_events().into_iter().map(Notification::Event));
+    notifications
+}
+
+/// The notifications of a command, from its events, at the time of the last reading
+pub fn collect_command<H: HoneyCellDisplacer>(ctrl: &mut HiveController<H>, events: &[CommandEvent]) -> Vec<Notification> {
+    let previous_state = events
+        .iter()
+        .find_map(|event| match event {
+            CommandEvent::StateChanged(transition) => Some(transition.previous_state),
+            CommandEvent::PolicyChanged => None,
+        })
+        .unwrap_or(ctrl.state());
+
+    let timestamp_s = ctrl.last_reading_at().unwrap_or_default();
+    collect(ctrl, previous_state, timestamp_s)
+}
+
+pub fn publish(publisher: &Publisher, notification: &Notification) {
+    match notification {
+        Notification::StateChange(notification) => {
+            publisher.publish_event(OutboundTopic::StateChange, notification.event_id(), notification, QoS::ExactlyOnce, notification.timestamp_s);
+        }
+        Notification::HarvestReady(notification) => {
+            let id = format!("harvest-ready/{}", notification.timestamp_s);
+            publisher.publish_event(OutboundTopic::HarvestReady, id, notification, QoS::AtLeastOnce, notification.timestamp_s); // AtLeastOnce because duplicates won't hurt
+        }
+        Notification::Event(HiveEvent::HarvestReport(report)) => {
+            info!("Harvest report: {}g yield", report.yield_g);
+
+            // A harvest starts once, its start identifies it
+            let id = format!("harvest-report/{}", report.started_at);
+            publisher.publish_event(OutboundTopic::HarvestReport, id, report, QoS::AtLeastOnce, report.completed_at); // AtLeastOnce because a lost report is lost yield data
+        }
+    }
+}
+
+/// Hands the notifications of the threads without a `Publisher` (the ticker, the local transports) to the publisher's thread.
+/// Unbounded, a harvest report must not be dropped nor the controller held while the publisher catches up, the outbox bounds them
+#[derive(Clone)]
+pub struct Notifier(Sender<Notification>);
+
+impl Notifier {
+    /// Collects the notifications of what changed since `previous_state` and queues them
+    pub fn notify<H: HoneyCellDisplacer>(&self, ctrl: &mut HiveController<H>, previous_state: HiveState, timestamp_s: u64) {
+        self.queue(collect(ctrl, previous_state, timestamp_s));
+    }
+
+    /// Queues the notifications of a command received over a local transport
+    pub fn notify_command<H: HoneyCellDisplacer>(&self, ctrl: &mut HiveController<H>, events: &[CommandEvent]) {
+        self.queue(collect_command(ctrl, events));
+    }
+
+    fn queue(&self, notifications: Vec<Notification>) {
+        for notification in notifications {
+            if let Err(e) = self.0.send(notification) {
+                error!("The notification publisher is gone, lost {:?}", e.0);
+            }
+        }
+    }
+}
+
+pub fn notification_channel() -> (Notifier, Receiver<Notification>) {
+    let (sender, receiver) = channel();
+    (Notifier(sender), receiver)
+}
+
+/// Publishes the queued notifications on its own thread, started once the publisher exists (an offline one for a BLE-only hive)
+pub fn spawn_notification_publisher(notifications: Receiver<Notification>, publisher: Publisher) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(6144)
+        .spawn(move || {
+            for notification in notifications {
+                publish(&publisher, &notification);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Logs the notifications of a hive that was never provisioned, it has no topics to keep them for
+#[cfg(feature = "ble")]
+pub fn spawn_notification_logger(notifications: Receiver<Notification>) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            for notification in notifications {
+                warn!("Not provisioned, nothing to publish {:?} to", notification);
+            }
+        })?;
+
+    Ok(())
+}
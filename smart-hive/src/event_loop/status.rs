@@ -0,0 +1,56 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::mqtt::client::QoS;
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use software_defined_hive::utils::clock::ReadingClock;
+use software_defined_hive::utils::telemetry::TelemetryPublisher;
+use crate::mqtt::publisher::Publisher;
+use crate::mqtt::topics::OutboundTopic;
+use crate::telemetry::system::system_stats;
+
+/// How often the status and the telemetry are checked for changes
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the retained status up to date on its own thread, whatever changed it: a reading, a tick or a command from any transport.
+/// Also closes the telemetry intervals, with the configs of the policy, so telemetry keeps going when no reading arrives
+pub fn spawn_status_reporter<H: HoneyCellDisplacer + Send + 'static>(
+    controller: Arc<Mutex<HiveController<H>>>,
+    publisher: Publisher,
+    telemetry: Arc<Mutex<TelemetryPublisher>>,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(6144)
+        .spawn(move || {
+            let mut clock = ReadingClock::default();
+
+            loop {
+                std::thread::sleep(STATUS_INTERVAL);
+
+                // Not locked while publishing
+                let (status, last_reading_at) = {
+                    let ctrl = controller.lock().unwrap();
+                    (ctrl.get_status(), ctrl.last_reading_at())
+                };
+                publisher.publish_status(&status);
+
+                clock.observe(last_reading_at);
+                let Some(now_s) = clock.now_s() else {
+                    continue;
+                };
+
+                let message = {
+                    let mut telemetry = telemetry.lock().unwrap();
+                    telemetry.configure(&status.policy.telemetry);
+                    telemetry.poll(now_s, &status, system_stats())
+                };
+                if let Some(message) = message {
+                    let id = format!("telemetry/{}", message.timestamp_s);
+                    publisher.publish_event(OutboundTopic::Telemetry, id, &message, QoS::AtMostOnce, message.timestamp_s); // AtMostOnce because the next interval makes up for a lost one
+                }
+            }
+        })?;
+
+    Ok(())
+}
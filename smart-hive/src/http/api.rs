@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+use software_defined_hive::api::rest::{self, authorize, Endpoint, RestResponse};
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use crate::event_loop::notifications::Notifier;
+use crate::provisioning::settings::DEVICE_NVS_NAMESPACE;
+use crate::storage::nvs::read_str;
+
+/// Token given at build time, a token stored in NVS takes precedence
+const BUILD_API_TOKEN: Option<&str> = option_env!("HTTP_API_TOKEN");
+const NVS_API_TOKEN: &str = "api_token";
+
+/// A policy or a command is well below this
+const MAX_BODY_LEN: usize = 4096;
+
+/// Serializing the status and the history takes more than the default stack
+const STACK_SIZE: usize = 10 * 1024;
+
+/// `None` when no token is configured, the API stays off rather than accept anyone on the network
+fn api_token(nvs: &EspDefaultNvsPartition) -> Option<String> {
+    let stored = EspNvs::new(nvs.clone(), DEVICE_NVS_NAMESPACE, true)
+        .and_then(|device| read_str(&device, NVS_API_TOKEN))
+        .unwrap_or_else(|e| {
+            warn!("Failed to read the API token from NVS: {}", e);
+            None
+        });
+
+    let token = stored.or_else(|| BUILD_API_TOKEN.filter(|token| !token.is_empty()).map(str::to_string));
+    if token.is_none() {
+        error!("No HTTP_API_TOKEN given, the HTTP API is disabled");
+    }
+    token
+}
+
+/// Starts the local REST API and the status page on port 80. `None` when no token is configured
+/// or the server fails to start. The server stops when dropped. What the commands change is notified like the MQTT ones
+pub fn start_api_server<H: HoneyCellDisplacer + Send + 'static>(
+    nvs: &EspDefaultNvsPartition,
+    controller: Arc<Mutex<HiveController<H>>>,
+    notifier: Notifier,
+) -> Option<EspHttpServer<'static>> {
+    let token = api_token(nvs)?;
+
+    match register(token, controller, notifier) {
+        Ok(server) => {
+            info!("HTTP API listening on port 80");
+            Some(server)
+        }
+        Err(e) => {
+            error!("Failed to start the HTTP API: {}", e);
+            None
+        }
+    }
+}
+
+fn register<H: HoneyCellDisplacer + Send + 'static>(
+    token: String,
+    controller: Arc<Mutex<HiveController<H>>>,
+    notifier: Notifier,
+) -> Result<EspHttpServer<'static>, EspError> {
+    let mut server = EspHttpServer::new(&Configuration {
+        stack_size: STACK_SIZE,
+        ..Default::default()
+    })?;
+
+    let token = Arc::new(token);
+    for endpoint in Endpoint::ALL {
+        let method = match endpoint.method() {
+            rest::Method::Get => Method::Get,
+            rest::Method::Put => Method::Put,
+            rest::Method::Post => Method::Post,
+        };
+
+        let token = Arc::clone(&token);
+        let controller = Arc::clone(&controller);
+        let notifier = notifier.clone();
+        server.fn_handler(endpoint.path(), method, move |request| serve(request, endpoint, &token, &controller, &notifier))?;
+    }
+
+    Ok(server)
+}
+
+/// A thin adapter: checks the token, reads the body and lets the host-side API answer
+fn serve<H: HoneyCellDisplacer>(
+    mut request: Request<&mut EspHttpConnection>,
+    endpoint: Endpoint,
+    token: &str,
+    controller: &Mutex<HiveController<H>>,
+    notifier: &Notifier,
+) -> Result<(), EspIOError> {
+    let query = request.uri().split_once('?').map(|(_, query)| query);
+    let response = if !authorize(token, request.header("Authorization"), query) {
+        warn!("Unauthorized request to {}", endpoint.path());
+        RestResponse::unauthorized()
+    } else {
+        match read_body(&mut request)? {
+            Some(body) => {
+                let mut ctrl = controller.lock().unwrap();
+                let (response, events) = rest::handle(&mut ctrl, endpoint, &body);
+                notifier.notify_command(&mut ctrl, &events);
+                response
+            }
+            None => RestResponse::too_large(),
+        }
+    };
+
+    request
+        .into_response(response.status, None, &[("Content-Type", response.content_type)])?
+        .write_all(response.body.as_bytes())
+}
+
+/// `None` when the body is larger than `MAX_BODY_LEN`
+fn read_body(request: &mut Request<&mut EspHttpConnection>) -> Result<Option<String>, EspIOError> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = request.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        body.extend_from_slice(&buf[..len]);
+        if body.len() > MAX_BODY_LEN {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
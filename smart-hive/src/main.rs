@@ -1,6 +1,13 @@
 mod mqtt;
 mod wi_fi;
 mod event_loop;
+mod telemetry;
+mod storage;
+mod connectivity;
+mod provisioning;
+mod http;
+#[cfg(feature = "ble")]
+mod ble;
 
 use std::sync::{Arc, Mutex};
 use esp_idf_hal::gpio::*;
@@ -8,29 +15,50 @@ use esp_idf_hal::ledc::{
     LedcDriver, LedcTimerDriver, Resolution, config::TimerConfig,
 };
 use esp_idf_hal::prelude::*;
+use esp_idf_hal::uart::{config::Config as UartConfig, UartDriver};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use hardware_abstraction::mcus::hal_esp32::Esp32Actuator;
 use std::time::Duration;
-use esp_idf_svc::mqtt::client::QoS;
-use crate::event_loop::event_loop::create_event_loop;
+use crate::connectivity::supervisor::{ConnectivitySupervisor, MqttSettings};
+use crate::event_loop::clock::spawn_ticker;
+use crate::event_loop::console::spawn_console;
+use crate::event_loop::notifications::{notification_channel, spawn_notification_publisher};
+#[cfg(feature = "ble")]
+use crate::event_loop::notifications::spawn_notification_logger;
+use crate::event_loop::status::spawn_status_reporter;
+use crate::http::api::start_api_server;
+use crate::mqtt::credentials::MqttCredentials;
 use crate::mqtt::mqtt::mqtt_create;
-use crate::wi_fi::wi_fi::wifi_create;
+use crate::mqtt::publisher::Publisher;
+use crate::mqtt::topics::{InboundTopic, OutboundTopic, TopicSchema};
+use crate::provisioning::button::spawn_button_watcher;
+use crate::provisioning::portal::run_portal;
+use crate::provisioning::settings::{take_provisioning_request, DeviceSettings};
+use crate::wi_fi::wi_fi::{has_networks, WifiManager};
+use esp_idf_hal::reset::restart;
 use log::*;
+use software_defined_hive::analytics::anomaly::WeightTrendDetector;
+use software_defined_hive::analytics::colony_health::ColonyHealthAnalyser;
 use software_defined_hive::controller::controller::HiveController;
 use software_defined_hive::state::policy::harvest::HarvestPolicyConfigs;
-use crate::event_loop::handlers::{handle_command, handle_sensor_reading};
+use software_defined_hive::utils::telemetry::TelemetryPublisher;
+use software_defined_hive::utils::reading_merger::SensorReadingMerger;
+use crate::event_loop::event_loop::MessageHandler;
+use crate::event_loop::handlers::{handle_command, handle_humidity_samples, handle_sensor_reading, handle_temperature_samples};
 
-#[derive(Debug)]
-struct MqttTopic<'a> {
-    topic: &'a str,
-    qos: QoS,
-}
+/// Optional, the credentials entered on the provisioning page take precedence
+const MQTT_USERNAME: &str = match option_env!("MQTT_USERNAME") {
+    Some(username) => username,
+    None => "",
+};
+const MQTT_PASSWORD: &str = match option_env!("MQTT_PASSWORD") {
+    Some(password) => password,
+    None => "",
+};
 
-const MQTT_BROKER_URL: &str = env!("MQTT_BROKER_URL");
-const MQTT_CLIENT_ID: &str = env!("MQTT_CLIENT_ID");
-const MQTT_USERNAME: &str = env!("MQTT_USERNAME");
-const MQTT_PASSWORD: &str = env!("MQTT_PASSWORD");
+/// Temperature and humidity samples further than this from a weight reading (seconds) are not merged into it
+const SENSOR_MERGE_TOLERANCE_S: u64 = 60;
 
 fn main() {
     // Initialize ESP-IDF runtime
@@ -41,6 +69,7 @@ fn main() {
         pins,
         ledc,
         modem,
+        uart0,
         ..
     } = Peripherals::take().unwrap();
 
@@ -90,45 +119,144 @@ fn main() {
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let nvs = EspDefaultNvsPartition::take().unwrap();
 
-    let _wifi = wifi_create(&sys_loop, &nvs, modem).unwrap();
+    // Provisioning on first boot (nothing configured) or after a long press of the BOOT button
+    let requested = take_provisioning_request(&nvs);
+    let device = DeviceSettings::load(&nvs).unwrap_or_else(|e| {
+        warn!("Failed to load the device settings: {}", e);
+        None
+    });
+
+    let mut button = PinDriver::input(pins.gpio0).unwrap();
+    button.set_pull(Pull::Up).unwrap();
+    spawn_button_watcher(button, nvs.clone(), Arc::clone(&controller)).unwrap();
+
+    // State changes and harvest reports of the threads without MQTT, published once MQTT is up
+    let (notifier, notifications) = notification_channel();
+
+    // The harvest timeouts are enforced even when no reading arrives
+    spawn_ticker(Arc::clone(&controller), notifier.clone()).unwrap();
+
+    // Bench console on the USB serial port (UART0), shared with the logs
+    let console_uart = UartDriver::new(
+        uart0,
+        pins.gpio1,
+        pins.gpio3,
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &UartConfig::default().baudrate(Hertz(115_200)),
+    )
+    .unwrap();
+    spawn_console(console_uart, Arc::clone(&controller), nvs.clone(), notifier.clone()).unwrap();
+
+    // Wi-Fi and BLE share the radio
+    #[cfg(feature = "ble")]
+    let (modem, bt_modem) = modem.split();
+    #[cfg(feature = "ble")]
+    let ble = ble::start_ble(bt_modem, &nvs, device.as_ref().map(|device| device.hive_id.as_str()), Arc::clone(&controller), notifier.clone());
+
+    let device = match device {
+        Some(device) if !requested && has_networks(&nvs) => device,
+        // Apiaries without Wi-Fi: the hive is controlled (and can be provisioned) over BLE only
+        #[cfg(feature = "ble")]
+        _ if !requested && ble.is_some() => {
+            info!("No Wi-Fi configured, the hive is only reachable over BLE");
+            // The notifications wait in the outbox, in NVS, and are published once the hive boots with Wi-Fi
+            match device.as_ref().map(|device| TopicSchema::new(&device.apiary, &device.hive_id)) {
+                Some(Ok(topics)) => spawn_notification_publisher(notifications, Publisher::offline(topics, &nvs)).unwrap(),
+                Some(Err(e)) => {
+                    error!("Invalid device settings, the notifications are only logged: {}", e);
+                    spawn_notification_logger(notifications).unwrap();
+                }
+                None => spawn_notification_logger(notifications).unwrap(),
+            }
+            loop {
+                std::thread::sleep(Duration::from_secs(60));
+            }
+        }
+        _ => {
+            info!("Entering provisioning mode");
+            let Err(e) = run_portal(&sys_loop, &nvs, modem);
+            error!("Provisioning portal failed: {}", e);
+            restart();
+        }
+    };
+
+    let mut wifi = WifiManager::new(&sys_loop, &nvs, modem).unwrap();
+    if let Err(e) = wifi.connect() {
+        // The connectivity supervisor keeps retrying
+        warn!("Wi-Fi is not connected at boot: {}", e);
+    }
+
+    // Local control while the broker is unreachable, served whenever the station is connected
+    let _api = start_api_server(&nvs, Arc::clone(&controller), notifier.clone());
+
+    // Credentials stored in NVS take precedence over the ones the firmware was built with
+    let mut credentials = MqttCredentials::from_build(MQTT_USERNAME, MQTT_PASSWORD);
+    if let Err(e) = credentials.override_from_nvs(&nvs) {
+        info!("No MQTT credentials in NVS ({}), using the ones the firmware was built with", e);
+    }
 
-    let (mut client, mut conn) = mqtt_create(MQTT_BROKER_URL, MQTT_CLIENT_ID, None, None).unwrap();
+    let topics = TopicSchema::new(&device.apiary, &device.hive_id).unwrap();
+    let availability_topic = topics.outbound(OutboundTopic::Availability);
+
+    let (client, conn) = mqtt_create(&device.broker_url, &device.client_id, &credentials, &availability_topic).unwrap();
 
     // Clone for the closure
     let controller_clone = Arc::clone(&controller);
 
+    // Moved into the closure, only the message handler uses them. Their thresholds follow the policy
+    let policy = controller.lock().unwrap().policy().clone();
+    let mut anomaly_detector = WeightTrendDetector::new(policy.anomaly);
+    let mut colony_health = ColonyHealthAnalyser::new(policy.colony_health);
+    let mut reading_merger = SensorReadingMerger::new(SENSOR_MERGE_TOLERANCE_S);
+    // The readings are collected by the message handler and published by the status reporter
+    let telemetry = Arc::new(Mutex::new(TelemetryPublisher::new(policy.telemetry)));
+
     // Clone client for publishing responses (need to wrap in Arc<Mutex> for thread safety)
     let client = Arc::new(Mutex::new(client));
-    let client_clone = Arc::clone(&client);
-
-    // Create event loop with message handler. (Using MutexGuard to prevent data races)
-    let mut mqtt_client = client.lock().unwrap();
-
-    // Subscribe to multiple topics
-    let mqtt_topics: Vec<MqttTopic> = vec![
-        MqttTopic { topic: "smart-hive/commands", qos: QoS::AtMostOnce },
-        MqttTopic { topic: "smart-hive/sensors/weight", qos: QoS::ExactlyOnce },
-    ];
-
-    // Create event loop with message router
-    create_event_loop(
-        &mut mqtt_client,
-        &mut conn,
-        &mqtt_topics,
-        move |topic, payload| {
-            match topic {
-                "smart-hive/commands" => {
-                    // This has to be precise
-                    handle_command(payload, &controller_clone, &client_clone, &QoS::AtMostOnce);
+    // Events published while offline are kept, in NVS, until the hive is back online
+    let publisher = Publisher::new(Arc::clone(&client), topics.clone(), &nvs);
+    let publisher_clone = publisher.clone();
+
+    spawn_notification_publisher(notifications, publisher.clone()).unwrap();
+
+    // Retained, so dashboards get the latest status as soon as they subscribe
+    spawn_status_reporter(Arc::clone(&controller), publisher.clone(), Arc::clone(&telemetry)).unwrap();
+
+    let topics_clone = topics.clone();
+
+    // Message router, shared with the listeners of the connections the supervisor recreates
+    let on_message: MessageHandler = Arc::new(Mutex::new(
+        move |topic: &str, payload: &str| {
+            match topics_clone.route(topic) {
+                Some(InboundTopic::Commands) => {
+                    handle_command(payload, &controller_clone, &publisher_clone, &topics_clone, &InboundTopic::Commands.qos());
+                }
+                Some(InboundTopic::WeightReadings) => {
+                    handle_sensor_reading(payload, &controller_clone, &publisher_clone, &mut reading_merger, &mut anomaly_detector, &mut colony_health, &telemetry);
+                }
+                Some(InboundTopic::TemperatureSamples) => {
+                    handle_temperature_samples(payload, &mut reading_merger);
                 }
-                "smart-hive/sensors/weight" => {
-                    // Our sensors can fire and forget, they will be publishing periodically, so no harm if we lose a packet or two
-                    handle_sensor_reading(payload, &controller_clone, &client_clone, &QoS::ExactlyOnce);
+                Some(InboundTopic::HumiditySamples) => {
+                    handle_humidity_samples(payload, &mut reading_merger);
                 }
-                _ => {
+                None => {
                     warn!("Received message on unknown topic: {:?}", topic);
                 }
             }
         },
-    ).unwrap();
+    ));
+
+    let mqtt = MqttSettings {
+        url: device.broker_url,
+        client_id: device.client_id,
+        credentials,
+        availability_topic,
+    };
+
+    // Keeps Wi-Fi and MQTT connected and the topics subscribed, forever
+    ConnectivitySupervisor::new(wifi, mqtt, client, publisher, conn, topics.subscriptions(), on_message)
+        .unwrap()
+        .run();
 }
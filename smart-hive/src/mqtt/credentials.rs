@@ -0,0 +1,123 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::tls::X509;
+
+use log::*;
+use crate::storage::nvs::{read_blob, read_str};
+
+/// NVS namespace of the MQTT credentials, its entries override the ones the firmware was built with
+pub const MQTT_NVS_NAMESPACE: &str = "mqtt";
+
+const NVS_USERNAME: &str = "username";
+const NVS_PASSWORD: &str = "password";
+const NVS_CA_CERTIFICATE: &str = "ca_cert";
+const NVS_CLIENT_CERTIFICATE: &str = "client_cert";
+const NVS_CLIENT_KEY: &str = "client_key";
+
+// PEM files given at build time through MQTT_CA_CERT_PATH, MQTT_CLIENT_CERT_PATH and MQTT_CLIENT_KEY_PATH (see build.rs),
+// NUL terminated and empty when not given
+const BUILD_CA_CERTIFICATE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mqtt_ca_cert.pem"));
+const BUILD_CLIENT_CERTIFICATE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mqtt_client_cert.pem"));
+const BUILD_CLIENT_KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/mqtt_client_key.pem"));
+
+/// How the hive authenticates to the broker and the broker to the hive.
+/// The certificates are NUL terminated PEM, they must outlive the MQTT client so they are `'static`
+#[derive(Clone, Default)]
+pub struct MqttCredentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// CA that signed the broker's certificate. Without it an `mqtts://` broker is verified against the bundled root CAs
+    pub ca_certificate: Option<&'static [u8]>,
+    /// Client certificate and key for mutual TLS
+    pub client_certificate: Option<&'static [u8]>,
+    pub client_key: Option<&'static [u8]>,
+}
+
+impl MqttCredentials {
+    /// Credentials the firmware was built with
+    pub fn from_build(username: &str, password: &str) -> Self {
+        Self {
+            username: (!username.is_empty()).then(|| username.to_string()),
+            password: (!password.is_empty()).then(|| password.to_string()),
+            ca_certificate: non_empty(BUILD_CA_CERTIFICATE),
+            client_certificate: non_empty(BUILD_CLIENT_CERTIFICATE),
+            client_key: non_empty(BUILD_CLIENT_KEY),
+        }
+    }
+
+    /// Replaces the credentials with the ones stored in NVS, so they can be rotated without reflashing.
+    /// Entries missing from NVS keep their current value
+    pub fn override_from_nvs(&mut self, nvs: &EspDefaultNvsPartition) -> Result<(), EspError> {
+        let nvs = EspNvs::new(nvs.clone(), MQTT_NVS_NAMESPACE, false)?;
+
+        if let Some(username) = read_str(&nvs, NVS_USERNAME)? {
+            info!("Using the MQTT username stored in NVS");
+            self.username = Some(username);
+        }
+        if let Some(password) = read_str(&nvs, NVS_PASSWORD)? {
+            self.password = Some(password);
+        }
+        if let Some(certificate) = read_pem(&nvs, NVS_CA_CERTIFICATE)? {
+            info!("Using the MQTT CA certificate stored in NVS");
+            self.ca_certificate = Some(certificate);
+        }
+        if let Some(certificate) = read_pem(&nvs, NVS_CLIENT_CERTIFICATE)? {
+            info!("Using the MQTT client certificate stored in NVS");
+            self.client_certificate = Some(certificate);
+        }
+        if let Some(key) = read_pem(&nvs, NVS_CLIENT_KEY)? {
+            self.client_key = Some(key);
+        }
+
+        Ok(())
+    }
+
+    pub fn ca_certificate(&self) -> Option<X509<'static>> {
+        self.ca_certificate.map(X509::pem_until_nul)
+    }
+
+    /// Client certificate and key, only when both are present
+    pub fn client_identity(&self) -> Option<(X509<'static>, X509<'static>)> {
+        match (self.client_certificate, self.client_key) {
+            (Some(certificate), Some(key)) => Some((X509::pem_until_nul(certificate), X509::pem_until_nul(key))),
+            (None, None) => None,
+            _ => {
+                warn!("Mutual TLS needs both a client certificate and a client key, connecting without them");
+                None
+            }
+        }
+    }
+}
+
+/// Stores the username and password, `None` removes the entry so the one the firmware was built with is used again
+pub fn store_credentials(nvs: &EspDefaultNvsPartition, username: Option<&str>, password: Option<&str>) -> Result<(), EspError> {
+    let mut nvs = EspNvs::new(nvs.clone(), MQTT_NVS_NAMESPACE, true)?;
+
+    for (key, value) in [(NVS_USERNAME, username), (NVS_PASSWORD, password)] {
+        match value {
+            Some(value) => nvs.set_str(key, value)?,
+            None => {
+                nvs.remove(key)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn non_empty(bytes: &'static [u8]) -> Option<&'static [u8]> {
+    (!bytes.is_empty() && bytes != [0]).then_some(bytes)
+}
+
+/// Reads a PEM blob and leaks it - it is read once at boot and has to live as long as the MQTT client
+fn read_pem(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<&'static [u8]>, EspError> {
+    let Some(mut pem) = read_blob(nvs, key)? else {
+        return Ok(None);
+    };
+
+    if pem.last() != Some(&0) {
+        pem.push(0);
+    }
+
+    Ok(Some(Box::leak(pem.into_boxed_slice())))
+}
@@ -1 +1,4 @@
-pub mod mqtt;
\ No newline at end of file
+pub mod mqtt;
+pub mod topics;
+pub mod credentials;
+pub mod publisher;
\ No newline at end of file
@@ -2,23 +2,52 @@ use std::time::Duration;
 use esp_idf_svc::mqtt::client::*;
 use esp_idf_svc::sys::EspError;
 
+use log::*;
+use crate::mqtt::credentials::MqttCredentials;
+use crate::mqtt::topics::AVAILABILITY_OFFLINE;
+
+/// Connects to the broker, `mqtts://` urls use TLS with the CA (or the bundled root CAs) and the optional client certificate of the credentials.
+/// The broker publishes `offline` on `availability_topic` (retained) when the hive drops off
 pub fn mqtt_create(
     url: &str,
     client_id: &str,
-    username: Option<&str>,
-    password: Option<&str>,
+    credentials: &MqttCredentials,
+    availability_topic: &str,
 ) -> Result<(EspMqttClient<'static>, EspMqttConnection), EspError> {
+    let tls = url.starts_with("mqtts://");
+    let ca_certificate = credentials.ca_certificate();
+    let (client_certificate, private_key) = credentials.client_identity().unzip();
+
+    if !tls && (ca_certificate.is_some() || client_certificate.is_some()) {
+        warn!("Certificates are configured but the broker url is not mqtts://, connecting without TLS");
+    }
+
+    if credentials.username.is_none() {
+        warn!("No MQTT username configured, connecting anonymously");
+    }
+
     let (mqtt_client, mqtt_conn) = EspMqttClient::new(
         url,
         &MqttClientConfiguration {
             client_id: Some(client_id),
-            username,
-            password,
+            username: credentials.username.as_deref(),
+            password: credentials.password.as_deref(),
             keep_alive_interval: Some(Duration::from_secs(30)),
             disable_clean_session: false,
+            server_certificate: ca_certificate,
+            // Without a CA of our own the broker is verified against the root CAs bundled with ESP-IDF
+            crt_bundle_attach: (tls && credentials.ca_certificate.is_none()).then_some(esp_idf_svc::sys::esp_crt_bundle_attach as _),
+            client_certificate,
+            private_key,
+            lwt: Some(LwtConfiguration {
+                topic: availability_topic,
+                payload: AVAILABILITY_OFFLINE.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
             ..Default::default()
         },
     )?;
 
     Ok((mqtt_client, mqtt_conn))
-}
\ No newline at end of file
+}
@@ -0,0 +1,305 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::mqtt::client::{EspMqttClient, MessageId, QoS};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use serde::Serialize;
+
+use log::*;
+use software_defined_hive::controller::controller::HiveStatus;
+use software_defined_hive::utils::outbox::{MessagePriority, Outbox, PushOutcome, QueuedMessage};
+use crate::mqtt::topics::{OutboundTopic, TopicSchema, AVAILABILITY_ONLINE};
+use crate::storage::nvs::read_blob;
+
+/// NVS namespace of the messages waiting for the broker, so a reboot while offline does not lose them
+const OUTBOX_NVS_NAMESPACE: &str = "outbox";
+const NVS_MESSAGES: &str = "messages";
+
+/// Bounds of the outbox, the NVS partition is shared with the Wi-Fi and the credentials
+const MAX_MESSAGES: usize = 64;
+const MAX_BYTES: usize = 6 * 1024;
+
+struct PublisherState {
+    outbox: Outbox,
+    online: bool,
+    nvs: Option<EspNvs<NvsDefault>>,
+    /// Whether NVS holds messages, so that nothing is written while the outbox only passes messages through
+    persisted: bool,
+    /// The latest status and whether the broker retains it
+    status: Option<String>,
+    status_published: bool,
+}
+
+/// Publishes the hive's events, or stores them while the hive is offline and replays them in order once it is back.
+/// Also announces the hive on its availability topic and keeps its status retained
+#[derive(Clone)]
+pub struct Publisher {
+    /// `None` for a hive without Wi-Fi, its events wait in the outbox until it gets a connection
+    client: Option<Arc<Mutex<EspMqttClient<'static>>>>,
+    topics: TopicSchema,
+    state: Arc<Mutex<PublisherState>>,
+}
+
+impl Publisher {
+    /// Restores the messages an earlier boot could not send
+    pub fn new(client: Arc<Mutex<EspMqttClient<'static>>>, topics: TopicSchema, nvs: &EspDefaultNvsPartition) -> Self {
+        Self::with_client(Some(client), topics, nvs)
+    }
+
+    /// Keeps the events of a hive without Wi-Fi in NVS, they are published once it boots with a connection
+    #[cfg(feature = "ble")]
+    pub fn offline(topics: TopicSchema, nvs: &EspDefaultNvsPartition) -> Self {
+        Self::with_client(None, topics, nvs)
+    }
+
+    fn with_client(client: Option<Arc<Mutex<EspMqttClient<'static>>>>, topics: TopicSchema, nvs: &EspDefaultNvsPartition) -> Self {
+        let mut outbox = Outbox::new(MAX_MESSAGES, MAX_BYTES);
+
+        let mut persisted = false;
+        let nvs = EspNvs::new(nvs.clone(), OUTBOX_NVS_NAMESPACE, true)
+            .inspect_err(|e| error!("Failed to open the outbox in NVS, it is kept in RAM only: {}", e))
+            .ok();
+        if let Some(nvs) = &nvs {
+            match restore(nvs) {
+                Ok(messages) if !messages.is_empty() => {
+                    info!("{} messages from before the restart are waiting for the broker", messages.len());
+                    outbox.restore(messages);
+                    persisted = true;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to restore the outbox: {}", e),
+            }
+        }
+
+        Self {
+            client,
+            topics,
+            state: Arc::new(Mutex::new(PublisherState {
+                outbox,
+                online: false,
+                nvs,
+                persisted,
+                status: None,
+                status_published: false,
+            })),
+        }
+    }
+
+    /// Queues an event behind the ones waiting and sends what it can. `id` identifies the event, the same event is only published once
+    pub fn publish_event(
+        &self,
+        topic: OutboundTopic,
+        id: String,
+        event: &impl Serialize,
+        qos: QoS,
+        timestamp_s: u64,
+    ) {
+        let payload = match serde_json::to_string(event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to serialize {}: {}", id, e);
+                return;
+            }
+        };
+
+        let message = QueuedMessage {
+            id,
+            topic: self.topics.outbound(topic),
+            payload,
+            qos: qos as u8,
+            priority: topic.priority(),
+            timestamp_s,
+        };
+        let important = message.priority > MessagePriority::Low;
+
+        let mut state = self.state.lock().unwrap();
+        match state.outbox.push(message) {
+            PushOutcome::Queued { evicted } => {
+                for message in evicted {
+                    warn!("Outbox full, dropped {}", message.id);
+                }
+            }
+            PushOutcome::Duplicate => return,
+            PushOutcome::Dropped => {
+                warn!("Outbox full of more important messages, dropped an event on {}", self.topics.outbound(topic));
+                return;
+            }
+        }
+
+        if state.online {
+            self.flush(&mut state);
+        } else if important {
+            // Telemetry alone is not worth the flash writes, it is persisted with the next event
+            persist(&mut state);
+        }
+    }
+
+    /// Publishes without queueing, for replies nobody waits for once the hive is offline
+    pub fn send(&self, topic: &str, payload: &str, qos: QoS) {
+        self.enqueue(topic, payload, qos, false);
+    }
+
+    /// Retains the status on the broker when it differs from the one retained, so dashboards get it as soon as they subscribe.
+    /// While offline only the latest is kept, it is published once the hive is back
+    pub fn publish_status(&self, status: &HiveStatus) {
+        let json = match serde_json::to_string(status) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Failed to serialize the status: {}", e);
+                return;
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        // A status that failed to publish is retried on the next call, even when it did not change
+        if state.status_published && state.status.as_ref() == Some(&json) {
+            return;
+        }
+
+        state.status = Some(json);
+        state.status_published = false;
+        if state.online {
+            self.retain_status(&mut state);
+        }
+    }
+
+    fn retain_status(&self, state: &mut PublisherState) {
+        if let Some(status) = &state.status {
+            state.status_published = self.enqueue(&self.topics.outbound(OutboundTopic::Status), status, QoS::AtLeastOnce, true);
+        }
+    }
+
+    fn enqueue(&self, topic: &str, payload: &str, qos: QoS, retain: bool) -> bool {
+        let Some(client) = &self.client else {
+            return false;
+        };
+
+        match client.lock().unwrap().enqueue(topic, qos, retain, payload.as_bytes()) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to publish to {}: {}", topic, e);
+                false
+            }
+        }
+    }
+
+    /// Set by the connectivity supervisor. Once the hive is back online it is announced (the birth message, the last will
+    /// is `offline`), the latest status is retained and the waiting messages are replayed
+    pub fn set_online(&self, online: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.online == online {
+            return;
+        }
+
+        state.online = online;
+        if online {
+            self.enqueue(&self.topics.outbound(OutboundTopic::Availability), AVAILABILITY_ONLINE, QoS::AtLeastOnce, true);
+
+            // The broker may have lost the retained status while the hive was away
+            self.retain_status(&mut state);
+
+            if !state.outbox.is_empty() {
+                info!("Replaying {} messages published while offline", state.outbox.len());
+            }
+            self.flush(&mut state);
+        } else {
+            // A reboot while offline loses neither the messages waiting nor the ones the broker has not acknowledged
+            persist(&mut state);
+        }
+    }
+
+    /// Set by the connectivity supervisor once the broker acknowledged a message (QoS 1 and 2)
+    pub fn acknowledge(&self, msg_id: MessageId) {
+        let mut state = self.state.lock().unwrap();
+        if state.outbox.acknowledge(msg_id).is_some() && state.persisted && state.outbox.in_flight() == 0 {
+            // Once per replay rather than once per message, the flash wears out
+            persist(&mut state);
+        }
+    }
+
+    /// Set by the connectivity supervisor when the client dropped a message it held, e.g. one that expired
+    /// while the hive was offline. It is sent again
+    pub fn requeue(&self, msg_id: MessageId) {
+        let mut state = self.state.lock().unwrap();
+        if state.outbox.requeue(msg_id) && state.online {
+            self.flush(&mut state);
+        }
+    }
+
+    /// Set by the connectivity supervisor when the connection is closed: the client is recreated without
+    /// the messages it held, they are sent again through the new one
+    pub fn requeue_in_flight(&self) {
+        let requeued = self.state.lock().unwrap().outbox.requeue_in_flight();
+        if requeued > 0 {
+            info!("{} messages were not acknowledged by the broker, they are sent again", requeued);
+        }
+    }
+
+    /// Hands the waiting messages to the client, oldest first, until one fails. QoS 0 messages leave the outbox
+    /// right away, the others once the broker acknowledged them
+    fn flush(&self, state: &mut PublisherState) {
+        let Some(client) = &self.client else {
+            return;
+        };
+
+        while let Some(message) = state.outbox.front() {
+            let qos = match message.qos {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => QoS::ExactlyOnce,
+            };
+
+            match client.lock().unwrap().enqueue(&message.topic, qos, false, message.payload.as_bytes()) {
+                Ok(_) if qos == QoS::AtMostOnce => {
+                    state.outbox.pop_sent();
+                }
+                Ok(msg_id) => state.outbox.set_in_flight(msg_id),
+                Err(e) => {
+                    error!("Failed to publish {} to {}: {}, kept in the outbox", message.id, message.topic, e);
+                    break;
+                }
+            }
+        }
+
+        if state.persisted {
+            persist(state);
+        }
+    }
+}
+
+fn restore(nvs: &EspNvs<NvsDefault>) -> Result<Vec<QueuedMessage>, String> {
+    match read_blob(nvs, NVS_MESSAGES).map_err(|e| e.to_string())? {
+        Some(blob) => serde_json::from_slice(&blob).map_err(|e| e.to_string()),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn persist(state: &mut PublisherState) {
+    let Some(nvs) = &mut state.nvs else {
+        return;
+    };
+    if state.outbox.is_empty() && !state.persisted {
+        return;
+    }
+
+    match try_persist(nvs, &state.outbox) {
+        Ok(()) => state.persisted = !state.outbox.is_empty(),
+        Err(e) => error!("Failed to persist the outbox: {}", e),
+    }
+}
+
+fn try_persist(nvs: &mut EspNvs<NvsDefault>, outbox: &Outbox) -> Result<(), EspError> {
+    if outbox.is_empty() {
+        nvs.remove(NVS_MESSAGES)?;
+        return Ok(());
+    }
+
+    let messages: Vec<&QueuedMessage> = outbox.messages().collect();
+    match serde_json::to_vec(&messages) {
+        Ok(blob) => nvs.set_blob(NVS_MESSAGES, &blob),
+        Err(e) => {
+            error!("Failed to serialize the outbox: {}", e);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use esp_idf_svc::mqtt::client::QoS;
+
+use software_defined_hive::provisioning::form::{validate_topic_level, BROADCAST_LEVEL};
+use software_defined_hive::utils::outbox::MessagePriority;
+
+/// Root of every topic of the fleet
+pub const TOPIC_ROOT: &str = "smart-hive";
+
+/// Takes the place of the apiary and/or the hive id in fleet-wide and apiary-wide topics
+pub const BROADCAST: &str = BROADCAST_LEVEL;
+
+/// Payloads of the availability topic, `offline` is the last will the broker publishes when the hive drops off
+pub const AVAILABILITY_ONLINE: &str = "online";
+pub const AVAILABILITY_OFFLINE: &str = "offline";
+
+/// Topics the hive subscribes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InboundTopic {
+    Commands,
+    WeightReadings,
+    TemperatureSamples,
+    HumiditySamples,
+}
+
+impl InboundTopic {
+    pub const ALL: [InboundTopic; 4] = [
+        InboundTopic::Commands,
+        InboundTopic::WeightReadings,
+        InboundTopic::TemperatureSamples,
+        InboundTopic::HumiditySamples,
+    ];
+
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            InboundTopic::Commands => "commands",
+            InboundTopic::WeightReadings => "sensors/weight",
+            InboundTopic::TemperatureSamples => "sensors/temperature",
+            InboundTopic::HumiditySamples => "sensors/humidity",
+        }
+    }
+
+    pub fn qos(&self) -> QoS {
+        match self {
+            // This has to be precise
+            InboundTopic::Commands => QoS::AtMostOnce,
+            InboundTopic::WeightReadings => QoS::ExactlyOnce,
+            // Sensor nodes publish periodically, so no harm if we lose a packet or two
+            InboundTopic::TemperatureSamples | InboundTopic::HumiditySamples => QoS::AtMostOnce,
+        }
+    }
+
+    /// Whether the topic is also subscribed to on the apiary-wide and fleet-wide broadcast prefixes
+    pub fn broadcast(&self) -> bool {
+        matches!(self, InboundTopic::Commands)
+    }
+}
+
+/// Topics the hive publishes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboundTopic {
+    StateChange,
+    HarvestReady,
+    HarvestReport,
+    AnomalyAlert,
+    ColonyHealthAlert,
+    Telemetry,
+    Responses,
+    /// `online` or `offline` (the last will), retained
+    Availability,
+    /// The latest `HiveStatus`, retained
+    Status,
+}
+
+impl OutboundTopic {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            OutboundTopic::StateChange => "notifications/state-change",
+            OutboundTopic::HarvestReady => "notifications/harvest-ready",
+            OutboundTopic::HarvestReport => "notifications/harvest-report",
+            OutboundTopic::AnomalyAlert => "alerts/anomaly",
+            OutboundTopic::ColonyHealthAlert => "alerts/colony-health",
+            OutboundTopic::Telemetry => "telemetry",
+            OutboundTopic::Responses => "responses",
+            OutboundTopic::Availability => "availability",
+            OutboundTopic::Status => "status",
+        }
+    }
+
+    /// What is kept when the offline outbox is full
+    pub fn priority(&self) -> MessagePriority {
+        match self {
+            OutboundTopic::HarvestReady | OutboundTopic::HarvestReport | OutboundTopic::AnomalyAlert => MessagePriority::High,
+            OutboundTopic::StateChange | OutboundTopic::ColonyHealthAlert | OutboundTopic::Responses => MessagePriority::Normal,
+            // Only the latest matters, the retained messages are not kept in the outbox
+            OutboundTopic::Telemetry | OutboundTopic::Availability | OutboundTopic::Status => MessagePriority::Low,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttTopic {
+    pub topic: String,
+    pub qos: QoS,
+}
+
+/// Builds every topic of a hive from its device ID: `smart-hive/{apiary}/{hive_id}/...`.
+///
+/// Fleet-wide commands are received on `smart-hive/broadcast/commands` and apiary-wide ones on `smart-hive/{apiary}/broadcast/commands`
+#[derive(Debug, Clone)]
+pub struct TopicSchema {
+    device_prefix: String,
+    apiary_prefix: String,
+    fleet_prefix: String,
+}
+
+impl TopicSchema {
+    pub fn new(apiary: &str, hive_id: &str) -> Result<Self, String> {
+        // The same rules the provisioning page enforces
+        validate_topic_level("apiary", apiary)?;
+        validate_topic_level("hive_id", hive_id)?;
+
+        Ok(Self {
+            device_prefix: format!("{}/{}/{}", TOPIC_ROOT, apiary, hive_id),
+            apiary_prefix: format!("{}/{}/{}", TOPIC_ROOT, apiary, BROADCAST),
+            fleet_prefix: format!("{}/{}", TOPIC_ROOT, BROADCAST),
+        })
+    }
+
+    pub fn inbound(&self, topic: InboundTopic) -> String {
+        format!("{}/{}", self.device_prefix, topic.suffix())
+    }
+
+    pub fn outbound(&self, topic: OutboundTopic) -> String {
+        format!("{}/{}", self.device_prefix, topic.suffix())
+    }
+
+    /// Every topic the hive has to subscribe to, broadcast ones included
+    pub fn subscriptions(&self) -> Vec<MqttTopic> {
+        InboundTopic::ALL
+            .iter()
+            .flat_map(|topic| {
+                let prefixes: &[&str] = if topic.broadcast() {
+                    &[&self.device_prefix, &self.apiary_prefix, &self.fleet_prefix]
+                } else {
+                    &[&self.device_prefix]
+                };
+
+                prefixes
+                    .iter()
+                    .map(|prefix| MqttTopic {
+                        topic: format!("{}/{}", prefix, topic.suffix()),
+                        qos: topic.qos(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Maps a received topic back to what it carries, `None` for topics that are not the hive's
+    pub fn route(&self, topic: &str) -> Option<InboundTopic> {
+        InboundTopic::ALL.into_iter().find(|inbound| {
+            let Some(prefix) = topic.strip_suffix(inbound.suffix()).and_then(|rest| rest.strip_suffix('/')) else {
+                return false;
+            };
+
+            prefix == self.device_prefix || (inbound.broadcast() && (prefix == self.apiary_prefix || prefix == self.fleet_prefix))
+        })
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::gpio::{Input, Pin, PinDriver};
+use esp_idf_hal::reset::restart;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+
+use log::*;
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::state::actuators::HoneyCellDisplacer;
+use crate::provisioning::settings::request_provisioning;
+
+/// Holding the button this long restarts the hive into provisioning
+const LONG_PRESS: Duration = Duration::from_secs(5);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Watches the (active low) provisioning button on its own thread. A long press is refused while a harvest is in progress,
+/// a restart would leave the honey cells displaced
+pub fn spawn_button_watcher<P: Pin, H: HoneyCellDisplacer + Send + 'static>(
+    button: PinDriver<'static, P, Input>,
+    nvs: EspDefaultNvsPartition,
+    controller: Arc<Mutex<HiveController<H>>>,
+) -> std::io::Result<JoinHandle<()>> {
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || watch(button, nvs, controller))
+}
+
+fn watch<P: Pin, H: HoneyCellDisplacer>(
+    button: PinDriver<'static, P, Input>,
+    nvs: EspDefaultNvsPartition,
+    controller: Arc<Mutex<HiveController<H>>>,
+) {
+    let mut pressed_since: Option<Instant> = None;
+
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+
+        if button.is_high() {
+            pressed_since = None;
+            continue;
+        }
+
+        let since = *pressed_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= LONG_PRESS {
+            // Locked until the restart, so that no command starts a harvest in between
+            let ctrl = controller.lock().unwrap();
+            if ctrl.state().harvesting() {
+                warn!("Provisioning refused, a harvest is in progress ({:?}). Cancel it or wait until it is verified", ctrl.state());
+                drop(ctrl);
+
+                // Wait for the button to be released before trying again
+                while button.is_low() {
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                pressed_since = None;
+                continue;
+            }
+
+            info!("Provisioning button held for {:?}, restarting into provisioning", LONG_PRESS);
+            match request_provisioning(&nvs) {
+                Ok(()) => restart(),
+                Err(e) => {
+                    error!("Failed to request provisioning: {}", e);
+                    pressed_since = None;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod settings;
+pub mod portal;
+pub mod button;
\ No newline at end of file
@@ -0,0 +1,163 @@
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::Duration;
+
+use esp_idf_hal::modem::WifiModemPeripheral;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::reset::restart;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_ARG};
+use esp_idf_svc::wifi::{AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi};
+
+use log::*;
+use software_defined_hive::provisioning::captive_dns::captive_dns_response;
+use software_defined_hive::provisioning::form::{render_page, render_saved_page, ProvisioningForm};
+use crate::provisioning::settings::store;
+
+/// The access point is named `SmartHive-XXXX` after the end of the MAC address
+const AP_SSID_PREFIX: &str = "SmartHive-";
+const AP_CHANNEL: u8 = 1;
+
+/// Optional WPA2 password of the access point given at build time, the access point is open without it
+const AP_PASSWORD: Option<&str> = option_env!("PROVISIONING_AP_PASSWORD");
+
+/// Larger bodies are not a submission of the configuration page
+const MAX_FORM_LEN: usize = 2048;
+
+/// Time for the confirmation page to reach the browser before the hive restarts
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+const DNS_PORT: u16 = 53;
+
+const HTML: (&str, &str) = ("Content-Type", "text/html; charset=utf-8");
+
+/// Starts an access point with a captive configuration page and serves it until the settings are
+/// saved, then restarts the hive with them. Only returns when the portal cannot be started
+pub fn run_portal(
+    sys_loop: &EspSystemEventLoop,
+    nvs: &EspDefaultNvsPartition,
+    modem: impl Peripheral<P = impl WifiModemPeripheral> + 'static,
+) -> Result<Infallible, EspError> {
+    let esp_wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))?;
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sys_loop.clone())?;
+
+    let mac = wifi.wifi().ap_netif().get_mac()?;
+    let ssid = format!("{}{:02X}{:02X}", AP_SSID_PREFIX, mac[4], mac[5]);
+    let password = AP_PASSWORD.unwrap_or_default();
+    let invalid = |_| EspError::from_infallible::<{ ESP_ERR_INVALID_ARG as i32 }>();
+
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: ssid.as_str().try_into().map_err(invalid)?,
+        password: password.try_into().map_err(invalid)?,
+        auth_method: if password.is_empty() { AuthMethod::None } else { AuthMethod::WPA2Personal },
+        channel: AP_CHANNEL,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+
+    let address = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    info!("Provisioning: join \"{}\" and open http://{}/", ssid, address);
+
+    spawn_dns_responder(address).map_err(|e| {
+        error!("Failed to start the captive DNS responder: {}", e);
+        EspError::from_infallible::<{ esp_idf_svc::sys::ESP_FAIL }>()
+    })?;
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |request| {
+        request
+            .into_response(200, None, &[HTML])?
+            .write_all(render_page(&ProvisioningForm::default(), &[]).as_bytes())
+    })?;
+
+    let nvs = nvs.clone();
+    server.fn_handler("/", Method::Post, move |request| submit(request, &nvs))?;
+
+    // Phones and laptops probe a well known url when they join, redirecting it opens the page
+    let location = format!("http://{}/", address);
+    server.fn_handler("/*", Method::Get, move |request| {
+        request.into_response(302, None, &[("Location", location.as_str())]).map(|_| ())
+    })?;
+
+    // The access point and the server stop when dropped
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+fn submit(mut request: Request<&mut EspHttpConnection>, nvs: &EspDefaultNvsPartition) -> Result<(), EspIOError> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let len = request.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+
+        body.extend_from_slice(&buf[..len]);
+        if body.len() > MAX_FORM_LEN {
+            return request.into_response(413, None, &[]).map(|_| ());
+        }
+    }
+
+    let form = ProvisioningForm::from_urlencoded(&String::from_utf8_lossy(&body));
+    let settings = match form.validate() {
+        Ok(settings) => settings,
+        Err(errors) => {
+            warn!("Invalid provisioning form: {:?}", errors);
+            return request.into_response(400, None, &[HTML])?.write_all(render_page(&form, &errors).as_bytes());
+        }
+    };
+
+    if let Err(e) = store(nvs, &settings) {
+        error!("Failed to store the provisioning settings: {}", e);
+        return request.into_response(500, None, &[])?.write_all(b"Failed to store the settings, please retry");
+    }
+
+    request.into_response(200, None, &[HTML])?.write_all(render_saved_page(&settings).as_bytes())?;
+
+    // Restart from another thread so the response is sent
+    std::thread::spawn(|| {
+        std::thread::sleep(RESTART_DELAY);
+        restart();
+    });
+
+    Ok(())
+}
+
+/// Resolves every name to the access point so that the clients find the configuration page
+fn spawn_dns_responder(address: Ipv4Addr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, client) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Captive DNS receive failed: {}", e);
+                        continue;
+                    }
+                };
+
+                if let Some(response) = captive_dns_response(&buf[..len], address.octets())
+                    && let Err(e) = socket.send_to(&response, client)
+                {
+                    warn!("Captive DNS reply to {} failed: {}", client, e);
+                }
+            }
+        })?;
+
+    Ok(())
+}
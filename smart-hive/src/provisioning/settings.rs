@@ -0,0 +1,101 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::EspError;
+
+use log::*;
+use software_defined_hive::provisioning::form::ProvisioningSettings;
+use crate::mqtt::credentials::store_credentials;
+use crate::storage::nvs::read_str;
+use crate::wi_fi::wi_fi::{store_network, WifiNetwork};
+
+/// NVS namespace of the broker url and the device ID written by the provisioning portal
+pub const DEVICE_NVS_NAMESPACE: &str = "device";
+
+const NVS_BROKER_URL: &str = "broker_url";
+const NVS_APIARY: &str = "apiary";
+const NVS_HIVE_ID: &str = "hive_id";
+/// Set by a long press of the button, the next boot enters provisioning
+const NVS_PROVISIONING_REQUESTED: &str = "provision";
+
+// Optional build-time defaults, so development builds can skip provisioning
+const BUILD_BROKER_URL: Option<&str> = option_env!("MQTT_BROKER_URL");
+const BUILD_CLIENT_ID: Option<&str> = option_env!("MQTT_CLIENT_ID");
+const BUILD_APIARY: Option<&str> = option_env!("HIVE_APIARY");
+const BUILD_HIVE_ID: Option<&str> = option_env!("HIVE_ID");
+
+const DEFAULT_APIARY: &str = "default";
+
+/// Where the hive connects to and who it is. The topics are derived from the device ID: smart-hive/{apiary}/{hive_id}/...
+#[derive(Debug, Clone)]
+pub struct DeviceSettings {
+    pub broker_url: String,
+    pub client_id: String,
+    pub apiary: String,
+    pub hive_id: String,
+}
+
+impl DeviceSettings {
+    /// Entries stored by the provisioning portal take precedence over the build-time ones.
+    /// `None` when the broker url or the hive id is unknown - the hive has to be provisioned
+    pub fn load(nvs: &EspDefaultNvsPartition) -> Result<Option<Self>, EspError> {
+        let stored = EspNvs::new(nvs.clone(), DEVICE_NVS_NAMESPACE, true)?;
+        let build = |value: Option<&str>| value.filter(|value| !value.is_empty()).map(str::to_string);
+
+        let stored_hive_id = read_str(&stored, NVS_HIVE_ID)?;
+        let provisioned = stored_hive_id.is_some();
+
+        let Some(broker_url) = read_str(&stored, NVS_BROKER_URL)?.or_else(|| build(BUILD_BROKER_URL)) else {
+            return Ok(None);
+        };
+        let Some(hive_id) = stored_hive_id.or_else(|| build(BUILD_HIVE_ID)).or_else(|| build(BUILD_CLIENT_ID)) else {
+            return Ok(None);
+        };
+        let apiary = read_str(&stored, NVS_APIARY)?.or_else(|| build(BUILD_APIARY)).unwrap_or_else(|| DEFAULT_APIARY.to_string());
+
+        // Every provisioned hive runs the same firmware, so its client id comes from its device ID
+        let client_id = match build(BUILD_CLIENT_ID) {
+            Some(client_id) if !provisioned => client_id,
+            _ => format!("smart-hive-{}-{}", apiary, hive_id),
+        };
+
+        Ok(Some(Self {
+            broker_url,
+            client_id,
+            apiary,
+            hive_id,
+        }))
+    }
+}
+
+/// Stores what was entered on the provisioning page: the network in the highest priority Wi-Fi slot,
+/// the credentials with the MQTT ones and the broker url and device ID in the device namespace
+pub fn store(nvs: &EspDefaultNvsPartition, settings: &ProvisioningSettings) -> Result<(), EspError> {
+    store_network(nvs, 0, &WifiNetwork::new(&settings.wifi_ssid, &settings.wifi_password))?;
+    store_credentials(nvs, settings.mqtt_username.as_deref(), settings.mqtt_password.as_deref())?;
+
+    let mut device = EspNvs::new(nvs.clone(), DEVICE_NVS_NAMESPACE, true)?;
+    device.set_str(NVS_BROKER_URL, &settings.broker_url)?;
+    device.set_str(NVS_APIARY, &settings.apiary)?;
+    device.set_str(NVS_HIVE_ID, &settings.hive_id)?;
+
+    info!("Provisioned as {}/{}, broker {}", settings.apiary, settings.hive_id, settings.broker_url);
+    Ok(())
+}
+
+/// Makes the next boot enter provisioning
+pub fn request_provisioning(nvs: &EspDefaultNvsPartition) -> Result<(), EspError> {
+    EspNvs::new(nvs.clone(), DEVICE_NVS_NAMESPACE, true)?.set_u8(NVS_PROVISIONING_REQUESTED, 1)
+}
+
+/// Whether provisioning was requested, the request is cleared so that a power cycle leaves an unfinished provisioning
+pub fn take_provisioning_request(nvs: &EspDefaultNvsPartition) -> bool {
+    let Ok(mut device) = EspNvs::new(nvs.clone(), DEVICE_NVS_NAMESPACE, true) else {
+        return false;
+    };
+
+    let requested = device.get_u8(NVS_PROVISIONING_REQUESTED).ok().flatten() == Some(1);
+    if requested && let Err(e) = device.remove(NVS_PROVISIONING_REQUESTED) {
+        warn!("Failed to clear the provisioning request: {}", e);
+    }
+
+    requested
+}
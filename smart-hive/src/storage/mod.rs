@@ -0,0 +1 @@
+pub mod nvs;
\ No newline at end of file
@@ -0,0 +1,22 @@
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+
+/// Reads a string entry, `None` when it is missing or empty
+pub fn read_str(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<String>, EspError> {
+    let Some(len) = nvs.str_len(key)? else {
+        return Ok(None);
+    };
+
+    let mut buf = vec![0u8; len];
+    Ok(nvs.get_str(key, &mut buf)?.filter(|value| !value.is_empty()).map(str::to_string))
+}
+
+/// Reads a blob entry, `None` when it is missing
+pub fn read_blob(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<Vec<u8>>, EspError> {
+    let Some(len) = nvs.blob_len(key)? else {
+        return Ok(None);
+    };
+
+    let mut buf = vec![0u8; len];
+    Ok(nvs.get_blob(key, &mut buf)?.map(<[u8]>::to_vec))
+}
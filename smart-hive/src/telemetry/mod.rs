@@ -0,0 +1 @@
+pub mod system;
\ No newline at end of file
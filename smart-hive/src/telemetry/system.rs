@@ -0,0 +1,18 @@
+use esp_idf_svc::sys::{esp_err_t, esp_get_free_heap_size, esp_get_minimum_free_heap_size, esp_wifi_sta_get_ap_info, wifi_ap_record_t, ESP_OK};
+use software_defined_hive::utils::telemetry::SystemStats;
+use crate::connectivity::supervisor::connectivity_status;
+
+/// RSSI of the access point the station is connected to, the heap usage and the connectivity state
+pub fn system_stats() -> SystemStats {
+    let mut ap_info = wifi_ap_record_t::default();
+
+    // Fails (and leaves ap_info untouched) while the station is not connected
+    let rssi_dbm = (unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) } == ESP_OK as esp_err_t).then_some(ap_info.rssi);
+
+    SystemStats {
+        rssi_dbm,
+        free_heap_bytes: Some(unsafe { esp_get_free_heap_size() }),
+        min_free_heap_bytes: Some(unsafe { esp_get_minimum_free_heap_size() }),
+        connectivity: Some(connectivity_status()),
+    }
+}
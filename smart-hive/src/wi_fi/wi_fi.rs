@@ -1,40 +1,243 @@
-use esp_idf_hal::modem::Modem;
+use esp_idf_hal::modem::WifiModemPeripheral;
+use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::sys::EspError;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{EspError, ESP_ERR_INVALID_ARG, ESP_ERR_WIFI_NOT_CONNECT};
 use esp_idf_svc::wifi::*;
 
 use log::*;
+use crate::storage::nvs::read_str;
 
-const WIFI_SSID: &str = env!("WIFI_SSID");
-const WIFI_PASS: &str = env!("WIFI_PASS");
+/// NVS namespace of the Wi-Fi networks (`ssid_0`/`pass_0` ... highest priority first) and of the last network that worked
+pub const WIFI_NVS_NAMESPACE: &str = "wifi";
 
+/// Number of networks that can be stored in NVS
+pub const MAX_NVS_NETWORKS: usize = 4;
 
-pub fn wifi_create(
-    sys_loop: &EspSystemEventLoop,
-    nvs: &EspDefaultNvsPartition,
-    modem: Modem
-) -> Result<EspWifi<'static>, EspError> {
-    // let peripherals = Peripherals::take()?;
+const NVS_LAST_GOOD_SSID: &str = "last_good";
 
-    let mut esp_wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))?;
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sys_loop.clone())?;
+/// A network the hive may join. An empty password is an open network, otherwise WPA2 or better is required
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+}
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: "Wokwi-GUEST".try_into().unwrap(),
-        password: "".try_into().unwrap(),
-        auth_method: AuthMethod::None,
-        ..Default::default()
-    }))?;
+impl WifiNetwork {
+    pub fn new(ssid: &str, password: &str) -> Self {
+        Self {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+        }
+    }
 
-    wifi.start()?;
-    info!("Wifi started");
+    fn client_configuration(&self) -> Result<ClientConfiguration, EspError> {
+        // The SSID is at most 32 bytes and the password 64
+        let invalid = |_| EspError::from_infallible::<{ ESP_ERR_INVALID_ARG as i32 }>();
 
-    wifi.connect()?;
-    info!("Wifi connected");
+        Ok(ClientConfiguration {
+            ssid: self.ssid.as_str().try_into().map_err(invalid)?,
+            password: self.password.as_str().try_into().map_err(invalid)?,
+            // The weakest security accepted, WPA3 access points are joined as well
+            auth_method: if self.password.is_empty() { AuthMethod::None } else { AuthMethod::WPA2Personal },
+            ..Default::default()
+        })
+    }
+}
 
-    wifi.wait_netif_up()?;
-    info!("Wifi netif up");
+/// Networks given at build time, highest priority first
+#[cfg(not(feature = "wokwi"))]
+fn build_networks() -> Vec<WifiNetwork> {
+    [
+        (option_env!("WIFI_SSID"), option_env!("WIFI_PASS")),
+        (option_env!("WIFI_SSID_2"), option_env!("WIFI_PASS_2")),
+        (option_env!("WIFI_SSID_3"), option_env!("WIFI_PASS_3")),
+    ]
+    .into_iter()
+    .filter_map(|(ssid, password)| ssid.filter(|ssid| !ssid.is_empty()).map(|ssid| WifiNetwork::new(ssid, password.unwrap_or_default())))
+    .collect()
+}
 
-    Ok(esp_wifi)
-}
\ No newline at end of file
+/// The Wokwi simulator only provides its open guest network
+#[cfg(feature = "wokwi")]
+fn build_networks() -> Vec<WifiNetwork> {
+    vec![WifiNetwork::new("Wokwi-GUEST", "")]
+}
+
+/// Joins the first reachable network of a prioritised list and remembers it, so the next boot tries it first
+pub struct WifiManager {
+    wifi: BlockingWifi<EspWifi<'static>>,
+    nvs: Option<EspNvs<NvsDefault>>,
+    networks: Vec<WifiNetwork>,
+}
+
+impl WifiManager {
+    /// Networks stored in NVS come first, then the ones the firmware was built with
+    pub fn new(
+        sys_loop: &EspSystemEventLoop,
+        nvs: &EspDefaultNvsPartition,
+        modem: impl Peripheral<P = impl WifiModemPeripheral> + 'static,
+    ) -> Result<Self, EspError> {
+        let esp_wifi = EspWifi::new(modem, sys_loop.clone(), Some(nvs.clone()))?;
+        let wifi = BlockingWifi::wrap(esp_wifi, sys_loop.clone())?;
+
+        let nvs = match EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true) {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                warn!("Wi-Fi NVS namespace unavailable ({}), the last good network will not be remembered", e);
+                None
+            }
+        };
+
+        let mut networks = match &nvs {
+            Some(nvs) => nvs_networks(nvs)?,
+            None => Vec::new(),
+        };
+        for network in build_networks() {
+            if !networks.iter().any(|known| known.ssid == network.ssid) {
+                networks.push(network);
+            }
+        }
+
+        Ok(Self {
+            wifi,
+            nvs,
+            networks,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.wifi.is_connected().unwrap_or(false)
+    }
+
+    /// Tries the networks in order of priority (the last good one first) until one connects
+    pub fn connect(&mut self) -> Result<(), EspError> {
+        if self.networks.is_empty() {
+            error!("No Wi-Fi networks configured");
+            return Err(not_connected());
+        }
+
+        if !self.wifi.is_started()? {
+            // Start with any configuration so that the access points can be scanned
+            self.wifi.set_configuration(&Configuration::Client(self.networks[0].client_configuration()?))?;
+            self.wifi.start()?;
+            info!("Wifi started");
+        }
+
+        let mut last_error = not_connected();
+        for network in self.candidates() {
+            info!("Connecting to Wi-Fi \"{}\"", network.ssid);
+
+            match self.try_connect(&network) {
+                Ok(()) => {
+                    info!("Wifi connected to \"{}\"", network.ssid);
+                    self.remember(&network.ssid);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Failed to connect to Wi-Fi \"{}\": {}", network.ssid, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn try_connect(&mut self, network: &WifiNetwork) -> Result<(), EspError> {
+        if self.wifi.is_connected()? {
+            self.wifi.disconnect()?;
+        }
+
+        self.wifi.set_configuration(&Configuration::Client(network.client_configuration()?))?;
+        self.wifi.connect()?;
+        self.wifi.wait_netif_up()?;
+        info!("Wifi netif up");
+
+        Ok(())
+    }
+
+    /// The networks to try in order: the last good one first, then by priority.
+    /// When the scan works, networks that are not in range are skipped to save their connect timeouts
+    fn candidates(&mut self) -> Vec<WifiNetwork> {
+        let last_good = self.nvs.as_ref().and_then(|nvs| read_str(nvs, NVS_LAST_GOOD_SSID).ok().flatten());
+
+        let mut candidates = self.networks.clone();
+        if let Some(index) = last_good.and_then(|ssid| candidates.iter().position(|network| network.ssid == ssid)) {
+            let network = candidates.remove(index);
+            candidates.insert(0, network);
+        }
+
+        match self.wifi.scan() {
+            Ok(access_points) => {
+                let in_range: Vec<WifiNetwork> = candidates
+                    .iter()
+                    .filter(|network| access_points.iter().any(|ap| ap.ssid.as_str() == network.ssid))
+                    .cloned()
+                    .collect();
+
+                // Hidden networks do not show up in a scan, so fall back to trying all of them
+                if in_range.is_empty() { candidates } else { in_range }
+            }
+            Err(e) => {
+                warn!("Wi-Fi scan failed ({}), trying all the networks", e);
+                candidates
+            }
+        }
+    }
+
+    fn remember(&mut self, ssid: &str) {
+        if let Some(nvs) = self.nvs.as_mut()
+            && let Err(e) = nvs.set_str(NVS_LAST_GOOD_SSID, ssid)
+        {
+            warn!("Failed to remember the Wi-Fi network: {}", e);
+        }
+    }
+}
+
+/// Whether any network is configured, the hive cannot get online without one
+pub fn has_networks(nvs: &EspDefaultNvsPartition) -> bool {
+    !build_networks().is_empty()
+        || EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true)
+            .and_then(|nvs| nvs_networks(&nvs))
+            .is_ok_and(|networks| !networks.is_empty())
+}
+
+/// Stores a network in the NVS slot `index` (0 has the highest priority). The last good network is
+/// forgotten so that the new one is tried first
+pub fn store_network(nvs: &EspDefaultNvsPartition, index: usize, network: &WifiNetwork) -> Result<(), EspError> {
+    if index >= MAX_NVS_NETWORKS {
+        return Err(EspError::from_infallible::<{ ESP_ERR_INVALID_ARG as i32 }>());
+    }
+
+    let mut nvs = EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true)?;
+    nvs.set_str(&format!("ssid_{}", index), &network.ssid)?;
+    nvs.set_str(&format!("pass_{}", index), &network.password)?;
+    nvs.remove(NVS_LAST_GOOD_SSID)?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "wokwi"))]
+fn nvs_networks(nvs: &EspNvs<NvsDefault>) -> Result<Vec<WifiNetwork>, EspError> {
+    let mut networks = Vec::new();
+
+    for index in 0..MAX_NVS_NETWORKS {
+        if let Some(ssid) = read_str(nvs, &format!("ssid_{}", index))? {
+            let password = read_str(nvs, &format!("pass_{}", index))?.unwrap_or_default();
+            networks.push(WifiNetwork::new(&ssid, &password));
+        }
+    }
+
+    Ok(networks)
+}
+
+/// The simulation only joins its guest network
+#[cfg(feature = "wokwi")]
+fn nvs_networks(_nvs: &EspNvs<NvsDefault>) -> Result<Vec<WifiNetwork>, EspError> {
+    Ok(Vec::new())
+}
+
+fn not_connected() -> EspError {
+    EspError::from_infallible::<{ ESP_ERR_WIFI_NOT_CONNECT as i32 }>()
+}
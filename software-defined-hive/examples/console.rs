@@ -0,0 +1,31 @@
+//! The bench console on a host, without a hive: `cargo run -p software-defined-hive --example console`
+use std::io;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use software_defined_hive::controller::console::{run_console, HELP};
+use software_defined_hive::controller::controller::HiveController;
+use software_defined_hive::state::actuators::{HoneyCellDisplacer, HoneyCellDisplacerCommand, HoneyCellDisplacerFault};
+use software_defined_hive::state::policy::harvest::HarvestPolicyConfigs;
+
+/// Prints what the actuator would do, the cells always close
+struct SimulatedDisplacer;
+
+impl HoneyCellDisplacer for SimulatedDisplacer {
+    fn execute(&mut self, command: HoneyCellDisplacerCommand) -> Result<(), HoneyCellDisplacerFault> {
+        println!("actuator: {:?}", command);
+        Ok(())
+    }
+
+    fn cells_closed(&mut self) -> Result<bool, HoneyCellDisplacerFault> {
+        Ok(true)
+    }
+}
+
+fn main() -> io::Result<()> {
+    let controller = Mutex::new(HiveController::new(HarvestPolicyConfigs::default(), SimulatedDisplacer));
+    let started = Instant::now();
+
+    println!("{}", HELP);
+    run_console(&controller, io::stdin().lock(), io::stdout(), |_| started.elapsed().as_secs())
+}
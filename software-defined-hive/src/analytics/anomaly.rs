@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::hive::HiveState;
+use crate::state::policy::anomaly::AnomalyDetectionConfigs;
+use crate::state::sensors::SensorReadings;
+
+/// Upper bound of the samples kept in memory, regardless of the configured windows
+const MAX_SAMPLES: usize = 1024;
+
+/// Runs on every sensor reading and raises alerts on unexpected changes. Implement this to plug in other detectors
+pub trait AnomalyDetector {
+    /// Feeds a reading (and the state of the hive when it was taken) to the detector, returns the alerts raised
+    fn observe(&mut self, reading: &SensorReadings, state: HiveState) -> Vec<AnomalyAlert>;
+
+    /// Called with the thresholds of the current policy before every reading, detectors without thresholds ignore them
+    fn configure(&mut self, _configs: &AnomalyDetectionConfigs) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// A sudden drop of a few kilograms - a swarm leaving the hive
+    Swarming,
+    /// A sudden drop bigger than any swarm - the hive is being stolen or was knocked over
+    Theft,
+    /// A steady loss of weight outside a harvest - other bees are robbing the honey
+    Robbing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyAlert {
+    pub kind: AnomalyKind,
+    pub severity: AlertSeverity,
+    /// Weight lost over `window_s` (grams)
+    pub weight_loss_g: u32,
+    pub window_s: u64,
+    /// Net hive weight when the alert was raised (grams)
+    pub weight_g: u32,
+    pub timestamp_s: u64,
+}
+
+/// Detects swarming, theft and robbing from the weight trend. Harvests are expected weight losses so they are ignored
+pub struct WeightTrendDetector {
+    configs: AnomalyDetectionConfigs,
+    // (timestamp_s, weight_g) oldest first
+    samples: VecDeque<(u64, u32)>,
+    last_alert_at: Vec<(AnomalyKind, u64)>,
+}
+
+impl WeightTrendDetector {
+    pub fn new(configs: AnomalyDetectionConfigs) -> Self {
+        Self {
+            configs,
+            samples: VecDeque::new(),
+            last_alert_at: Vec::new(),
+        }
+    }
+
+    pub fn configs(&self) -> &AnomalyDetectionConfigs {
+        &self.configs
+    }
+
+    /// Highest weight seen within `window_s` of `now` minus the current weight
+    fn loss_within(&self, window_s: u64, now: u64, weight_g: u32) -> u32 {
+        self.samples
+            .iter()
+            .filter(|(timestamp_s, _)| now.saturating_sub(*timestamp_s) <= window_s)
+            .map(|(_, weight)| weight.saturating_sub(weight_g))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn raise(&mut self, kind: AnomalyKind, severity: AlertSeverity, weight_loss_g: u32, window_s: u64, reading: &SensorReadings) -> Option<AnomalyAlert> {
+        let now = reading.timestamp_s;
+
+        match self.last_alert_at.iter_mut().find(|(alert_kind, _)| *alert_kind == kind) {
+            Some((_, last)) if now.saturating_sub(*last) < self.configs.alert_cooldown_s => return None,
+            Some((_, last)) => *last = now,
+            None => self.last_alert_at.push((kind, now)),
+        }
+
+        Some(AnomalyAlert {
+            kind,
+            severity,
+            weight_loss_g,
+            window_s,
+            weight_g: reading.weight_g,
+            timestamp_s: now,
+        })
+    }
+}
+
+impl AnomalyDetector for WeightTrendDetector {
+    fn configure(&mut self, configs: &AnomalyDetectionConfigs) {
+        self.configs = *configs;
+    }
+
+    fn observe(&mut self, reading: &SensorReadings, state: HiveState) -> Vec<AnomalyAlert> {
+        let mut alerts = Vec::new();
+
+        if !matches!(state, HiveState::Monitoring | HiveState::Candidate | HiveState::Ready) {
+            // Harvesting (or faulted) - the weight trend before and after is not comparable
+            self.samples.clear();
+            return alerts;
+        }
+
+        let now = reading.timestamp_s;
+        let sudden_window_s = self.configs.sudden_drop_window_s;
+        let robbing_window_s = self.configs.robbing_window_s;
+        let sudden_loss_g = self.loss_within(sudden_window_s, now, reading.weight_g);
+
+        if sudden_loss_g >= self.configs.swarm_drop_g {
+            if sudden_loss_g >= self.configs.theft_drop_g {
+                alerts.extend(self.raise(AnomalyKind::Theft, AlertSeverity::Critical, sudden_loss_g, sudden_window_s, reading));
+            } else {
+                alerts.extend(self.raise(AnomalyKind::Swarming, AlertSeverity::Warning, sudden_loss_g, sudden_window_s, reading));
+            }
+
+            // The weight after the drop is the new baseline, otherwise the drop would later be mistaken for robbing
+            self.samples.clear();
+        } else {
+            // Only a loss that is not explained by a sudden drop counts as robbing
+            let steady_loss_g = self.loss_within(robbing_window_s, now, reading.weight_g);
+
+            if steady_loss_g >= self.configs.robbing_loss_g {
+                alerts.extend(self.raise(AnomalyKind::Robbing, AlertSeverity::Warning, steady_loss_g, robbing_window_s, reading));
+            }
+        }
+
+        let retention_s = sudden_window_s.max(robbing_window_s);
+        while self.samples
+            .front()
+            .is_some_and(|(timestamp_s, _)| now.saturating_sub(*timestamp_s) > retention_s)
+            || self.samples.len() >= MAX_SAMPLES
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, reading.weight_g));
+
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(weight_g: u32, timestamp_s: u64) -> SensorReadings {
+        SensorReadings {
+            weight_g,
+            timestamp_s,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds the readings while monitoring, returns every alert raised
+    fn observe_all(detector: &mut WeightTrendDetector, readings: &[(u32, u64)]) -> Vec<AnomalyAlert> {
+        readings
+            .iter()
+            .flat_map(|&(weight_g, timestamp_s)| detector.observe(&reading(weight_g, timestamp_s), HiveState::Monitoring))
+            .collect()
+    }
+
+    #[test]
+    fn a_stable_weight_raises_nothing() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+
+        let readings: Vec<(u32, u64)> = (0..200).map(|i| (40_000 + (i % 3) * 50, i as u64 * 60)).collect();
+        assert!(observe_all(&mut detector, &readings).is_empty());
+    }
+
+    #[test]
+    fn a_sudden_drop_of_a_few_kilograms_is_a_swarm() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+
+        let alerts = observe_all(&mut detector, &[(40_000, 0), (40_000, 60), (38_500, 120)]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].kind, alerts[0].severity), (AnomalyKind::Swarming, AlertSeverity::Warning));
+        assert_eq!((alerts[0].weight_loss_g, alerts[0].window_s), (1500, 600));
+        assert_eq!((alerts[0].weight_g, alerts[0].timestamp_s), (38_500, 120));
+
+        // The weight after the swarm is the new baseline
+        assert!(observe_all(&mut detector, &[(38_500, 180), (38_450, 240)]).is_empty());
+    }
+
+    #[test]
+    fn a_drop_bigger_than_any_swarm_is_theft() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+
+        let alerts = observe_all(&mut detector, &[(40_000, 0), (25_000, 60)]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].kind, alerts[0].severity), (AnomalyKind::Theft, AlertSeverity::Critical));
+        assert_eq!(alerts[0].weight_loss_g, 15_000);
+    }
+
+    #[test]
+    fn a_drop_spread_over_more_than_the_window_is_not_sudden() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+
+        // 1.2 kg in 400 g steps, 11 minutes apart
+        let alerts = observe_all(&mut detector, &[(40_000, 0), (39_600, 660), (39_200, 1320), (38_800, 1980)]);
+        assert!(alerts.iter().all(|alert| alert.kind == AnomalyKind::Robbing));
+    }
+
+    #[test]
+    fn a_steady_loss_is_robbing() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+
+        // 100 g every 10 minutes
+        let readings: Vec<(u32, u64)> = (0..9).map(|i| (40_000 - i * 100, i as u64 * 600)).collect();
+        assert!(observe_all(&mut detector, &readings[..8]).is_empty());
+
+        let alerts = observe_all(&mut detector, &readings[8..]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].kind, alerts[0].severity), (AnomalyKind::Robbing, AlertSeverity::Warning));
+        assert_eq!((alerts[0].weight_loss_g, alerts[0].window_s), (800, 3 * 3600));
+    }
+
+    #[test]
+    fn a_loss_older_than_the_robbing_window_is_forgotten() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs {
+            robbing_window_s: 3600,
+            ..Default::default()
+        });
+
+        // 700 g lost in the first hour, then another 700 g in the next
+        let readings: Vec<(u32, u64)> = (0..=14).map(|i| (40_000 - i * 100, i as u64 * 600)).collect();
+        let alerts = observe_all(&mut detector, &readings);
+        assert!(alerts.is_empty(), "{:?}", alerts);
+    }
+
+    #[test]
+    fn alerts_of_a_kind_wait_for_the_cooldown() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+
+        assert_eq!(observe_all(&mut detector, &[(40_000, 0), (38_500, 60)]).len(), 1);
+        // Within the hour
+        assert!(observe_all(&mut detector, &[(38_500, 600), (37_000, 660)]).is_empty());
+        // Another kind is not held back
+        assert_eq!(observe_all(&mut detector, &[(37_000, 700), (20_000, 760)])[0].kind, AnomalyKind::Theft);
+        // Once the hour has passed
+        let alerts = observe_all(&mut detector, &[(20_000, 3600), (18_500, 3660)]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].kind, AnomalyKind::Swarming);
+    }
+
+    #[test]
+    fn harvests_are_not_anomalies() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+        observe_all(&mut detector, &[(40_000, 0), (40_000, 60)]);
+
+        for (state, weight_g, timestamp_s) in [
+            (HiveState::Authorized, 40_000, 120),
+            (HiveState::Draining, 30_000, 180),
+            (HiveState::Verifying, 28_000, 240),
+            (HiveState::Fault, 28_000, 300),
+        ] {
+            assert!(detector.observe(&reading(weight_g, timestamp_s), state).is_empty());
+        }
+
+        // The trend starts over after the harvest
+        assert!(observe_all(&mut detector, &[(28_000, 360), (27_900, 420)]).is_empty());
+    }
+
+    #[test]
+    fn follows_the_thresholds_of_the_policy() {
+        let mut detector = WeightTrendDetector::new(AnomalyDetectionConfigs::default());
+        detector.configure(&AnomalyDetectionConfigs {
+            swarm_drop_g: 2000,
+            robbing_loss_g: 5000,
+            ..Default::default()
+        });
+        assert_eq!(detector.configs().swarm_drop_g, 2000);
+
+        assert!(observe_all(&mut detector, &[(40_000, 0), (38_500, 60)]).is_empty());
+        assert_eq!(observe_all(&mut detector, &[(36_000, 120)])[0].kind, AnomalyKind::Swarming);
+    }
+}
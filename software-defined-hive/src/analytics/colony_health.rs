@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analytics::anomaly::AlertSeverity;
+use crate::state::policy::colony_health::ColonyHealthConfigs;
+use crate::state::sensors::SensorReadings;
+
+/// Upper bound of the samples kept in memory, regardless of the configured window
+const MAX_SAMPLES: usize = 1024;
+
+/// How far outside the healthy brood range (°C * 10) a warning becomes critical
+const CRITICAL_DEVIATION_X10: i16 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColonyHealthWarningKind {
+    /// The nest temperature is unstable or follows the outside temperature - the colony is not regulating its brood
+    PossibleQueenlessness,
+    /// The nest is hotter than a healthy brood temperature
+    Overheating,
+    /// The nest is colder than a healthy brood temperature
+    ChilledBrood,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ColonyHealthWarning {
+    pub kind: ColonyHealthWarningKind,
+    pub severity: AlertSeverity,
+    /// Average internal temperature over the window (°C * 10)
+    pub mean_temperature_x10: i16,
+    /// Max - min internal temperature over the window (°C * 10)
+    pub temperature_range_x10: i16,
+    /// Average of internal - external temperature over the window (°C * 10), `None` without an external sensor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_temperature_gap_x10: Option<i16>,
+    pub window_s: u64,
+    pub timestamp_s: u64,
+}
+
+/// Summary of the internal temperature over the rolling window
+#[derive(Debug, Clone, Copy)]
+struct TemperatureWindow {
+    mean_x10: i16,
+    range_x10: i16,
+    mean_external_x10: Option<i16>,
+    mean_gap_x10: Option<i16>,
+}
+
+/// Tracks the brood nest temperature over a rolling window and raises health warnings
+pub struct ColonyHealthAnalyser {
+    configs: ColonyHealthConfigs,
+    // (timestamp_s, internal temperature, external temperature) oldest first
+    samples: VecDeque<(u64, i16, Option<i16>)>,
+    last_warning_at: Vec<(ColonyHealthWarningKind, u64)>,
+}
+
+impl ColonyHealthAnalyser {
+    pub fn new(configs: ColonyHealthConfigs) -> Self {
+        Self {
+            configs,
+            samples: VecDeque::new(),
+            last_warning_at: Vec::new(),
+        }
+    }
+
+    pub fn configs(&self) -> &ColonyHealthConfigs {
+        &self.configs
+    }
+
+    /// Takes the thresholds of the current policy, the samples already in the window are kept
+    pub fn configure(&mut self, configs: &ColonyHealthConfigs) {
+        self.configs = *configs;
+    }
+
+    /// Feeds a reading to the analyser, returns the warnings raised. Readings without an internal temperature are ignored
+    pub fn observe(&mut self, reading: &SensorReadings) -> Vec<ColonyHealthWarning> {
+        let mut warnings = Vec::new();
+
+        let Some(temperature_x10) = reading.temperature_x10 else {
+            return warnings;
+        };
+
+        let now = reading.timestamp_s;
+        while self.samples
+            .front()
+            .is_some_and(|(timestamp_s, _, _)| now.saturating_sub(*timestamp_s) > self.configs.window_s)
+            || self.samples.len() >= MAX_SAMPLES
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, temperature_x10, reading.external_temperature_x10));
+
+        if self.samples.len() < self.configs.min_samples {
+            return warnings;
+        }
+
+        let window = self.summarise();
+        let min_x10 = self.configs.brood_min_temperature_x10;
+        let max_x10 = self.configs.brood_max_temperature_x10;
+
+        if window.mean_x10 > max_x10 {
+            let severity = if window.mean_x10 >= max_x10 + CRITICAL_DEVIATION_X10 {
+                AlertSeverity::Critical
+            } else {
+                AlertSeverity::Warning
+            };
+            warnings.extend(self.raise(ColonyHealthWarningKind::Overheating, severity, window, now));
+        }
+
+        if window.mean_x10 < min_x10 {
+            let severity = if window.mean_x10 <= min_x10 - CRITICAL_DEVIATION_X10 {
+                AlertSeverity::Critical
+            } else {
+                AlertSeverity::Warning
+            };
+            warnings.extend(self.raise(ColonyHealthWarningKind::ChilledBrood, severity, window, now));
+        }
+
+        let unstable = window.range_x10 > self.configs.max_temperature_range_x10;
+        // When it is cool outside a healthy colony keeps the nest well above the outside temperature
+        let follows_outside = matches!(
+            (window.mean_gap_x10, window.mean_external_x10),
+            (Some(gap_x10), Some(external_x10)) if gap_x10 < self.configs.min_temperature_gap_x10 && external_x10 < min_x10
+        );
+
+        if unstable || follows_outside {
+            warnings.extend(self.raise(ColonyHealthWarningKind::PossibleQueenlessness, AlertSeverity::Warning, window, now));
+        }
+
+        warnings
+    }
+
+    fn summarise(&self) -> TemperatureWindow {
+        let count = self.samples.len() as i64;
+        let sum: i64 = self.samples.iter().map(|(_, internal, _)| *internal as i64).sum();
+        let min = self.samples.iter().map(|(_, internal, _)| *internal).min().unwrap_or_default();
+        let max = self.samples.iter().map(|(_, internal, _)| *internal).max().unwrap_or_default();
+
+        let (external_sum, gap_sum, external_count) = self.samples
+            .iter()
+            .filter_map(|(_, internal, external)| external.map(|external| (*internal as i64, external as i64)))
+            .fold((0i64, 0i64, 0i64), |(external_sum, gap_sum, n), (internal, external)| {
+                (external_sum + external, gap_sum + internal - external, n + 1)
+            });
+
+        TemperatureWindow {
+            mean_x10: (sum / count.max(1)) as i16,
+            range_x10: max - min,
+            mean_external_x10: (external_count > 0).then(|| (external_sum / external_count) as i16),
+            mean_gap_x10: (external_count > 0).then(|| (gap_sum / external_count) as i16),
+        }
+    }
+
+    fn raise(&mut self, kind: ColonyHealthWarningKind, severity: AlertSeverity, window: TemperatureWindow, now: u64) -> Option<ColonyHealthWarning> {
+        match self.last_warning_at.iter_mut().find(|(warning_kind, _)| *warning_kind == kind) {
+            Some((_, last)) if now.saturating_sub(*last) < self.configs.warning_cooldown_s => return None,
+            Some((_, last)) => *last = now,
+            None => self.last_warning_at.push((kind, now)),
+        }
+
+        Some(ColonyHealthWarning {
+            kind,
+            severity,
+            mean_temperature_x10: window.mean_x10,
+            temperature_range_x10: window.range_x10,
+            mean_temperature_gap_x10: window.mean_gap_x10,
+            window_s: self.configs.window_s,
+            timestamp_s: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 minutes apart from `start_s`, returns every warning raised
+    fn observe_all(analyser: &mut ColonyHealthAnalyser, start_s: u64, temperatures_x10: &[(i16, Option<i16>)]) -> Vec<ColonyHealthWarning> {
+        temperatures_x10
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &(temperature_x10, external_temperature_x10))| {
+                analyser.observe(&SensorReadings {
+                    weight_g: 40_000,
+                    temperature_x10: Some(temperature_x10),
+                    external_temperature_x10,
+                    timestamp_s: start_s + i as u64 * 600,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn kinds(warnings: &[ColonyHealthWarning]) -> Vec<(ColonyHealthWarningKind, AlertSeverity)> {
+        warnings.iter().map(|warning| (warning.kind, warning.severity)).collect()
+    }
+
+    #[test]
+    fn a_regulated_brood_nest_is_healthy() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+
+        let temperatures: Vec<(i16, Option<i16>)> = (0..48).map(|i| (340 + (i % 3) as i16 * 5, Some(120))).collect();
+        assert!(observe_all(&mut analyser, 0, &temperatures).is_empty());
+    }
+
+    #[test]
+    fn waits_for_min_samples() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+
+        assert!(observe_all(&mut analyser, 0, &[(380, None); 11]).is_empty());
+        let warnings = observe_all(&mut analyser, 11 * 600, &[(380, None)]);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::Overheating, AlertSeverity::Warning)]);
+        assert_eq!((warnings[0].mean_temperature_x10, warnings[0].temperature_range_x10), (380, 0));
+        assert_eq!((warnings[0].window_s, warnings[0].timestamp_s), (6 * 3600, 11 * 600));
+    }
+
+    #[test]
+    fn overheating() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+        let warnings = observe_all(&mut analyser, 0, &[(375, None); 12]);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::Overheating, AlertSeverity::Warning)]);
+
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+        let warnings = observe_all(&mut analyser, 0, &[(385, None); 12]);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::Overheating, AlertSeverity::Critical)]);
+    }
+
+    #[test]
+    fn chilled_brood() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+        let warnings = observe_all(&mut analyser, 0, &[(320, None); 12]);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::ChilledBrood, AlertSeverity::Warning)]);
+
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+        let warnings = observe_all(&mut analyser, 0, &[(310, None); 12]);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::ChilledBrood, AlertSeverity::Critical)]);
+    }
+
+    #[test]
+    fn an_unstable_nest_may_be_queenless() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+
+        // Within the healthy range on average, but swinging 3.5°C
+        let temperatures: Vec<(i16, Option<i16>)> = (0..12).map(|i| (if i % 2 == 0 { 330 } else { 365 }, None)).collect();
+        let warnings = observe_all(&mut analyser, 0, &temperatures);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::PossibleQueenlessness, AlertSeverity::Warning)]);
+        assert_eq!(warnings[0].temperature_range_x10, 35);
+    }
+
+    #[test]
+    fn a_nest_following_the_cold_outside_may_be_queenless() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+        let warnings = observe_all(&mut analyser, 0, &[(335, Some(300)); 12]);
+        assert_eq!(kinds(&warnings), vec![(ColonyHealthWarningKind::PossibleQueenlessness, AlertSeverity::Warning)]);
+        assert_eq!(warnings[0].mean_temperature_gap_x10, Some(35));
+
+        // On a hot day the nest is close to the outside temperature anyway
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+        assert!(observe_all(&mut analyser, 0, &[(350, Some(340)); 12]).is_empty());
+    }
+
+    #[test]
+    fn warnings_of_a_kind_wait_for_the_cooldown() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs::default());
+
+        assert_eq!(observe_all(&mut analyser, 0, &[(380, None); 12]).len(), 1);
+        // Still overheating for the rest of the cooldown
+        assert!(observe_all(&mut analyser, 12 * 600, &[(380, None); 24]).is_empty());
+        // The first warning came with the 12th reading
+        assert_eq!(observe_all(&mut analyser, 11 * 600 + 6 * 3600, &[(380, None)]).len(), 1);
+    }
+
+    #[test]
+    fn readings_without_a_temperature_are_ignored() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs {
+            min_samples: 1,
+            ..Default::default()
+        });
+
+        let reading = SensorReadings {
+            weight_g: 40_000,
+            timestamp_s: 0,
+            ..Default::default()
+        };
+        assert!(analyser.observe(&reading).is_empty());
+        assert!(analyser.samples.is_empty());
+    }
+
+    #[test]
+    fn old_samples_leave_the_window() {
+        let mut analyser = ColonyHealthAnalyser::new(ColonyHealthConfigs {
+            window_s: 3600,
+            min_samples: 6,
+            ..Default::default()
+        });
+
+        // A cold night followed by a healthy morning
+        assert_eq!(observe_all(&mut analyser, 0, &[(300, None); 6]).len(), 1);
+        assert!(observe_all(&mut analyser, 4 * 3600, &[(345, None); 7]).is_empty());
+        assert_eq!(analyser.samples.len(), 7);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod anomaly;
+pub mod colony_health;
\ No newline at end of file
@@ -0,0 +1,2 @@
+pub mod rest;
+pub mod status_page;
\ No newline at end of file
@@ -0,0 +1,249 @@
+use crate::api::status_page::render_status_page;
+use crate::controller::controller::{HiveCommand, HiveController};
+use crate::controller::dispatcher::{dispatch, dispatch_command, reply_to_json, CommandError, CommandEvent, CommandReply, DispatchOutcome};
+use crate::provisioning::form::parse_urlencoded;
+use crate::state::actuators::HoneyCellDisplacer;
+use crate::state::policy::harvest::HarvestPolicyConfigs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Put,
+    Post,
+}
+
+/// The routes of the local API, each maps to a `HiveCommand`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    /// The HTML status page for a phone browser
+    StatusPage,
+    Status,
+    GetPolicy,
+    /// The body is a `HarvestPolicyConfigs`
+    PutPolicy,
+    /// The body is a `HiveCommand`
+    Commands,
+    Harvests,
+}
+
+impl Endpoint {
+    pub const ALL: [Endpoint; 6] = [
+        Endpoint::StatusPage,
+        Endpoint::Status,
+        Endpoint::GetPolicy,
+        Endpoint::PutPolicy,
+        Endpoint::Commands,
+        Endpoint::Harvests,
+    ];
+
+    pub fn path(&self) -> &'static str {
+        match self {
+            Endpoint::StatusPage => "/",
+            Endpoint::Status => "/status",
+            Endpoint::GetPolicy | Endpoint::PutPolicy => "/policy",
+            Endpoint::Commands => "/commands",
+            Endpoint::Harvests => "/harvests",
+        }
+    }
+
+    pub fn method(&self) -> Method {
+        match self {
+            Endpoint::PutPolicy => Method::Put,
+            Endpoint::Commands => Method::Post,
+            _ => Method::Get,
+        }
+    }
+}
+
+pub const JSON: &str = "application/json";
+pub const HTML: &str = "text/html; charset=utf-8";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl RestResponse {
+    fn json(status: u16, body: String) -> Self {
+        Self {
+            status,
+            content_type: JSON,
+            body,
+        }
+    }
+
+    /// A missing or wrong token
+    pub fn unauthorized() -> Self {
+        Self::json(401, CommandError::Invalid("Missing or invalid token".to_string()).to_json())
+    }
+
+    /// The body is larger than the API accepts
+    pub fn too_large() -> Self {
+        Self::json(413, CommandError::Invalid("The body is too large".to_string()).to_json())
+    }
+}
+
+/// Scripts send the token as `Authorization: Bearer <token>`, a browser opening the status page
+/// as `?token=<token>`. An empty token never authorizes
+pub fn authorize(token: &str, authorization: Option<&str>, query: Option<&str>) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+
+    let bearer = authorization
+        .and_then(|header| header.trim().strip_prefix("Bearer "))
+        .map(|bearer| bearer.trim().to_string());
+    let queried = query.and_then(|query| {
+        parse_urlencoded(query)
+            .into_iter()
+            .find_map(|(name, value)| (name == "token").then_some(value))
+    });
+
+    bearer.into_iter().chain(queried).any(|candidate| constant_time_eq(candidate.as_bytes(), token.as_bytes()))
+}
+
+/// Compares every byte so that the time taken does not tell how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Serves an authorized request through the dispatcher, like a command from any other transport.
+/// Returns the side effects of the command with the response, for the firmware to announce them
+pub fn handle<H: HoneyCellDisplacer>(controller: &mut HiveController<H>, endpoint: Endpoint, body: &str) -> (RestResponse, Vec<CommandEvent>) {
+    // The response is built from the returned reply
+    let mut ignore = |_: &CommandReply| {};
+
+    let outcome = match endpoint {
+        Endpoint::StatusPage => {
+            let status = controller.get_status();
+            let response = RestResponse {
+                status: 200,
+                content_type: HTML,
+                body: render_status_page(&status, controller.last_reading_at(), controller.harvest_history().back()),
+            };
+            return (response, Vec::new());
+        }
+        Endpoint::Status => dispatch_command(controller, HiveCommand::GetStatus, &mut ignore),
+        Endpoint::GetPolicy => dispatch_command(controller, HiveCommand::GetPolicy, &mut ignore),
+        Endpoint::PutPolicy => match serde_json::from_str::<HarvestPolicyConfigs>(body) {
+            Ok(policy) => dispatch_command(controller, HiveCommand::UpdatePolicy { policy: Box::new(policy) }, &mut ignore),
+            Err(e) => DispatchOutcome {
+                reply: Err(CommandError::Invalid(e.to_string())),
+                events: Vec::new(),
+            },
+        },
+        Endpoint::Commands => dispatch(controller, body, &mut ignore),
+        Endpoint::Harvests => dispatch_command(controller, HiveCommand::GetHarvestHistory, &mut ignore),
+    };
+
+    let response = RestResponse::json(status_code(endpoint, &outcome.reply), reply_to_json(&outcome.reply));
+    (response, outcome.events)
+}
+
+/// 400 for a body that is not understood, 422 for a policy that does not validate and 409 for
+/// a command the hive cannot execute in its current state
+fn status_code(endpoint: Endpoint, reply: &CommandReply) -> u16 {
+    match reply {
+        Ok(_) => 200,
+        Err(CommandError::Invalid(_)) => 400,
+        Err(CommandError::Failed(_)) if endpoint == Endpoint::PutPolicy => 422,
+        Err(CommandError::Failed(_)) => 409,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::controller::CommandResponse;
+    use crate::controller::dispatcher::StateTransition;
+    use crate::state::actuators::MockDisplacer;
+    use crate::state::hive::HiveState;
+
+    const TOKEN: &str = "s3cret";
+
+    #[test]
+    fn authorizes_a_bearer_token() {
+        assert!(authorize(TOKEN, Some("Bearer s3cret"), None));
+        assert!(authorize(TOKEN, Some("  Bearer s3cret  "), None));
+
+        assert!(!authorize(TOKEN, Some("Bearer s3cre"), None));
+        assert!(!authorize(TOKEN, Some("Bearer s3cret2"), None));
+        assert!(!authorize(TOKEN, Some("Basic s3cret"), None));
+        assert!(!authorize(TOKEN, Some("s3cret"), None));
+        assert!(!authorize(TOKEN, None, None));
+    }
+
+    #[test]
+    fn authorizes_a_token_in_the_query() {
+        assert!(authorize(TOKEN, None, Some("token=s3cret")));
+        assert!(authorize(TOKEN, None, Some("refresh=30&token=s3cret")));
+        assert!(authorize(TOKEN, None, Some("token=s3%63ret")));
+        // A wrong header does not stop a valid query, nor the other way round
+        assert!(authorize(TOKEN, Some("Bearer wrong"), Some("token=s3cret")));
+        assert!(authorize(TOKEN, Some("Bearer s3cret"), Some("token=wrong")));
+
+        assert!(!authorize(TOKEN, None, Some("token=wrong")));
+        assert!(!authorize(TOKEN, None, Some("key=s3cret")));
+        assert!(!authorize(TOKEN, None, Some("")));
+    }
+
+    #[test]
+    fn an_empty_token_never_authorizes() {
+        assert!(!authorize("", Some("Bearer "), None));
+        assert!(!authorize("", Some("Bearer"), Some("token=")));
+        assert!(!authorize("", None, None));
+    }
+
+    #[test]
+    fn status_codes() {
+        let invalid = Err(CommandError::Invalid("expected value".to_string()));
+        let failed = Err(CommandError::Failed("Invalid policy configuration".to_string()));
+
+        for endpoint in Endpoint::ALL {
+            assert_eq!(status_code(endpoint, &Ok(CommandResponse::ack())), 200);
+            assert_eq!(status_code(endpoint, &invalid), 400);
+        }
+        assert_eq!(status_code(Endpoint::PutPolicy, &failed), 422);
+        assert_eq!(status_code(Endpoint::Commands, &failed), 409);
+    }
+
+    #[test]
+    fn handles_the_endpoints() {
+        let mut controller = HiveController::new(HarvestPolicyConfigs::default(), MockDisplacer::default());
+
+        let (response, events) = handle(&mut controller, Endpoint::Status, "");
+        assert_eq!((response.status, response.content_type), (200, JSON));
+        assert!(events.is_empty());
+
+        let (response, _) = handle(&mut controller, Endpoint::StatusPage, "");
+        assert_eq!((response.status, response.content_type), (200, HTML));
+
+        let (response, _) = handle(&mut controller, Endpoint::PutPolicy, "{");
+        assert_eq!(response.status, 400);
+
+        let (response, _) = handle(&mut controller, Endpoint::Commands, r#"{"command":"authorize_harvest"}"#);
+        assert_eq!(response.status, 409);
+    }
+
+    #[test]
+    fn returns_the_events_of_a_command() {
+        let mut controller = HiveController::new(HarvestPolicyConfigs::default(), MockDisplacer::default());
+
+        let (response, events) = handle(&mut controller, Endpoint::Commands, r#"{"command":"emergency_stop"}"#);
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            events,
+            vec![CommandEvent::StateChanged(StateTransition {
+                previous_state: HiveState::Monitoring,
+                new_state: HiveState::Fault,
+            })]
+        );
+
+        let policy = serde_json::to_string(controller.policy()).unwrap();
+        let (response, events) = handle(&mut controller, Endpoint::PutPolicy, &policy);
+        assert_eq!(response.status, 200);
+        assert_eq!(events, vec![CommandEvent::PolicyChanged]);
+    }
+}
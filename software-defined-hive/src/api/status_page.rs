@@ -0,0 +1,79 @@
+use crate::controller::controller::HiveStatus;
+use crate::controller::events::HarvestReport;
+use crate::provisioning::form::escape_html;
+use crate::state::hive::FaultReason;
+
+/// Seconds between reloads of the page
+const REFRESH_S: u32 = 30;
+
+/// A minimal page for checking the hive from a phone, it reloads itself with the same url (token included).
+/// Timestamps are rendered in the browser's local time
+pub fn render_status_page(status: &HiveStatus, last_reading_at: Option<u64>, last_harvest: Option<&HarvestReport>) -> String {
+    let mut rows = vec![
+        row("State", &format!("{:?}", status.state)),
+        row("Weight", &kg(status.last_weight_g)),
+        row("Raw weight", &kg(status.last_raw_weight_g)),
+        row("Last reading", &timestamp(last_reading_at)),
+    ];
+
+    if let Some(reason) = &status.fault_reason {
+        rows.push(row("Fault", &fault(reason)));
+    }
+    if let Some(trip) = &status.interlock {
+        rows.push(row("Harvest blocked", &escape_html(&trip.to_string())));
+    }
+
+    match last_harvest {
+        Some(report) => {
+            rows.push(row("Last harvest", &timestamp(Some(report.completed_at))));
+            rows.push(row("Yield", &kg(Some(report.yield_g))));
+            if let Some(trip) = &report.interrupted_by {
+                rows.push(row("Interrupted by", &escape_html(&trip.to_string())));
+            }
+        }
+        None => rows.push(row("Last harvest", &timestamp(status.last_harvest_at))),
+    }
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>Smart Hive</title>\
+         <style>body{{font-family:sans-serif;max-width:30em;margin:auto;padding:1em}}table{{width:100%;border-collapse:collapse}}\
+         th,td{{text-align:left;padding:.4em;border-bottom:1px solid #ddd}}</style></head>\
+         <body><h1>Smart Hive</h1><table>{}</table>\
+         <script>document.querySelectorAll('[data-ts]').forEach(e=>e.textContent=new Date(e.dataset.ts*1000).toLocaleString())</script>\
+         </body></html>",
+        REFRESH_S,
+        rows.concat()
+    )
+}
+
+fn row(label: &str, value: &str) -> String {
+    format!("<tr><th>{}</th><td>{}</td></tr>", label, value)
+}
+
+fn kg(weight_g: Option<u32>) -> String {
+    match weight_g {
+        Some(weight_g) => format!("{:.1} kg", weight_g as f32 / 1000.0),
+        None => "-".to_string(),
+    }
+}
+
+/// Replaced by the browser's local time, the unix timestamp stays when scripts are off
+fn timestamp(at: Option<u64>) -> String {
+    match at {
+        Some(at) => format!("<span data-ts=\"{}\">{}</span>", at, at),
+        None => "never".to_string(),
+    }
+}
+
+fn fault(reason: &FaultReason) -> String {
+    let text = match reason {
+        FaultReason::Actuator { fault } => format!("actuator ({:?})", fault),
+        FaultReason::EmergencyStop => "emergency stop".to_string(),
+        FaultReason::WeightDidNotDrop { drop_g, min_drop_g } => {
+            format!("weight dropped by {} g, expected at least {} g", drop_g, min_drop_g)
+        }
+        FaultReason::CellsNotClosed => "cells not closed".to_string(),
+    };
+    escape_html(&text)
+}
@@ -0,0 +1,301 @@
+use std::io::{self, BufRead, Write};
+use std::sync::Mutex;
+
+use crate::controller::controller::{HiveCommand, HiveController};
+use crate::controller::dispatcher::{dispatch_command, reply_to_json, CommandEvent, CommandReply};
+use crate::state::actuators::HoneyCellDisplacer;
+use crate::state::sensors::SensorReadings;
+
+pub const HELP: &str = "\
+commands: a HiveCommand as JSON, or
+  status | policy | history
+  authorize | cancel | stop | reset
+  slide up | slide down
+  inject weight <g> [temp <°C>] [humidity <%>]
+  help";
+
+/// A reading typed on the console, fed to the controller as if the sensors reported it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InjectedReading {
+    pub weight_g: u32,
+    pub temperature_x10: Option<i16>,
+    pub humidity_x10: Option<u16>,
+}
+
+/// A line typed on the console
+#[derive(Debug, Clone)]
+pub enum ConsoleInput {
+    /// Boxed, a policy update is much larger than the other inputs
+    Command(Box<HiveCommand>),
+    Inject(InjectedReading),
+    Help,
+    /// A blank line
+    Nothing,
+}
+
+/// Parses a JSON `HiveCommand` (a line starting with `{`) or a shorthand, see `HELP`
+pub fn parse_line(line: &str) -> Result<ConsoleInput, String> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map(|command| ConsoleInput::Command(Box::new(command))).map_err(|e| e.to_string());
+    }
+
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let command = match words.as_slice() {
+        [] => return Ok(ConsoleInput::Nothing),
+        ["help" | "?"] => return Ok(ConsoleInput::Help),
+        ["inject", "weight", rest @ ..] => return parse_inject(rest).map(ConsoleInput::Inject),
+        ["status"] => HiveCommand::GetStatus,
+        ["policy"] => HiveCommand::GetPolicy,
+        ["history" | "harvests"] => HiveCommand::GetHarvestHistory,
+        ["authorize"] => HiveCommand::AuthorizeHarvest,
+        ["cancel"] => HiveCommand::CancelHarvest,
+        ["stop" | "estop"] => HiveCommand::EmergencyStop,
+        ["reset"] => HiveCommand::ResetFault,
+        ["slide", "up"] => HiveCommand::ManualSlideUp,
+        ["slide", "down"] => HiveCommand::ManualSlideDown,
+        _ => return Err(format!("Unknown command \"{}\", type help", line)),
+    };
+
+    Ok(ConsoleInput::Command(Box::new(command)))
+}
+
+/// `<g> [temp <°C>] [humidity <%>]`
+fn parse_inject(words: &[&str]) -> Result<InjectedReading, String> {
+    let [weight, options @ ..] = words else {
+        return Err("Usage: inject weight <g> [temp <°C>] [humidity <%>]".to_string());
+    };
+
+    let mut reading = InjectedReading {
+        weight_g: weight.parse().map_err(|_| format!("Invalid weight \"{}\", expected grams", weight))?,
+        ..Default::default()
+    };
+
+    for option in options.chunks(2) {
+        match option {
+            ["temp", value] => {
+                let temperature_x10 = tenths(value).filter(|t| i16::try_from(*t).is_ok());
+                reading.temperature_x10 = Some(temperature_x10.ok_or(format!("Invalid temperature \"{}\"", value))? as i16);
+            }
+            ["humidity", value] => {
+                let humidity_x10 = tenths(value).filter(|h| (0..=1000).contains(h));
+                reading.humidity_x10 = Some(humidity_x10.ok_or(format!("Invalid humidity \"{}\"", value))? as u16);
+            }
+            _ => return Err(format!("Unexpected \"{}\"", option.join(" "))),
+        }
+    }
+
+    Ok(reading)
+}
+
+/// "34.5" -> 345, the console takes the units a person reads while the controller works with tenths
+fn tenths(value: &str) -> Option<i32> {
+    let value: f32 = value.parse().ok()?;
+    value.is_finite().then(|| (value * 10.0).round() as i32)
+}
+
+/// Runs a line against the controller and returns what to print: the reply and the state transitions.
+/// `now_s` gives the timestamp of an injected reading from the timestamp of the last reading
+pub fn execute_line<H: HoneyCellDisplacer>(
+    controller: &mut HiveController<H>,
+    line: &str,
+    now_s: impl FnOnce(Option<u64>) -> u64,
+) -> Vec<String> {
+    let input = match parse_line(line) {
+        Ok(input) => input,
+        Err(e) => return vec![format!("error: {}", e)],
+    };
+
+    match input {
+        ConsoleInput::Nothing => Vec::new(),
+        ConsoleInput::Help => vec![HELP.to_string()],
+        ConsoleInput::Command(command) => {
+            let mut ignore = |_: &CommandReply| {};
+            let outcome = dispatch_command(controller, *command, &mut ignore);
+
+            let mut output = vec![reply_to_json(&outcome.reply)];
+            for event in outcome.events {
+                if let CommandEvent::StateChanged(transition) = event {
+                    output.push(format!("state: {:?} -> {:?}", transition.previous_state, transition.new_state));
+                }
+            }
+            output
+        }
+        ConsoleInput::Inject(injected) => {
+            let previous_state = controller.state();
+            controller.update(SensorReadings {
+                weight_g: injected.weight_g,
+                temperature_x10: injected.temperature_x10,
+                humidity_x10: injected.humidity_x10,
+                timestamp_s: now_s(controller.last_reading_at()),
+                ..Default::default()
+            });
+
+            let status = controller.get_status();
+            let mut output = vec![format!(
+                "weight: {} g (filtered {} g)",
+                injected.weight_g,
+                status.last_weight_g.unwrap_or_default()
+            )];
+            if status.state != previous_state {
+                output.push(format!("state: {:?} -> {:?}", previous_state, status.state));
+            }
+            output
+        }
+    }
+}
+
+/// Reads lines until the input ends, e.g. from the UART or stdin, and prints the results.
+/// The controller is only locked while a line runs
+pub fn run_console<H: HoneyCellDisplacer>(
+    controller: &Mutex<HiveController<H>>,
+    input: impl BufRead,
+    mut output: impl Write,
+    mut now_s: impl FnMut(Option<u64>) -> u64,
+) -> io::Result<()> {
+    write!(output, "> ")?;
+    output.flush()?;
+
+    for line in input.lines() {
+        let printed = execute_line(&mut controller.lock().unwrap(), &line?, &mut now_s);
+        for text in printed {
+            writeln!(output, "{}", text)?;
+        }
+
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::actuators::MockDisplacer;
+    use crate::state::policy::harvest::HarvestPolicyConfigs;
+
+    fn command(line: &str) -> HiveCommand {
+        match parse_line(line) {
+            Ok(ConsoleInput::Command(command)) => *command,
+            other => panic!("{:?} for \"{}\"", other, line),
+        }
+    }
+
+    fn inject(line: &str) -> Result<InjectedReading, String> {
+        match parse_line(line)? {
+            ConsoleInput::Inject(reading) => Ok(reading),
+            other => panic!("{:?} for \"{}\"", other, line),
+        }
+    }
+
+    #[test]
+    fn shorthands() {
+        assert!(matches!(command("status"), HiveCommand::GetStatus));
+        assert!(matches!(command("policy"), HiveCommand::GetPolicy));
+        assert!(matches!(command("history"), HiveCommand::GetHarvestHistory));
+        assert!(matches!(command("harvests"), HiveCommand::GetHarvestHistory));
+        assert!(matches!(command("authorize"), HiveCommand::AuthorizeHarvest));
+        assert!(matches!(command("cancel"), HiveCommand::CancelHarvest));
+        assert!(matches!(command("stop"), HiveCommand::EmergencyStop));
+        assert!(matches!(command("estop"), HiveCommand::EmergencyStop));
+        assert!(matches!(command("reset"), HiveCommand::ResetFault));
+        assert!(matches!(command("slide up"), HiveCommand::ManualSlideUp));
+        assert!(matches!(command("  slide   down \r"), HiveCommand::ManualSlideDown));
+
+        assert!(matches!(parse_line("help"), Ok(ConsoleInput::Help)));
+        assert!(matches!(parse_line("?"), Ok(ConsoleInput::Help)));
+        assert!(matches!(parse_line(""), Ok(ConsoleInput::Nothing)));
+        assert!(matches!(parse_line("  \t"), Ok(ConsoleInput::Nothing)));
+    }
+
+    #[test]
+    fn unknown_shorthands_are_rejected() {
+        for line in ["launch", "slide", "slide sideways", "status now", "STATUS"] {
+            assert!(parse_line(line).unwrap_err().starts_with("Unknown command"), "{}", line);
+        }
+    }
+
+    #[test]
+    fn injects_a_weight() {
+        assert_eq!(
+            inject("inject weight 5200"),
+            Ok(InjectedReading {
+                weight_g: 5200,
+                temperature_x10: None,
+                humidity_x10: None,
+            })
+        );
+    }
+
+    #[test]
+    fn injects_a_weight_with_temperature_and_humidity() {
+        let expected = InjectedReading {
+            weight_g: 5200,
+            temperature_x10: Some(345),
+            humidity_x10: Some(610),
+        };
+        assert_eq!(inject("inject weight 5200 temp 34.5 humidity 61"), Ok(expected));
+        assert_eq!(inject("inject weight 5200 humidity 61.0 temp 34.5"), Ok(expected));
+
+        let reading = inject("inject weight 0 temp -3.25").unwrap();
+        assert_eq!(reading.temperature_x10, Some(-33));
+        assert_eq!(inject("inject weight 1 humidity 100").unwrap().humidity_x10, Some(1000));
+    }
+
+    #[test]
+    fn bad_numbers_are_rejected() {
+        for (line, error) in [
+            ("inject weight", "Usage: "),
+            ("inject weight heavy", "Invalid weight \"heavy\""),
+            ("inject weight -5", "Invalid weight \"-5\""),
+            ("inject weight 5.5", "Invalid weight \"5.5\""),
+            ("inject weight 5200 temp warm", "Invalid temperature \"warm\""),
+            ("inject weight 5200 temp NaN", "Invalid temperature \"NaN\""),
+            ("inject weight 5200 temp inf", "Invalid temperature \"inf\""),
+            ("inject weight 5200 temp 4000", "Invalid temperature \"4000\""),
+            ("inject weight 5200 humidity 101", "Invalid humidity \"101\""),
+            ("inject weight 5200 humidity -1", "Invalid humidity \"-1\""),
+            ("inject weight 5200 temp", "Unexpected \"temp\""),
+            ("inject weight 5200 pressure 1013", "Unexpected \"pressure 1013\""),
+        ] {
+            let e = inject(line).unwrap_err();
+            assert!(e.starts_with(error), "\"{}\" gave \"{}\"", line, e);
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        assert!(matches!(command(r#"{"command":"get_status"}"#), HiveCommand::GetStatus));
+        assert!(matches!(command(r#"  {"command": "emergency_stop"}  "#), HiveCommand::EmergencyStop));
+
+        let policy = serde_json::to_string(&HarvestPolicyConfigs::default()).unwrap();
+        let line = format!(r#"{{"command":"update_policy","policy":{}}}"#, policy);
+        assert!(matches!(command(&line), HiveCommand::UpdatePolicy { .. }));
+
+        assert!(parse_line(r#"{"command":"launch"}"#).is_err());
+        assert!(parse_line("{").is_err());
+        // Only a line starting with a brace is JSON
+        assert!(parse_line(r#"status {"command":"get_status"}"#).unwrap_err().starts_with("Unknown command"));
+    }
+
+    #[test]
+    fn executes_lines() {
+        let mut controller = HiveController::new(HarvestPolicyConfigs::default(), MockDisplacer::default());
+
+        assert!(execute_line(&mut controller, "", |_| 0).is_empty());
+        assert_eq!(execute_line(&mut controller, "help", |_| 0), vec![HELP.to_string()]);
+        assert!(execute_line(&mut controller, "launch", |_| 0)[0].starts_with("error: Unknown command"));
+
+        let printed = execute_line(&mut controller, "stop", |_| 0);
+        assert_eq!(printed.len(), 2);
+        assert_eq!(printed[1], "state: Monitoring -> Fault");
+
+        let printed = execute_line(&mut controller, "inject weight 5200", |last_reading_at| {
+            assert_eq!(last_reading_at, None);
+            100
+        });
+        assert!(printed[0].starts_with("weight: 5200 g"));
+        assert_eq!(controller.last_reading_at(), Some(100));
+    }
+}
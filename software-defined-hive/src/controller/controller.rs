@@ -1,26 +1,120 @@
+use std::collections::VecDeque;
+
 use serde::{Deserialize, Serialize};
-use log::info;
+use log::{info, warn};
+
+use crate::controller::events::{HarvestConditions, HarvestReport, HiveEvent};
 
+use crate::state::policy::compensation::TemperatureCompensationConfigs;
 use crate::state::policy::harvest::HarvestPolicyConfigs;
+use crate::state::policy::interlocks::{EnvironmentalSensor, InterlockTrip};
 use crate::state::actuators::{HoneyCellDisplacer, HoneyCellDisplacerCommand};
-use crate::state::hive::HiveState;
+use crate::state::hive::{FaultReason, HiveState};
 use crate::state::sensors::SensorReadings;
+use crate::utils::compensation::{compensate, fit_compensation, CalibrationLog, CalibrationSample};
+use crate::utils::filters::WeightFilterChain;
+
+const SECONDS_PER_DAY: u32 = 24 * 3600;
+
+/// Number of harvest reports kept on the device, the oldest report is dropped first
+pub const HARVEST_HISTORY_CAPACITY: usize = 16;
+
+/// Number of (raw weight, external temperature) averages kept for calibrating the temperature compensation,
+/// 48 hours of `CALIBRATION_LOG_INTERVAL_S` so that a no-load period can span a day/night cycle
+pub const CALIBRATION_LOG_CAPACITY: usize = 576;
 
 /// This describes the brain of the hive
 pub struct HiveController<H: HoneyCellDisplacer> {
     state: HiveState,
     policy: HarvestPolicyConfigs,
     honey_cell_displacer: H,
+    weight_filter: WeightFilterChain,
 
     // Internal memory
     last_weight_g: Option<u32>,
+    last_raw_weight_g: Option<u32>,
+    last_compensated_weight_g: Option<u32>,
     stable_since: Option<u64>,
     drain_started_at: Option<u64>,
+    verification_started_at: Option<u64>,
+    fault_reason: Option<FaultReason>,
+    last_harvest_at: Option<u64>,
+    last_reading_at: Option<u64>,
+    // Newest time seen, from a reading or a tick - the interlocks measure the age of the readings against it
+    clock_s: Option<u64>,
+    // (value, timestamp_s) of the latest environmental readings - used by the interlocks
+    last_temperature_x10: Option<(i16, u64)>,
+    last_humidity_x10: Option<(u16, u64)>,
+    last_external_temperature_x10: Option<(i16, u64)>,
+    calibration_log: CalibrationLog,
+    harvest: Option<HarvestInProgress>,
+    harvest_history: VecDeque<HarvestReport>,
+    events: Vec<HiveEvent>,
 
     // Latched intent
     authorized: bool,
 }
 
+/// What we know about the ongoing harvest, turned into a `HarvestReport` once it is verified
+#[derive(Debug, Default)]
+struct HarvestInProgress {
+    started_at: u64,
+    weight_before_g: u32,
+    drain_ended_at: Option<u64>,
+    interrupted_by: Option<InterlockTrip>,
+    // (sum, number of samples) of the environmental readings while draining
+    temperature_x10: (i64, i64),
+    external_temperature_x10: (i64, i64),
+    humidity_x10: (i64, i64),
+}
+
+impl HarvestInProgress {
+    fn record_conditions(&mut self, reading: &SensorReadings) {
+        fn accumulate(total: &mut (i64, i64), value: Option<i64>) {
+            if let Some(value) = value {
+                total.0 += value;
+                total.1 += 1;
+            }
+        }
+
+        accumulate(&mut self.temperature_x10, reading.temperature_x10.map(i64::from));
+        accumulate(&mut self.external_temperature_x10, reading.external_temperature_x10.map(i64::from));
+        accumulate(&mut self.humidity_x10, reading.humidity_x10.map(i64::from));
+    }
+
+    fn into_report(self, weight_after_g: u32, completed_at: u64) -> HarvestReport {
+        fn average((sum, count): (i64, i64)) -> Option<i64> {
+            (count > 0).then(|| sum / count)
+        }
+
+        let yield_g = self.weight_before_g.saturating_sub(weight_after_g);
+        let drain_duration_s = self
+            .drain_ended_at
+            .unwrap_or(completed_at)
+            .saturating_sub(self.started_at);
+        let average_flow_rate_g_per_min = match drain_duration_s {
+            0 => 0,
+            duration_s => (yield_g as u64 * 60 / duration_s) as u32,
+        };
+
+        HarvestReport {
+            started_at: self.started_at,
+            completed_at,
+            weight_before_g: self.weight_before_g,
+            weight_after_g,
+            yield_g,
+            drain_duration_s,
+            average_flow_rate_g_per_min,
+            conditions: HarvestConditions {
+                average_temperature_x10: average(self.temperature_x10).map(|value| value as i16),
+                average_external_temperature_x10: average(self.external_temperature_x10).map(|value| value as i16),
+                average_humidity_x10: average(self.humidity_x10).map(|value| value as u16),
+            },
+            interrupted_by: self.interrupted_by,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "command")]
 pub enum HiveCommand {
@@ -44,7 +138,7 @@ pub enum HiveCommand {
 
     #[serde(rename = "update_policy")]
     UpdatePolicy {
-        policy: HarvestPolicyConfigs,
+        policy: Box<HarvestPolicyConfigs>,
     },
 
     #[serde(rename = "get_policy")]
@@ -52,14 +146,33 @@ pub enum HiveCommand {
 
     #[serde(rename = "get_status")]
     GetStatus,
+
+    #[serde(rename = "get_harvest_history")]
+    GetHarvestHistory,
+
+    /// Fits the temperature compensation from the samples logged between start_s and end_s, the load on the scale must not change in that period
+    #[serde(rename = "calibrate_temperature_compensation")]
+    CalibrateTemperatureCompensation {
+        start_s: u64,
+        end_s: u64,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HiveStatus {
     pub state: HiveState,
+    /// Latest weight after the filter chain - this is what drives the FSM
     pub last_weight_g: Option<u32>,
+    /// Latest weight as reported by the sensor
+    pub last_raw_weight_g: Option<u32>,
     pub stable_since: Option<u64>,
     pub drain_started_at: Option<u64>,
+    pub verification_started_at: Option<u64>,
+    /// Why the hive is in the Fault state (`None` in any other state)
+    pub fault_reason: Option<FaultReason>,
+    pub last_harvest_at: Option<u64>,
+    /// The interlock currently blocking a harvest, if any
+    pub interlock: Option<InterlockTrip>,
     pub policy: HarvestPolicyConfigs,
 }
 
@@ -69,22 +182,107 @@ pub struct PolicyUpdateResponse {
     pub policy: HarvestPolicyConfigs,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompensationCalibrationResponse {
+    pub status: String,
+    pub samples: usize,
+    pub compensation: TemperatureCompensationConfigs,
+}
+
+/// `{"status":"success"}`, for the commands without a response of their own
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AckResponse {
+    pub status: String,
+}
+
+/// What a command returns, serialized as the bare response (`get_status` is a `HiveStatus`, `get_policy` the policy...)
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum CommandResponse {
+    Ack(AckResponse),
+    Status(Box<HiveStatus>),
+    Policy(Box<HarvestPolicyConfigs>),
+    HarvestHistory(Vec<HarvestReport>),
+    PolicyUpdated(Box<PolicyUpdateResponse>),
+    CompensationCalibrated(CompensationCalibrationResponse),
+}
+
+impl CommandResponse {
+    pub fn ack() -> Self {
+        CommandResponse::Ack(AckResponse {
+            status: "success".into(),
+        })
+    }
+}
+
 impl<H: HoneyCellDisplacer> HiveController<H> {
     pub fn new(policy: HarvestPolicyConfigs, honey_cell_displacer: H) -> Self {
         Self {
             state: HiveState::Monitoring,
+            weight_filter: WeightFilterChain::new(policy.filters.clone()),
             policy,
             honey_cell_displacer,
             last_weight_g: None,
+            last_raw_weight_g: None,
+            last_compensated_weight_g: None,
             stable_since: None,
             drain_started_at: None,
+            verification_started_at: None,
+            fault_reason: None,
+            last_harvest_at: None,
+            last_reading_at: None,
+            clock_s: None,
+            last_temperature_x10: None,
+            last_humidity_x10: None,
+            last_external_temperature_x10: None,
+            calibration_log: CalibrationLog::new(CALIBRATION_LOG_CAPACITY),
+            harvest: None,
+            harvest_history: VecDeque::with_capacity(HARVEST_HISTORY_CAPACITY),
+            events: Vec::new(),
             authorized: false,
         }
     }
 
     // SENSOR UPDATE (DRIVES FSM)
 
-    pub fn update(&mut self, reading: SensorReadings) {
+    pub fn update(&mut self, mut reading: SensorReadings) {
+        // A delayed reading would run the FSM and the filters back in time
+        if let Some(last_reading_at) = self.last_reading_at.filter(|last| reading.timestamp_s < *last) {
+            warn!("Dropped a sensor reading older than the last one ({}s < {}s)", reading.timestamp_s, last_reading_at);
+            return;
+        }
+
+        self.record_environment(&reading);
+
+        // Sensor pipeline: temperature compensation -> filter chain. Everything from here on (FSM, harvest report) works with the filtered weight
+        let raw_weight_g = reading.weight_g;
+        self.last_raw_weight_g = Some(raw_weight_g);
+
+        if let Some(external_temperature_x10) = reading.external_temperature_x10 {
+            self.calibration_log.push(CalibrationSample {
+                timestamp_s: reading.timestamp_s,
+                external_temperature_x10,
+                raw_weight_g,
+            });
+        }
+
+        // A stale temperature would add a drift of its own
+        let now = self.clock_s.unwrap_or_default();
+        let compensated_weight_g = match self.last_external_temperature_x10 {
+            Some((external_temperature_x10, timestamp_s)) if now.saturating_sub(timestamp_s) <= self.policy.compensation.max_temperature_age_s => {
+                compensate(raw_weight_g, external_temperature_x10, &self.policy.compensation)
+            }
+            _ => raw_weight_g,
+        };
+        self.last_compensated_weight_g = Some(compensated_weight_g);
+        reading.weight_g = if matches!(self.state, HiveState::Monitoring | HiveState::Candidate | HiveState::Ready) {
+            self.weight_filter.apply(compensated_weight_g, reading.timestamp_s)
+        } else {
+            // The harvest drop is a real change that the filters would smooth away or reject, they start over after the harvest
+            self.weight_filter.reset();
+            compensated_weight_g
+        };
+
         match self.state {
             HiveState::Monitoring => {
                 self.last_weight_g = Some(reading.weight_g);
@@ -104,7 +302,7 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
                             .get_or_insert(reading.timestamp_s);
 
                         if reading.timestamp_s
-                            - self.stable_since.unwrap()
+                            .saturating_sub(self.stable_since.unwrap())
                             >= self.policy.stability_window_s
                         {
                             self.state = HiveState::Ready;
@@ -118,25 +316,36 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
             HiveState::Ready => {
                 if self.authorized {
                     self.authorized = false;
-                    self.enter_authorized(reading.timestamp_s);
+
+                    // Conditions might have changed since the authorization was accepted
+                    match self.check_interlocks() {
+                        Ok(_) => self.enter_authorized(&reading),
+                        Err(trip) => warn!("Authorized harvest blocked by interlock: {}", trip),
+                    }
+                } else if self.auto_harvest_permitted(&reading) && self.check_interlocks().is_ok() {
+                    info!("Harvest auto-authorized by policy");
+                    self.enter_authorized(&reading);
                 }
             }
 
             HiveState::Draining => {
-                if reading.timestamp_s
-                    - self.drain_started_at.unwrap()
+                if let Some(harvest) = self.harvest.as_mut() {
+                    harvest.record_conditions(&reading);
+                }
+
+                if let Err(trip) = self.check_interlocks() {
+                    warn!("Interlock tripped while draining: {}", trip);
+                    self.interrupt_drain(trip, reading.timestamp_s);
+                } else if reading.timestamp_s
+                    .saturating_sub(self.drain_started_at.unwrap_or(reading.timestamp_s))
                     >= self.policy.max_drain_time_s
                 {
-                    self.enter_closing();
+                    self.enter_closing(reading.timestamp_s);
                 }
             }
 
             HiveState::Verifying => {
-                if let Some(last) = self.last_weight_g {
-                    if reading.weight_g < last {
-                        self.reset_to_monitoring();
-                    }
-                }
+                self.verify_harvest(reading.weight_g, reading.timestamp_s);
             }
 
             HiveState::Fault => {
@@ -149,15 +358,62 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
         self.last_weight_g = Some(reading.weight_g);
     }
 
+    // CLOCK (DRIVES THE TIMEOUTS WITHOUT READINGS)
+
+    /// Advances the time-driven transitions when no reading arrives, e.g. while the hive is offline and the sensor readings do not reach it.
+    /// A drain is never left open past `max_drain_time_s` or once its sensor data goes stale, and a verification is concluded
+    /// with the last known weight. `now_s` has to be on the same clock as the reading timestamps
+    pub fn tick(&mut self, now_s: u64) {
+        self.clock_s = self.clock_s.max(Some(now_s));
+
+        // A reading newer than now_s already drove the FSM
+        if self.last_reading_at.is_some_and(|last| last >= now_s) {
+            return;
+        }
+
+        match self.state {
+            HiveState::Draining => {
+                let draining_for_s = now_s.saturating_sub(self.drain_started_at.unwrap_or(now_s));
+
+                if let Err(trip) = self.check_interlocks() {
+                    warn!("Interlock tripped while draining without sensor readings: {}", trip);
+                    self.interrupt_drain(trip, now_s);
+                } else if draining_for_s >= self.policy.max_drain_time_s {
+                    warn!("Drain timed out without sensor readings, closing the cells");
+                    self.enter_closing(now_s);
+                }
+            }
+
+            HiveState::Verifying => {
+                if let Some(weight_g) = self.last_weight_g {
+                    self.verify_harvest(weight_g, now_s);
+                }
+            }
+
+            _ => {}
+        }
+    }
+
     // COMMAND HANDLING (INTENT)
 
+    /// Executes a command, the JSON of the response (`None` for an acknowledgement) is what the transports send back
     pub fn process_command(&mut self, command: HiveCommand) -> Result<Option<String>, String> {
+        match self.execute_command(command)? {
+            CommandResponse::Ack(_) => Ok(None),
+            response => serde_json::to_string(&response).map(Some).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn execute_command(&mut self, command: HiveCommand) -> Result<CommandResponse, String> {
         match command {
             HiveCommand::AuthorizeHarvest => {
                 if self.state == HiveState::Ready {
+                    self.check_interlocks()
+                        .map_err(|trip| format!("Harvest blocked by interlock: {}", trip))?;
+
                     self.authorized = true;
                     info!("Harvest authorized");
-                    Ok(None)
+                    Ok(CommandResponse::ack())
                 } else {
                     Err(format!("Cannot authorize harvest in state {:?}", self.state))
                 }
@@ -166,23 +422,21 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
             HiveCommand::CancelHarvest => {
                 if matches!(self.state, HiveState::Ready | HiveState::Draining) {
                     self.reset_to_monitoring();
-                    Ok(None)
+                    Ok(CommandResponse::ack())
                 } else {
                     Err(format!("Cannot cancel harvest in state {:?}", self.state))
                 }
             }
 
             HiveCommand::EmergencyStop => {
-                let _ = self.honey_cell_displacer
-                    .execute(HoneyCellDisplacerCommand::Stop);
-                self.state = HiveState::Fault;
-                Ok(None)
+                self.enter_fault(FaultReason::EmergencyStop);
+                Ok(CommandResponse::ack())
             }
 
             HiveCommand::ResetFault => {
                 if self.state == HiveState::Fault {
                     self.reset_to_monitoring();
-                    Ok(None)
+                    Ok(CommandResponse::ack())
                 } else {
                     Err(format!("Not in fault state, current state: {:?}", self.state))
                 }
@@ -192,34 +446,68 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
                 self.honey_cell_displacer
                     .execute(HoneyCellDisplacerCommand::SlideDown)
                     .map_err(|e| format!("Failed to slide down: {:?}", e))?;
-                Ok(None)
+                Ok(CommandResponse::ack())
             }
 
             HiveCommand::ManualSlideUp => {
                 self.honey_cell_displacer
                     .execute(HoneyCellDisplacerCommand::SlideUp)
                     .map_err(|e| format!("Failed to slide up: {:?}", e))?;
-                Ok(None)
+                Ok(CommandResponse::ack())
             }
 
             HiveCommand::UpdatePolicy { policy } => {
                 self.validate_policy(&policy)?;
-                self.policy = policy.clone();
-
-                Ok(Some(
-                    serde_json::to_string(&PolicyUpdateResponse {
-                        status: "success".into(),
-                        policy,
-                    }).map_err(|e| e.to_string())?
-                ))
+
+                if policy.filters != self.policy.filters || policy.compensation != self.policy.compensation {
+                    self.weight_filter = WeightFilterChain::new(policy.filters.clone());
+                }
+                self.policy = (*policy).clone();
+
+                Ok(CommandResponse::PolicyUpdated(Box::new(PolicyUpdateResponse {
+                    status: "success".into(),
+                    policy: *policy,
+                })))
             }
 
             HiveCommand::GetPolicy => {
-                Ok(Some(serde_json::to_string(&self.policy).unwrap()))
+                Ok(CommandResponse::Policy(Box::new(self.policy.clone())))
             }
 
             HiveCommand::GetStatus => {
-                Ok(Some(serde_json::to_string(&self.get_status()).unwrap()))
+                Ok(CommandResponse::Status(Box::new(self.get_status())))
+            }
+
+            HiveCommand::GetHarvestHistory => {
+                Ok(CommandResponse::HarvestHistory(self.harvest_history.iter().cloned().collect()))
+            }
+
+            HiveCommand::CalibrateTemperatureCompensation { start_s, end_s } => {
+                let samples = self.calibration_log.samples_between(start_s, end_s);
+                let compensation = TemperatureCompensationConfigs {
+                    max_temperature_age_s: self.policy.compensation.max_temperature_age_s,
+                    ..fit_compensation(&samples)?
+                };
+
+                // The fitted coefficient has to pass the same checks as a policy update
+                let mut policy = self.policy.clone();
+                policy.compensation = compensation.clone();
+                self.validate_policy(&policy)
+                    .map_err(|e| format!("Calibration rejected: {}", e))?;
+
+                info!(
+                    "Temperature compensation calibrated: {} g/°C * 10 around {}°C * 10",
+                    compensation.coefficient_g_per_c_x10, compensation.reference_temperature_x10
+                );
+                self.policy = policy;
+                // The filters hold uncompensated history
+                self.weight_filter = WeightFilterChain::new(self.policy.filters.clone());
+
+                Ok(CommandResponse::CompensationCalibrated(CompensationCalibrationResponse {
+                    status: "success".into(),
+                    samples: samples.len(),
+                    compensation,
+                }))
             }
         }
     }
@@ -227,20 +515,215 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
 
     // STATE ENTRY ACTIONS
 
-    fn enter_authorized(&mut self, now: u64) {
+    fn enter_authorized(&mut self, reading: &SensorReadings) {
+        let now = reading.timestamp_s;
+
         match self.honey_cell_displacer.execute(HoneyCellDisplacerCommand::SlideDown) {
             Ok(_) => {
                 self.drain_started_at = Some(now);
+                self.last_harvest_at = Some(now);
+                self.harvest = Some(HarvestInProgress {
+                    started_at: now,
+                    weight_before_g: reading.weight_g,
+                    ..Default::default()
+                });
                 self.state = HiveState::Draining;
             }
-            Err(_) => self.state = HiveState::Fault,
+            Err(fault) => self.enter_fault(FaultReason::Actuator { fault }),
+        }
+    }
+
+    /// Controlled close - stops draining but leaves the cells in a safe (closed) position
+    fn interrupt_drain(&mut self, trip: InterlockTrip, now: u64) {
+        if let Some(harvest) = self.harvest.as_mut() {
+            harvest.interrupted_by = Some(trip);
         }
+        self.enter_closing(now);
     }
 
-    fn enter_closing(&mut self) {
+    fn enter_closing(&mut self, now: u64) {
+        if let Some(harvest) = self.harvest.as_mut() {
+            harvest.drain_ended_at = Some(now);
+        }
+
         match self.honey_cell_displacer.execute(HoneyCellDisplacerCommand::SlideUp) {
-            Ok(_) => self.state = HiveState::Verifying,
-            Err(_) => self.state = HiveState::Fault,
+            Ok(_) => {
+                self.verification_started_at = Some(now);
+                self.state = HiveState::Verifying;
+            }
+            Err(fault) => self.enter_fault(FaultReason::Actuator { fault }),
+        }
+    }
+
+    /// A harvest is successful when the hive lost at least `min_harvest_drop_g` and the top limit switch confirms the cells are closed.
+    /// Both have to be confirmed within `verification_timeout_s`, otherwise the hive goes to Fault
+    fn verify_harvest(&mut self, weight_g: u32, now: u64) {
+        let cells_closed = match self.honey_cell_displacer.cells_closed() {
+            Ok(cells_closed) => cells_closed,
+            Err(fault) => {
+                self.enter_fault(FaultReason::Actuator { fault });
+                return;
+            }
+        };
+
+        let min_drop_g = self.min_harvest_drop_g(now);
+        let drop_g = self.harvest
+            .as_ref()
+            .map(|harvest| harvest.weight_before_g.saturating_sub(weight_g))
+            .unwrap_or_default();
+
+        if cells_closed && drop_g >= min_drop_g {
+            self.complete_harvest(weight_g, now);
+            return;
+        }
+
+        let verifying_for_s = now.saturating_sub(self.verification_started_at.unwrap_or(now));
+
+        if verifying_for_s >= self.policy.verification_timeout_s {
+            if !cells_closed {
+                self.enter_fault(FaultReason::CellsNotClosed);
+            } else {
+                self.enter_fault(FaultReason::WeightDidNotDrop { drop_g, min_drop_g });
+            }
+        }
+    }
+
+    /// A drain cut short by an interlock only has to yield its share of the minimum, pro rata of `max_drain_time_s`
+    fn min_harvest_drop_g(&self, now: u64) -> u32 {
+        let min_drop_g = self.policy.min_harvest_drop_g;
+
+        match &self.harvest {
+            Some(harvest) if harvest.interrupted_by.is_some() => {
+                let drained_s = harvest.drain_ended_at.unwrap_or(now).saturating_sub(harvest.started_at);
+                let share_g = min_drop_g as u64 * drained_s / self.policy.max_drain_time_s.max(1);
+                share_g.min(min_drop_g as u64) as u32
+            }
+            _ => min_drop_g,
+        }
+    }
+
+    fn enter_fault(&mut self, reason: FaultReason) {
+        warn!("Hive fault: {:?}", reason);
+
+        // Whatever went wrong, the displacers must not keep moving
+        if let Err(fault) = self.honey_cell_displacer.execute(HoneyCellDisplacerCommand::Stop) {
+            warn!("Failed to stop the honey cell displacer: {:?}", fault);
+        }
+        self.state = HiveState::Fault;
+        self.fault_reason = Some(reason);
+    }
+
+    fn complete_harvest(&mut self, weight_g: u32, now: u64) {
+        if let Some(harvest) = self.harvest.take() {
+            let report = harvest.into_report(weight_g, now);
+            info!("Harvest complete: {}g of honey in {}s", report.yield_g, report.drain_duration_s);
+
+            if self.harvest_history.len() == HARVEST_HISTORY_CAPACITY {
+                self.harvest_history.pop_front();
+            }
+            self.harvest_history.push_back(report.clone());
+            self.events.push(HiveEvent::HarvestReport(report));
+        }
+
+        self.reset_to_monitoring();
+    }
+
+    // ENVIRONMENTAL INTERLOCKS
+
+    fn record_environment(&mut self, reading: &SensorReadings) {
+        self.last_reading_at = Some(reading.timestamp_s);
+        self.clock_s = self.clock_s.max(Some(reading.timestamp_s));
+
+        if let Some(temperature_x10) = reading.temperature_x10 {
+            self.last_temperature_x10 = Some((temperature_x10, reading.timestamp_s));
+        }
+
+        if let Some(humidity_x10) = reading.humidity_x10 {
+            self.last_humidity_x10 = Some((humidity_x10, reading.timestamp_s));
+        }
+
+        if let Some(external_temperature_x10) = reading.external_temperature_x10 {
+            self.last_external_temperature_x10 = Some((external_temperature_x10, reading.timestamp_s));
+        }
+    }
+
+    /// Evaluates the configured interlocks against the latest environmental readings. A missing or stale reading trips the interlock that depends on it
+    fn check_interlocks(&self) -> Result<(), InterlockTrip> {
+        let interlocks = &self.policy.interlocks;
+        let now = self.clock_s.unwrap_or_default();
+
+        if let Some(min_temperature_x10) = interlocks.min_temperature_x10 {
+            let temperature_x10 = self.fresh_reading(self.last_temperature_x10, EnvironmentalSensor::Temperature, now)?;
+
+            if temperature_x10 < min_temperature_x10 {
+                return Err(InterlockTrip::TooCold { temperature_x10, min_temperature_x10 });
+            }
+        }
+
+        if let Some(max_humidity_x10) = interlocks.max_humidity_x10 {
+            let humidity_x10 = self.fresh_reading(self.last_humidity_x10, EnvironmentalSensor::Humidity, now)?;
+
+            if humidity_x10 > max_humidity_x10 {
+                return Err(InterlockTrip::TooHumid { humidity_x10, max_humidity_x10 });
+            }
+        }
+
+        if let Some(min_external_temperature_x10) = interlocks.min_external_temperature_x10 {
+            let external_temperature_x10 =
+                self.fresh_reading(self.last_external_temperature_x10, EnvironmentalSensor::ExternalTemperature, now)?;
+
+            if external_temperature_x10 < min_external_temperature_x10 {
+                return Err(InterlockTrip::TooColdOutside { external_temperature_x10, min_external_temperature_x10 });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fresh_reading<T: Copy>(
+        &self,
+        reading: Option<(T, u64)>,
+        sensor: EnvironmentalSensor,
+        now: u64,
+    ) -> Result<T, InterlockTrip> {
+        let (value, timestamp_s) = reading
+            .ok_or(InterlockTrip::StaleSensorData { sensor, age_s: None })?;
+        let age_s = now.saturating_sub(timestamp_s);
+
+        match self.policy.interlocks.max_sensor_age_s {
+            Some(max_age_s) if age_s > max_age_s => {
+                Err(InterlockTrip::StaleSensorData { sensor, age_s: Some(age_s) })
+            }
+            _ => Ok(value),
+        }
+    }
+
+    // AUTO HARVEST RULES
+
+    /// All the auto-harvest rules have to pass: enabled, inside a time-of-day window, warm enough and not harvested too recently
+    fn auto_harvest_permitted(&self, reading: &SensorReadings) -> bool {
+        let rules = &self.policy.auto_harvest;
+
+        if !rules.enabled {
+            return false;
+        }
+
+        let local_time_s = reading.timestamp_s as i64 + rules.utc_offset_s as i64;
+        let second_of_day = local_time_s.rem_euclid(SECONDS_PER_DAY as i64) as u32;
+
+        if !rules.windows.iter().any(|window| window.contains(second_of_day)) {
+            return false;
+        }
+
+        match reading.temperature_x10 {
+            Some(temperature_x10) if temperature_x10 >= rules.min_temperature_x10 => {}
+            // No temperature reading means we cannot tell whether the honey will flow
+            _ => return false,
+        }
+
+        match self.last_harvest_at {
+            Some(last) => reading.timestamp_s.saturating_sub(last) >= rules.min_interval_s,
+            None => true,
         }
     }
 
@@ -249,6 +732,9 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
         self.authorized = false;
         self.stable_since = None;
         self.drain_started_at = None;
+        self.verification_started_at = None;
+        self.fault_reason = None;
+        self.harvest = None;
     }
 
 
@@ -258,12 +744,41 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
         self.state
     }
 
+    pub fn policy(&self) -> &HarvestPolicyConfigs {
+        &self.policy
+    }
+
+    /// Timestamp of the latest reading, the clock `tick` has to follow
+    pub fn last_reading_at(&self) -> Option<u64> {
+        self.last_reading_at
+    }
+
+    /// Completed harvests, oldest first (bounded by `HARVEST_HISTORY_CAPACITY`)
+    pub fn harvest_history(&self) -> &VecDeque<HarvestReport> {
+        &self.harvest_history
+    }
+
+    /// Drains the events emitted since the last call
+    pub fn take_events(&mut self) -> Vec<HiveEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// The last weight corrected for the temperature drift of the load cells, before the filters
+    pub fn last_compensated_weight_g(&self) -> Option<u32> {
+        self.last_compensated_weight_g
+    }
+
     pub fn get_status(&self) -> HiveStatus {
         HiveStatus {
             state: self.state,
             last_weight_g: self.last_weight_g,
+            last_raw_weight_g: self.last_raw_weight_g,
             stable_since: self.stable_since,
             drain_started_at: self.drain_started_at,
+            verification_started_at: self.verification_started_at,
+            fault_reason: self.fault_reason,
+            last_harvest_at: self.last_harvest_at,
+            interlock: self.check_interlocks().err(),
             policy: self.policy.clone(),
         }
     }
@@ -274,9 +789,574 @@ impl<H: HoneyCellDisplacer> HiveController<H> {
             || policy.stability_window_s == 0
             || policy.max_drain_time_s == 0
             || policy.max_drain_time_s > 3600
+            || policy.min_harvest_drop_g == 0
+            || policy.verification_timeout_s == 0
         {
             return Err("Invalid policy configuration".into());
         }
+
+        let auto_harvest = &policy.auto_harvest;
+        if auto_harvest.enabled && auto_harvest.windows.is_empty() {
+            return Err("Auto harvest requires at least one time-of-day window".into());
+        }
+
+        if auto_harvest.windows.iter().any(|window| {
+            window.start_s >= SECONDS_PER_DAY
+                || window.end_s >= SECONDS_PER_DAY
+                || window.start_s == window.end_s
+        }) {
+            return Err("Invalid auto harvest time-of-day window".into());
+        }
+
+        if auto_harvest.utc_offset_s.unsigned_abs() > 14 * 3600 {
+            return Err("Invalid auto harvest UTC offset".into());
+        }
+
+        if policy.filters.median_window == 0
+            || policy.filters.median_window > 15
+            || policy.filters.ema_alpha_pct == 0
+            || policy.filters.ema_alpha_pct > 100
+            || policy.filters.max_rate_g_per_s == Some(0)
+        {
+            return Err("Invalid sensor filter configuration".into());
+        }
+
+        if policy.compensation.coefficient_g_per_c_x10.unsigned_abs() > 10_000 {
+            return Err("Invalid temperature compensation coefficient".into());
+        }
+
+        let anomaly = &policy.anomaly;
+        if anomaly.sudden_drop_window_s == 0
+            || anomaly.swarm_drop_g == 0
+            || anomaly.theft_drop_g <= anomaly.swarm_drop_g
+            || anomaly.robbing_window_s == 0
+            || anomaly.robbing_loss_g == 0
+        {
+            return Err("Invalid anomaly detection configuration".into());
+        }
+
+        let colony_health = &policy.colony_health;
+        if colony_health.window_s == 0
+            || colony_health.min_samples == 0
+            || colony_health.brood_min_temperature_x10 >= colony_health.brood_max_temperature_x10
+            || colony_health.max_temperature_range_x10 <= 0
+            || colony_health.min_temperature_gap_x10 < 0
+        {
+            return Err("Invalid colony health configuration".into());
+        }
+
+        if policy.telemetry.interval_s == 0 || policy.telemetry.deadband.is_some_and(|deadband| deadband.max_silence_s == 0) {
+            return Err("Invalid telemetry configuration".into());
+        }
+
+        if policy.interlocks.max_humidity_x10.is_some_and(|humidity_x10| humidity_x10 > 1000)
+            || policy.interlocks.max_sensor_age_s == Some(0)
+        {
+            return Err("Invalid interlock configuration".into());
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::actuators::MockDisplacer;
+    use crate::state::policy::harvest::{AutoHarvestPolicy, TimeOfDayWindow};
+    use crate::state::policy::anomaly::AnomalyDetectionConfigs;
+    use crate::state::policy::interlocks::EnvironmentalInterlocks;
+
+    const HOUR: u64 = 3600;
+    /// 2025-01-31 00:00:00 UTC
+    const MIDNIGHT: u64 = 1_738_281_600;
+
+    fn controller(policy: HarvestPolicyConfigs) -> HiveController<MockDisplacer> {
+        HiveController::new(policy, MockDisplacer::default())
+    }
+
+    fn reading(weight_g: u32, timestamp_s: u64) -> SensorReadings {
+        SensorReadings {
+            weight_g,
+            temperature_x10: Some(300),
+            timestamp_s,
+            ..Default::default()
+        }
+    }
+
+    fn auto_harvest(windows: Vec<TimeOfDayWindow>, utc_offset_s: i32) -> HarvestPolicyConfigs {
+        HarvestPolicyConfigs {
+            auto_harvest: AutoHarvestPolicy {
+                enabled: true,
+                windows,
+                min_temperature_x10: 250,
+                min_interval_s: 24 * HOUR,
+                utc_offset_s,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Stable for a minute
+    fn quick_policy() -> HarvestPolicyConfigs {
+        HarvestPolicyConfigs {
+            stability_window_s: 60,
+            ..Default::default()
+        }
+    }
+
+    /// Feeds a stable weight until the hive is Ready, returns the time of the last reading
+    fn make_ready(hive: &mut HiveController<MockDisplacer>, start: u64, extra: impl Fn(&mut SensorReadings)) -> u64 {
+        let mut t = start;
+        while hive.state() != HiveState::Ready {
+            let mut reading = reading(6000, t);
+            extra(&mut reading);
+            hive.update(reading);
+            assert!(t - start <= 600, "the hive never became Ready");
+            t += 30;
+        }
+        t - 30
+    }
+
+    fn window(start_h: u32, end_h: u32) -> TimeOfDayWindow {
+        TimeOfDayWindow { start_s: start_h * 3600, end_s: end_h * 3600 }
+    }
+
+    #[test]
+    fn auto_harvest_is_off_by_default() {
+        let hive = controller(HarvestPolicyConfigs::default());
+
+        assert!(!hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 12 * HOUR)));
+    }
+
+    #[test]
+    fn auto_harvest_window_edges() {
+        let hive = controller(auto_harvest(vec![window(10, 16)], 0));
+
+        assert!(!hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 10 * HOUR - 1)));
+        assert!(hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 10 * HOUR)));
+        assert!(hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 16 * HOUR - 1)));
+        assert!(!hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 16 * HOUR)));
+    }
+
+    #[test]
+    fn auto_harvest_window_wrapping_midnight() {
+        let hive = controller(auto_harvest(vec![window(22, 2)], 0));
+
+        assert!(hive.auto_harvest_permitted(&reading(6000, MIDNIGHT - HOUR)));
+        assert!(hive.auto_harvest_permitted(&reading(6000, MIDNIGHT)));
+        assert!(hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 2 * HOUR - 1)));
+        assert!(!hive.auto_harvest_permitted(&reading(6000, MIDNIGHT + 2 * HOUR)));
+        assert!(!hive.auto_harvest_permitted(&reading(6000, MIDNIGHT - 2 * HOUR - 1)));
+    }
+
+    #[test]
+    fn auto_harvest_windows_are_in_local_time() {
+        // 10:00 - 16:00 in UTC+3 is 07:00 - 13:00 UTC
+        let east = controller(auto_harvest(vec![window(10, 16)], 3 * 3600));
+        assert!(east.auto_harvest_permitted(&reading(6000, MIDNIGHT + 7 * HOUR)));
+        assert!(!east.auto_harvest_permitted(&reading(6000, MIDNIGHT + 13 * HOUR)));
+
+        // 22:00 - 02:00 in UTC-5 is 03:00 - 07:00 UTC, the local day starts the previous UTC day
+        let west = controller(auto_harvest(vec![window(22, 2)], -5 * 3600));
+        assert!(west.auto_harvest_permitted(&reading(6000, MIDNIGHT + 3 * HOUR)));
+        assert!(!west.auto_harvest_permitted(&reading(6000, MIDNIGHT + 7 * HOUR)));
+        assert!(!west.auto_harvest_permitted(&reading(6000, MIDNIGHT + 2 * HOUR)));
+    }
+
+    #[test]
+    fn auto_harvest_requires_a_warm_enough_hive() {
+        let hive = controller(auto_harvest(vec![window(10, 16)], 0));
+        let noon = MIDNIGHT + 12 * HOUR;
+
+        let cold = SensorReadings { temperature_x10: Some(249), ..reading(6000, noon) };
+        let warm = SensorReadings { temperature_x10: Some(250), ..reading(6000, noon) };
+        let unknown = SensorReadings { temperature_x10: None, ..reading(6000, noon) };
+
+        assert!(!hive.auto_harvest_permitted(&cold));
+        assert!(hive.auto_harvest_permitted(&warm));
+        assert!(!hive.auto_harvest_permitted(&unknown));
+    }
+
+    #[test]
+    fn auto_harvest_waits_the_minimum_interval() {
+        let mut hive = controller(auto_harvest(vec![window(0, 23)], 0));
+        let noon = MIDNIGHT + 12 * HOUR;
+        hive.last_harvest_at = Some(noon - 24 * HOUR + 1);
+
+        assert!(!hive.auto_harvest_permitted(&reading(6000, noon)));
+        assert!(hive.auto_harvest_permitted(&reading(6000, noon + 1)));
+    }
+
+    #[test]
+    fn ready_hive_drains_inside_the_window() {
+        let mut policy = auto_harvest(vec![window(10, 16)], 0);
+        policy.stability_window_s = 60;
+        let mut hive = controller(policy);
+        let start = MIDNIGHT + 9 * HOUR;
+
+        for t in (0..=120).step_by(30) {
+            hive.update(reading(6000, start + t));
+        }
+        assert_eq!(hive.state(), HiveState::Ready);
+
+        hive.update(reading(6000, MIDNIGHT + 10 * HOUR));
+        assert_eq!(hive.state(), HiveState::Draining);
+        assert_eq!(hive.honey_cell_displacer.commands, vec![HoneyCellDisplacerCommand::SlideDown]);
+    }
+
+    fn interlocked_policy() -> HarvestPolicyConfigs {
+        let mut policy = quick_policy();
+        policy.interlocks = EnvironmentalInterlocks {
+            min_temperature_x10: Some(150),
+            max_humidity_x10: Some(800),
+            min_external_temperature_x10: Some(100),
+            max_sensor_age_s: Some(600),
+        };
+        policy
+    }
+
+    fn environment(reading: &mut SensorReadings) {
+        reading.humidity_x10 = Some(500);
+        reading.external_temperature_x10 = Some(200);
+    }
+
+    #[test]
+    fn authorization_is_rejected_with_the_interlock() {
+        let mut hive = controller(interlocked_policy());
+        make_ready(&mut hive, MIDNIGHT, |reading| {
+            environment(reading);
+            reading.external_temperature_x10 = Some(50);
+        });
+
+        let error = hive.execute_command(HiveCommand::AuthorizeHarvest).unwrap_err();
+        assert!(error.contains("too cold outside"), "{}", error);
+        assert_eq!(
+            hive.get_status().interlock,
+            Some(InterlockTrip::TooColdOutside { external_temperature_x10: 50, min_external_temperature_x10: 100 })
+        );
+    }
+
+    #[test]
+    fn readings_go_stale_when_every_sensor_stops() {
+        let mut hive = controller(interlocked_policy());
+        let last = make_ready(&mut hive, MIDNIGHT, environment);
+        assert_eq!(hive.get_status().interlock, None);
+
+        // Nothing reports any more, only the clock moves on
+        hive.tick(last + 601);
+
+        assert_eq!(
+            hive.get_status().interlock,
+            Some(InterlockTrip::StaleSensorData { sensor: EnvironmentalSensor::Temperature, age_s: Some(601) })
+        );
+        assert!(hive.execute_command(HiveCommand::AuthorizeHarvest).is_err());
+    }
+
+    #[test]
+    fn stale_readings_close_a_drain_without_readings() {
+        let mut policy = interlocked_policy();
+        policy.max_drain_time_s = 1800;
+        let mut hive = controller(policy);
+        let last = make_ready(&mut hive, MIDNIGHT, environment);
+        hive.execute_command(HiveCommand::AuthorizeHarvest).unwrap();
+        let mut next = reading(6000, last + 30);
+        environment(&mut next);
+        hive.update(next);
+        assert_eq!(hive.state(), HiveState::Draining);
+
+        hive.tick(last + 30 + 600);
+        assert_eq!(hive.state(), HiveState::Draining);
+        hive.tick(last + 30 + 601);
+
+        assert_eq!(hive.state(), HiveState::Verifying);
+        assert!(matches!(
+            hive.harvest.as_ref().and_then(|harvest| harvest.interrupted_by),
+            Some(InterlockTrip::StaleSensorData { .. })
+        ));
+        assert_eq!(hive.honey_cell_displacer.commands.last(), Some(&HoneyCellDisplacerCommand::SlideUp));
+    }
+
+    /// Drains until the humidity trips the interlock `drained_s` after the start, returns when the drain was cut short
+    fn interrupted_drain(hive: &mut HiveController<MockDisplacer>, drained_s: u64) -> u64 {
+        let last = make_ready(hive, MIDNIGHT, environment);
+        hive.execute_command(HiveCommand::AuthorizeHarvest).unwrap();
+
+        let mut next = reading(6000, last + 30);
+        environment(&mut next);
+        hive.update(next);
+        assert_eq!(hive.state(), HiveState::Draining);
+
+        let mut humid = reading(6000, last + 30 + drained_s);
+        environment(&mut humid);
+        humid.humidity_x10 = Some(900);
+        hive.update(humid);
+        assert_eq!(hive.state(), HiveState::Verifying);
+        last + 30 + drained_s
+    }
+
+    #[test]
+    fn interrupted_drain_has_to_yield_its_share() {
+        let mut hive = controller(interlocked_policy());
+        // Half of max_drain_time_s, half of min_harvest_drop_g
+        let closed_at = interrupted_drain(&mut hive, 300);
+
+        hive.update(reading(5600, closed_at + 30));
+        assert_eq!(hive.state(), HiveState::Verifying);
+
+        hive.update(reading(5600, closed_at + 120));
+        assert_eq!(hive.state(), HiveState::Fault);
+        assert_eq!(hive.get_status().fault_reason, Some(FaultReason::WeightDidNotDrop { drop_g: 400, min_drop_g: 500 }));
+        assert_eq!(hive.honey_cell_displacer.commands.last(), Some(&HoneyCellDisplacerCommand::Stop));
+    }
+
+    #[test]
+    fn interrupted_drain_is_reported() {
+        let mut hive = controller(interlocked_policy());
+        let closed_at = interrupted_drain(&mut hive, 300);
+
+        hive.update(reading(5500, closed_at + 30));
+
+        assert_eq!(hive.state(), HiveState::Monitoring);
+        let report = hive.harvest_history().back().unwrap();
+        assert_eq!(report.yield_g, 500);
+        assert!(matches!(report.interrupted_by, Some(InterlockTrip::TooHumid { humidity_x10: 900, .. })));
+    }
+
+    #[test]
+    fn verification_timeout_stops_the_displacer() {
+        let mut hive = controller(quick_policy());
+        let last = make_ready(&mut hive, MIDNIGHT, |_| {});
+        hive.execute_command(HiveCommand::AuthorizeHarvest).unwrap();
+        hive.update(reading(6000, last + 30));
+        hive.update(reading(3000, last + 630));
+        assert_eq!(hive.state(), HiveState::Verifying);
+
+        // The top limit switch never confirms
+        hive.honey_cell_displacer.cells_closed = false;
+        hive.tick(last + 750);
+
+        assert_eq!(hive.state(), HiveState::Fault);
+        assert_eq!(hive.get_status().fault_reason, Some(FaultReason::CellsNotClosed));
+        assert_eq!(hive.honey_cell_displacer.commands.last(), Some(&HoneyCellDisplacerCommand::Stop));
+    }
+
+    #[test]
+    fn tick_does_nothing_outside_a_harvest() {
+        let mut hive = controller(quick_policy());
+        hive.tick(MIDNIGHT);
+        assert_eq!(hive.state(), HiveState::Monitoring);
+
+        let last = make_ready(&mut hive, MIDNIGHT, |_| {});
+        hive.tick(last + 24 * HOUR);
+        assert_eq!(hive.state(), HiveState::Ready);
+        assert!(hive.honey_cell_displacer.commands.is_empty());
+    }
+
+    /// Authorizes the harvest of a Ready hive and drains it with one reading, returns when draining started
+    fn draining(hive: &mut HiveController<MockDisplacer>) -> u64 {
+        let last = make_ready(hive, MIDNIGHT, |_| {});
+        hive.execute_command(HiveCommand::AuthorizeHarvest).unwrap();
+        hive.update(reading(6000, last + 30));
+        assert_eq!(hive.state(), HiveState::Draining);
+        last + 30
+    }
+
+    #[test]
+    fn tick_closes_a_drain_that_timed_out() {
+        let mut hive = controller(quick_policy());
+        let started_at = draining(&mut hive);
+
+        hive.tick(started_at + 599);
+        assert_eq!(hive.state(), HiveState::Draining);
+
+        hive.tick(started_at + 600);
+        assert_eq!(hive.state(), HiveState::Verifying);
+        assert_eq!(hive.honey_cell_displacer.commands.last(), Some(&HoneyCellDisplacerCommand::SlideUp));
+    }
+
+    #[test]
+    fn tick_ignores_times_a_reading_already_covered() {
+        let mut hive = controller(quick_policy());
+        let started_at = draining(&mut hive);
+        hive.update(reading(5000, started_at + 590));
+
+        // The ticker's clock lags behind the readings
+        hive.tick(started_at + 590);
+        assert_eq!(hive.state(), HiveState::Draining);
+    }
+
+    #[test]
+    fn tick_verifies_the_harvest_and_reports_it() {
+        let mut hive = controller(quick_policy());
+        let started_at = draining(&mut hive);
+        hive.update(reading(4000, started_at + 300));
+
+        hive.tick(started_at + 600);
+        hive.tick(started_at + 601);
+
+        assert_eq!(hive.state(), HiveState::Monitoring);
+        let events = hive.take_events();
+        assert!(matches!(events.as_slice(), [HiveEvent::HarvestReport(report)] if report.yield_g == 2000));
+        assert!(hive.take_events().is_empty());
+    }
+
+    #[test]
+    fn anomaly_thresholds_are_updated_with_the_policy() {
+        let mut hive = controller(HarvestPolicyConfigs::default());
+
+        let mut policy = HarvestPolicyConfigs::default();
+        policy.anomaly.swarm_drop_g = 1500;
+        hive.execute_command(HiveCommand::UpdatePolicy { policy: Box::new(policy.clone()) }).unwrap();
+        assert_eq!(hive.policy().anomaly.swarm_drop_g, 1500);
+
+        // Every swarm would be reported as a theft
+        policy.anomaly.theft_drop_g = 1500;
+        assert!(hive.execute_command(HiveCommand::UpdatePolicy { policy: Box::new(policy) }).is_err());
+        assert_eq!(hive.policy().anomaly.theft_drop_g, AnomalyDetectionConfigs::default().theft_drop_g);
+    }
+
+    #[test]
+    fn colony_health_thresholds_are_validated() {
+        let mut hive = controller(HarvestPolicyConfigs::default());
+
+        let mut policy = HarvestPolicyConfigs::default();
+        policy.colony_health.brood_min_temperature_x10 = 340;
+        hive.execute_command(HiveCommand::UpdatePolicy { policy: Box::new(policy.clone()) }).unwrap();
+        assert_eq!(hive.policy().colony_health.brood_min_temperature_x10, 340);
+
+        policy.colony_health.brood_max_temperature_x10 = 340;
+        assert!(hive.execute_command(HiveCommand::UpdatePolicy { policy: Box::new(policy) }).is_err());
+    }
+
+    #[test]
+    fn telemetry_configs_are_validated() {
+        let mut hive = controller(HarvestPolicyConfigs::default());
+
+        let mut policy = HarvestPolicyConfigs::default();
+        policy.telemetry.interval_s = 60;
+        hive.execute_command(HiveCommand::UpdatePolicy { policy: Box::new(policy.clone()) }).unwrap();
+        assert_eq!(hive.policy().telemetry.interval_s, 60);
+
+        policy.telemetry.interval_s = 0;
+        assert!(hive.execute_command(HiveCommand::UpdatePolicy { policy: Box::new(policy) }).is_err());
+    }
+
+    #[test]
+    fn harvest_drop_bypasses_the_filters() {
+        let mut policy = quick_policy();
+        policy.filters.ema_alpha_pct = 10;
+        policy.filters.max_rate_g_per_s = Some(1);
+        let mut hive = controller(policy);
+        let last = make_ready(&mut hive, MIDNIGHT, |_| {});
+        hive.execute_command(HiveCommand::AuthorizeHarvest).unwrap();
+        hive.update(reading(6000, last + 30));
+        assert_eq!(hive.state(), HiveState::Draining);
+
+        hive.update(reading(4500, last + 630));
+        hive.update(reading(4500, last + 660));
+
+        assert_eq!(hive.state(), HiveState::Monitoring);
+        assert_eq!(hive.harvest_history().back().unwrap().yield_g, 1500);
+    }
+
+    fn calibration_readings(hive: &mut HiveController<MockDisplacer>, drift_g_per_x10: u32) {
+        // The temperature climbs by 1°C every 5 minutes on a constant load
+        for i in 0..12u32 {
+            let mut reading = reading(3000 + drift_g_per_x10 * i * 10, MIDNIGHT + i as u64 * 300);
+            reading.external_temperature_x10 = Some(150 + i as i16 * 10);
+            hive.update(reading);
+        }
+    }
+
+    #[test]
+    fn calibration_sets_the_compensation() {
+        let mut policy = HarvestPolicyConfigs::default();
+        policy.compensation.max_temperature_age_s = 900;
+        let mut hive = controller(policy);
+        calibration_readings(&mut hive, 2);
+
+        let response = hive.execute_command(HiveCommand::CalibrateTemperatureCompensation { start_s: MIDNIGHT, end_s: MIDNIGHT + 3600 });
+
+        assert!(matches!(response, Ok(CommandResponse::CompensationCalibrated(_))));
+        assert_eq!(hive.policy().compensation.coefficient_g_per_c_x10, 200);
+        assert_eq!(hive.policy().compensation.max_temperature_age_s, 900);
+    }
+
+    #[test]
+    fn calibration_out_of_the_policy_bounds_is_rejected() {
+        let mut hive = controller(HarvestPolicyConfigs::default());
+        // 200 g per 0.1°C is a coefficient of 20000, over the 10000 a policy update accepts
+        calibration_readings(&mut hive, 200);
+
+        let error = hive
+            .execute_command(HiveCommand::CalibrateTemperatureCompensation { start_s: MIDNIGHT, end_s: MIDNIGHT + 3600 })
+            .unwrap_err();
+
+        assert!(error.contains("compensation coefficient"), "{}", error);
+        assert_eq!(hive.policy().compensation, TemperatureCompensationConfigs::default());
+    }
+
+    #[test]
+    fn stale_external_temperature_is_not_compensated() {
+        let mut policy = quick_policy();
+        policy.compensation =
+            TemperatureCompensationConfigs { coefficient_g_per_c_x10: 200, reference_temperature_x10: 200, max_temperature_age_s: 600 };
+        let mut hive = controller(policy);
+
+        let mut warm = reading(5100, MIDNIGHT);
+        warm.external_temperature_x10 = Some(250);
+        hive.update(warm);
+        assert_eq!(hive.get_status().last_weight_g, Some(5000));
+
+        hive.update(reading(5100, MIDNIGHT + 600));
+        assert_eq!(hive.get_status().last_weight_g, Some(5000));
+        hive.update(reading(5100, MIDNIGHT + 601));
+        assert_eq!(hive.get_status().last_weight_g, Some(5100));
+        assert_eq!(hive.last_compensated_weight_g(), Some(5100));
+    }
+
+    #[test]
+    fn compensation_does_not_depend_on_the_interlock_max_age() {
+        let mut policy = quick_policy();
+        policy.compensation = TemperatureCompensationConfigs { coefficient_g_per_c_x10: 200, reference_temperature_x10: 200, ..Default::default() };
+        policy.interlocks.max_sensor_age_s = None;
+        let mut hive = controller(policy);
+
+        let mut warm = reading(5100, MIDNIGHT);
+        warm.external_temperature_x10 = Some(250);
+        hive.update(warm);
+        assert_eq!(hive.last_compensated_weight_g(), Some(5000));
+
+        hive.update(reading(5100, MIDNIGHT + 1801));
+        assert_eq!(hive.last_compensated_weight_g(), Some(5100));
+    }
+
+    #[test]
+    fn readings_older_than_the_last_one_are_dropped() {
+        let mut hive = controller(quick_policy());
+        hive.update(reading(6000, MIDNIGHT));
+        hive.update(reading(6000, MIDNIGHT + 30));
+        assert_eq!(hive.state(), HiveState::Candidate);
+
+        // A batch delayed by the gateway, older than the start of the stability window
+        for delayed in [reading(6000, MIDNIGHT + 20), reading(3000, MIDNIGHT + 10)] {
+            hive.update(delayed);
+        }
+
+        assert_eq!(hive.last_reading_at(), Some(MIDNIGHT + 30));
+        assert_eq!(hive.get_status().last_raw_weight_g, Some(6000));
+        hive.update(reading(6000, MIDNIGHT + 90));
+        assert_eq!(hive.state(), HiveState::Ready);
+    }
+
+    #[test]
+    fn process_command_returns_the_json_of_the_response() {
+        let mut hive = controller(HarvestPolicyConfigs::default());
+
+        assert_eq!(hive.process_command(HiveCommand::ManualSlideUp), Ok(None));
+        let status = hive.process_command(HiveCommand::GetStatus).unwrap().unwrap();
+        assert!(status.contains("\"state\":\"Monitoring\""), "{}", status);
+        assert!(hive.process_command(HiveCommand::ResetFault).is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use serde::Serialize;
+use log::{error, info};
+
+use crate::controller::controller::{CommandResponse, HiveCommand, HiveController};
+use crate::state::actuators::HoneyCellDisplacer;
+use crate::state::hive::HiveState;
+
+/// Why a command produced an error instead of a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// The payload is not a `HiveCommand`
+    Invalid(String),
+    /// The controller rejected the command
+    Failed(String),
+}
+
+impl CommandError {
+    /// `{"status":"error","message":...}`
+    pub fn to_json(&self) -> String {
+        let (CommandError::Invalid(message) | CommandError::Failed(message)) = self;
+        serde_json::json!({ "status": "error", "message": message }).to_string()
+    }
+}
+
+/// The reply to a command, sent back over the transport the command came from
+pub type CommandReply = Result<CommandResponse, CommandError>;
+
+/// The JSON every transport sends for a reply
+pub fn reply_to_json(reply: &CommandReply) -> String {
+    match reply {
+        Ok(response) => serde_json::to_string(response).unwrap_or_else(|e| CommandError::Failed(e.to_string()).to_json()),
+        Err(e) => e.to_json(),
+    }
+}
+
+/// Where a transport sends the replies. Each transport decides what is worth sending,
+/// e.g. MQTT does not publish acknowledgements while BLE does
+pub trait ReplySink {
+    fn send(&mut self, reply: &CommandReply);
+}
+
+impl<F: FnMut(&CommandReply)> ReplySink for F {
+    fn send(&mut self, reply: &CommandReply) {
+        self(reply)
+    }
+}
+
+/// Collects the replies, for transports that answer once the command is done
+impl ReplySink for Vec<CommandReply> {
+    fn send(&mut self, reply: &CommandReply) {
+        self.push(reply.clone());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StateTransition {
+    pub previous_state: HiveState,
+    pub new_state: HiveState,
+}
+
+/// Side effects of a command that the transports may want to announce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CommandEvent {
+    /// e.g. `emergency_stop` puts the hive in Fault
+    StateChanged(StateTransition),
+    /// `update_policy` or a temperature compensation calibration changed the policy
+    PolicyChanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct DispatchOutcome {
+    pub reply: CommandReply,
+    pub events: Vec<CommandEvent>,
+}
+
+/// Parses a raw payload as a `HiveCommand` and dispatches it, whatever transport it came from
+pub fn dispatch<H: HoneyCellDisplacer>(controller: &mut HiveController<H>, payload: &str, sink: &mut dyn ReplySink) -> DispatchOutcome {
+    match serde_json::from_str::<HiveCommand>(payload) {
+        Ok(command) => dispatch_command(controller, command, sink),
+        Err(e) => {
+            error!("Failed to parse command: {}", e);
+
+            let reply = Err(CommandError::Invalid(e.to_string()));
+            sink.send(&reply);
+            DispatchOutcome {
+                reply,
+                events: Vec::new(),
+            }
+        }
+    }
+}
+
+/// Executes a command, sends its reply to the sink and returns it with the side effects of the command
+pub fn dispatch_command<H: HoneyCellDisplacer>(
+    controller: &mut HiveController<H>,
+    command: HiveCommand,
+    sink: &mut dyn ReplySink,
+) -> DispatchOutcome {
+    info!("Received command: {:?}", command);
+    let previous_state = controller.state();
+
+    let reply = controller.execute_command(command).map_err(|e| {
+        error!("Command failed: {}", e);
+        CommandError::Failed(e)
+    });
+    sink.send(&reply);
+
+    let mut events = Vec::new();
+    let new_state = controller.state();
+    if new_state != previous_state {
+        info!("State transition: {:?} -> {:?}", previous_state, new_state);
+        events.push(CommandEvent::StateChanged(StateTransition { previous_state, new_state }));
+    }
+    if matches!(reply, Ok(CommandResponse::PolicyUpdated(_) | CommandResponse::CompensationCalibrated(_))) {
+        events.push(CommandEvent::PolicyChanged);
+    }
+
+    DispatchOutcome { reply, events }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::actuators::MockDisplacer;
+    use crate::state::policy::harvest::HarvestPolicyConfigs;
+
+    fn controller() -> HiveController<MockDisplacer> {
+        HiveController::new(HarvestPolicyConfigs::default(), MockDisplacer::default())
+    }
+
+    #[test]
+    fn unparsable_payloads_are_invalid() {
+        let mut controller = controller();
+
+        for payload in ["", "not json", r#"{"command":"launch"}"#, r#"{"command":"update_policy"}"#] {
+            let outcome = dispatch(&mut controller, payload, &mut Vec::new());
+            assert!(matches!(outcome.reply, Err(CommandError::Invalid(_))), "{}", payload);
+            assert!(outcome.events.is_empty());
+        }
+        assert_eq!(controller.state(), HiveState::Monitoring);
+    }
+
+    #[test]
+    fn a_parsed_command_is_executed() {
+        let mut controller = controller();
+
+        let outcome = dispatch(&mut controller, r#"{"command":"manual_slide_up"}"#, &mut Vec::new());
+        assert!(matches!(outcome.reply, Ok(CommandResponse::Ack(_))));
+        assert!(outcome.events.is_empty());
+
+        let outcome = dispatch(&mut controller, r#"{"command":"get_status"}"#, &mut Vec::new());
+        assert!(matches!(outcome.reply, Ok(CommandResponse::Status(status)) if status.state == HiveState::Monitoring));
+    }
+
+    #[test]
+    fn rejected_commands_fail_without_events() {
+        let mut controller = controller();
+
+        let outcome = dispatch_command(&mut controller, HiveCommand::AuthorizeHarvest, &mut Vec::new());
+        assert_eq!(outcome.reply.unwrap_err(), CommandError::Failed("Cannot authorize harvest in state Monitoring".to_string()));
+        assert!(outcome.events.is_empty());
+    }
+
+    #[test]
+    fn state_changes_are_reported() {
+        let mut controller = controller();
+
+        let outcome = dispatch_command(&mut controller, HiveCommand::EmergencyStop, &mut Vec::new());
+        assert_eq!(
+            outcome.events,
+            vec![CommandEvent::StateChanged(StateTransition {
+                previous_state: HiveState::Monitoring,
+                new_state: HiveState::Fault,
+            })]
+        );
+
+        let outcome = dispatch_command(&mut controller, HiveCommand::ResetFault, &mut Vec::new());
+        assert_eq!(
+            outcome.events,
+            vec![CommandEvent::StateChanged(StateTransition {
+                previous_state: HiveState::Fault,
+                new_state: HiveState::Monitoring,
+            })]
+        );
+    }
+
+    #[test]
+    fn policy_changes_are_reported() {
+        let mut controller = controller();
+
+        let policy = HarvestPolicyConfigs {
+            min_honey_weight_g: 4000,
+            ..Default::default()
+        };
+        let outcome = dispatch_command(&mut controller, HiveCommand::UpdatePolicy { policy: Box::new(policy) }, &mut Vec::new());
+        assert!(matches!(outcome.reply, Ok(CommandResponse::PolicyUpdated(_))));
+        assert_eq!(outcome.events, vec![CommandEvent::PolicyChanged]);
+        assert_eq!(controller.policy().min_honey_weight_g, 4000);
+
+        // An invalid policy changes nothing
+        let policy = HarvestPolicyConfigs {
+            stability_window_s: 0,
+            ..Default::default()
+        };
+        let outcome = dispatch_command(&mut controller, HiveCommand::UpdatePolicy { policy: Box::new(policy) }, &mut Vec::new());
+        assert_eq!(outcome.reply.unwrap_err(), CommandError::Failed("Invalid policy configuration".to_string()));
+        assert!(outcome.events.is_empty());
+        assert_eq!(controller.policy().min_honey_weight_g, 4000);
+    }
+
+    #[test]
+    fn every_reply_goes_to_the_sink() {
+        let mut controller = controller();
+        let mut replies: Vec<CommandReply> = Vec::new();
+
+        dispatch(&mut controller, "not json", &mut replies);
+        dispatch(&mut controller, r#"{"command":"reset_fault"}"#, &mut replies);
+        dispatch(&mut controller, r#"{"command":"manual_slide_up"}"#, &mut replies);
+
+        assert_eq!(replies.len(), 3);
+        assert!(matches!(replies[0], Err(CommandError::Invalid(_))));
+        assert!(matches!(replies[1], Err(CommandError::Failed(_))));
+        assert!(matches!(replies[2], Ok(CommandResponse::Ack(_))));
+    }
+
+    #[test]
+    fn replies_as_json() {
+        let json = |reply: &CommandReply| serde_json::from_str::<serde_json::Value>(&reply_to_json(reply)).unwrap();
+
+        assert_eq!(json(&Ok(CommandResponse::ack())), serde_json::json!({ "status": "success" }));
+        assert_eq!(
+            json(&Err(CommandError::Failed("Not in fault state".to_string()))),
+            serde_json::json!({ "status": "error", "message": "Not in fault state" })
+        );
+        assert_eq!(
+            json(&Err(CommandError::Invalid("expected value".to_string()))),
+            serde_json::json!({ "status": "error", "message": "expected value" })
+        );
+    }
+}
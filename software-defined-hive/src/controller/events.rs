@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+use crate::state::policy::interlocks::InterlockTrip;
+
+/// Events emitted by the controller as a side effect of driving the FSM. They are collected with `HiveController::take_events`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HiveEvent {
+    HarvestReport(HarvestReport),
+}
+
+/// A summary of a completed harvest (Draining -> Closing -> Verifying -> Monitoring)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HarvestReport {
+    /// Timestamp when the cells were displaced and draining started
+    pub started_at: u64,
+    /// Timestamp when the harvest was verified as complete
+    pub completed_at: u64,
+    /// Net hive weight just before draining (grams)
+    pub weight_before_g: u32,
+    /// Net hive weight after the cells were closed (grams)
+    pub weight_after_g: u32,
+    /// Honey harvested i.e. weight_before_g - weight_after_g (grams)
+    pub yield_g: u32,
+    /// Time from displacing the cells to starting to close them (seconds)
+    pub drain_duration_s: u64,
+    /// Average honey flow rate over the drain (grams per minute)
+    pub average_flow_rate_g_per_min: u32,
+    /// Environmental conditions while draining
+    pub conditions: HarvestConditions,
+    /// Set when an interlock cut the drain short
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interrupted_by: Option<InterlockTrip>,
+}
+
+/// Averages of the environmental readings received while draining. `None` when the sensor did not report
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HarvestConditions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_temperature_x10: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_external_temperature_x10: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average_humidity_x10: Option<u16>,
+}
@@ -1 +1,4 @@
-pub mod controller;
\ No newline at end of file
+pub mod controller;
+pub mod events;
+pub mod dispatcher;
+pub mod console;
\ No newline at end of file
@@ -1,5 +1,7 @@
 pub mod state;
 pub mod controller;
 pub mod utils;
-
+pub mod analytics;
+pub mod provisioning;
+pub mod api;
 
@@ -0,0 +1,159 @@
+/// Header of a DNS message (RFC 1035 4.1.1)
+const HEADER_LEN: usize = 12;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const OPCODE_MASK: u16 = 0x7800;
+
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// Short, so that the clients forget the portal soon after the hive leaves provisioning
+const ANSWER_TTL_S: u32 = 10;
+
+/// Answers every A query with `address` - the captive portal resolves every name to itself so
+/// that phones and laptops open the configuration page when they join the access point.
+///
+/// Other query types get an empty answer, which makes the clients fall back to A. `None` for
+/// anything that is not a standard query with a single question, which is dropped
+pub fn captive_dns_response(query: &[u8], address: [u8; 4]) -> Option<Vec<u8>> {
+    if query.len() < HEADER_LEN {
+        return None;
+    }
+
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    if flags & FLAG_RESPONSE != 0 || flags & OPCODE_MASK != 0 || questions != 1 {
+        return None;
+    }
+
+    // The name is a sequence of length-prefixed labels ending with an empty one
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        // Compression pointers are not expected in a question
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+
+    let question = query.get(HEADER_LEN..end + 4)?;
+    let query_type = u16::from_be_bytes([query[end], query[end + 1]]);
+    let query_class = u16::from_be_bytes([query[end + 2], query[end + 3]]);
+    let answer = query_class == CLASS_IN && (query_type == TYPE_A || query_type == TYPE_ANY);
+
+    let mut response = Vec::with_capacity(HEADER_LEN + question.len() + 16);
+    response.extend_from_slice(&query[0..2]);
+    response.extend_from_slice(&(FLAG_RESPONSE | FLAG_AUTHORITATIVE | (flags & FLAG_RECURSION_DESIRED)).to_be_bytes());
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&u16::from(answer).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+
+    if answer {
+        // Pointer to the name of the question, right after the header
+        response.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&ANSWER_TTL_S.to_be_bytes());
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&address);
+    }
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PORTAL: [u8; 4] = [192, 168, 71, 1];
+
+    /// A standard query with recursion desired for `name`
+    fn query(name: &str, query_type: u16) -> Vec<u8> {
+        let mut query = vec![0xAB, 0xCD, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&query_type.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn answers_a_queries_with_the_portal() {
+        let query = query("connectivitycheck.gstatic.com", TYPE_A);
+        let response = captive_dns_response(&query, PORTAL).unwrap();
+
+        // Same ID, a response with recursion desired copied, one question and one answer
+        assert_eq!(&response[0..2], &[0xAB, 0xCD]);
+        assert_eq!(u16::from_be_bytes([response[2], response[3]]), FLAG_RESPONSE | FLAG_AUTHORITATIVE | FLAG_RECURSION_DESIRED);
+        assert_eq!(&response[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&response[HEADER_LEN..query.len()], &query[HEADER_LEN..]);
+
+        let answer = &response[query.len()..];
+        assert_eq!(&answer[0..2], &[0xC0, HEADER_LEN as u8]);
+        assert_eq!(&answer[2..6], &[0, 1, 0, 1]);
+        assert_eq!(&answer[6..10], &ANSWER_TTL_S.to_be_bytes());
+        assert_eq!(&answer[10..], &[0, 4, 192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn other_types_get_an_empty_answer() {
+        const TYPE_AAAA: u16 = 28;
+        let query = query("example.com", TYPE_AAAA);
+        let response = captive_dns_response(&query, PORTAL).unwrap();
+
+        assert_eq!(&response[6..8], &[0, 0]);
+        assert_eq!(response.len(), query.len());
+    }
+
+    #[test]
+    fn drops_malformed_queries() {
+        let valid = query("example.com", TYPE_A);
+
+        // Shorter than a header
+        assert!(captive_dns_response(&valid[..HEADER_LEN - 1], PORTAL).is_none());
+        // The name runs past the end, or the type and class are missing
+        assert!(captive_dns_response(&valid[..HEADER_LEN + 5], PORTAL).is_none());
+        assert!(captive_dns_response(&valid[..valid.len() - 1], PORTAL).is_none());
+
+        // A label longer than what is left
+        let mut truncated = valid.clone();
+        truncated[HEADER_LEN] = 60;
+        assert!(captive_dns_response(&truncated, PORTAL).is_none());
+
+        // A compression pointer in the question
+        let mut compressed = valid[..HEADER_LEN].to_vec();
+        compressed.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+        assert!(captive_dns_response(&compressed, PORTAL).is_none());
+    }
+
+    #[test]
+    fn drops_anything_but_a_standard_query_with_one_question() {
+        let valid = query("example.com", TYPE_A);
+
+        let mut response = valid.clone();
+        response[2] |= 0x80;
+        assert!(captive_dns_response(&response, PORTAL).is_none());
+
+        // Inverse query (opcode 1)
+        let mut inverse = valid.clone();
+        inverse[2] |= 0x08;
+        assert!(captive_dns_response(&inverse, PORTAL).is_none());
+
+        for questions in [0u8, 2] {
+            let mut query = valid.clone();
+            query[5] = questions;
+            assert!(captive_dns_response(&query, PORTAL).is_none());
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use serde::{Deserialize, Serialize};
+
+/// Topic level that addresses every hive, neither the apiary nor the hive id can take it
+pub const BROADCAST_LEVEL: &str = "broadcast";
+
+/// 802.11 limits
+pub const MAX_SSID_LEN: usize = 32;
+pub const MIN_WIFI_PASSWORD_LEN: usize = 8;
+pub const MAX_WIFI_PASSWORD_LEN: usize = 63;
+
+const MAX_BROKER_URL_LEN: usize = 128;
+const MAX_ID_LEN: usize = 32;
+const MAX_CREDENTIAL_LEN: usize = 64;
+
+const BROKER_SCHEMES: [&str; 4] = ["mqtt://", "mqtts://", "ws://", "wss://"];
+
+/// A field that failed validation and why, shown next to the field on the configuration page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// The configuration page as submitted (or the same fields as JSON), nothing is validated yet
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ProvisioningForm {
+    pub wifi_ssid: String,
+    pub wifi_password: String,
+    pub broker_url: String,
+    pub apiary: String,
+    pub hive_id: String,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+}
+
+/// What the hive stores once the form is valid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvisioningSettings {
+    pub wifi_ssid: String,
+    /// Empty for an open network
+    pub wifi_password: String,
+    pub broker_url: String,
+    pub apiary: String,
+    pub hive_id: String,
+    /// `None` keeps the credentials the firmware was built with
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+}
+
+impl ProvisioningForm {
+    /// Reads an `application/x-www-form-urlencoded` body, unknown fields are ignored
+    pub fn from_urlencoded(body: &str) -> Self {
+        let mut form = Self::default();
+
+        for (name, value) in parse_urlencoded(body) {
+            let field = match name.as_str() {
+                "wifi_ssid" => &mut form.wifi_ssid,
+                "wifi_password" => &mut form.wifi_password,
+                "broker_url" => &mut form.broker_url,
+                "apiary" => &mut form.apiary,
+                "hive_id" => &mut form.hive_id,
+                "mqtt_username" => &mut form.mqtt_username,
+                "mqtt_password" => &mut form.mqtt_password,
+                _ => continue,
+            };
+            *field = value;
+        }
+
+        form
+    }
+
+    /// Checks every field, all the errors are returned so the user can fix them at once.
+    /// The SSID and the passwords are taken as typed, the other fields are trimmed
+    pub fn validate(&self) -> Result<ProvisioningSettings, Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        let ssid_len = self.wifi_ssid.len();
+        if ssid_len == 0 || ssid_len > MAX_SSID_LEN {
+            errors.push(FieldError::new("wifi_ssid", format!("Must be 1 to {} bytes", MAX_SSID_LEN)));
+        }
+
+        let password_len = self.wifi_password.len();
+        if password_len != 0 && !(MIN_WIFI_PASSWORD_LEN..=MAX_WIFI_PASSWORD_LEN).contains(&password_len) {
+            errors.push(FieldError::new(
+                "wifi_password",
+                format!("Must be {} to {} characters, or empty for an open network", MIN_WIFI_PASSWORD_LEN, MAX_WIFI_PASSWORD_LEN),
+            ));
+        }
+
+        let broker_url = self.broker_url.trim();
+        if let Err(e) = validate_broker_url(broker_url) {
+            errors.push(FieldError::new("broker_url", e));
+        }
+
+        let apiary = self.apiary.trim();
+        if let Err(e) = validate_topic_level("apiary", apiary) {
+            errors.push(FieldError::new("apiary", e));
+        }
+
+        let hive_id = self.hive_id.trim();
+        if let Err(e) = validate_topic_level("hive_id", hive_id) {
+            errors.push(FieldError::new("hive_id", e));
+        }
+
+        let mqtt_username = self.mqtt_username.trim();
+        if mqtt_username.len() > MAX_CREDENTIAL_LEN {
+            errors.push(FieldError::new("mqtt_username", format!("Must be at most {} bytes", MAX_CREDENTIAL_LEN)));
+        }
+        if self.mqtt_password.len() > MAX_CREDENTIAL_LEN {
+            errors.push(FieldError::new("mqtt_password", format!("Must be at most {} bytes", MAX_CREDENTIAL_LEN)));
+        }
+        if mqtt_username.is_empty() && !self.mqtt_password.is_empty() {
+            errors.push(FieldError::new("mqtt_username", "A password needs a username"));
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let non_empty = |value: &str| (!value.is_empty()).then(|| value.to_string());
+        Ok(ProvisioningSettings {
+            wifi_ssid: self.wifi_ssid.clone(),
+            wifi_password: self.wifi_password.clone(),
+            broker_url: broker_url.to_string(),
+            apiary: apiary.to_string(),
+            hive_id: hive_id.to_string(),
+            mqtt_username: non_empty(mqtt_username),
+            mqtt_password: non_empty(&self.mqtt_password),
+        })
+    }
+}
+
+/// An ID has to be a single, non-wildcard topic level that cannot be mistaken for a broadcast
+pub fn validate_topic_level(name: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() || value.len() > MAX_ID_LEN || value == BROADCAST_LEVEL || value.contains(['/', '+', '#']) {
+        return Err(format!(
+            "Invalid {} \"{}\": must be a topic level of 1 to {} bytes other than \"{}\" without '/', '+' or '#'",
+            name, value, MAX_ID_LEN, BROADCAST_LEVEL
+        ));
+    }
+
+    Ok(())
+}
+
+/// `mqtt://`, `mqtts://`, `ws://` or `wss://`, a host and an optional port
+pub fn validate_broker_url(url: &str) -> Result<(), String> {
+    if url.len() > MAX_BROKER_URL_LEN || url.contains(char::is_whitespace) {
+        return Err(format!("Must be at most {} characters without spaces", MAX_BROKER_URL_LEN));
+    }
+
+    let Some(rest) = BROKER_SCHEMES.iter().find_map(|scheme| url.strip_prefix(scheme)) else {
+        return Err(format!("Must start with one of {}", BROKER_SCHEMES.join(", ")));
+    };
+
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    };
+
+    if host.is_empty() {
+        return Err("The host is missing".to_string());
+    }
+
+    if let Some(port) = port
+        && !port.parse::<u16>().is_ok_and(|port| port != 0)
+    {
+        return Err(format!("Invalid port \"{}\"", port));
+    }
+
+    Ok(())
+}
+
+/// Splits an `application/x-www-form-urlencoded` body into decoded (name, value) pairs
+pub fn parse_urlencoded(body: &str) -> Vec<(String, String)> {
+    body.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+/// `+` is a space and `%XX` a byte, malformed escapes are kept as they are
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(&[high, low]) if bytes[i] == b'%' => hex(high).zip(hex(low)),
+            _ => None,
+        };
+
+        match (bytes[i], escaped) {
+            (_, Some((high, low))) => {
+                decoded.push(high << 4 | low);
+                i += 2;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+/// The configuration page. Submitted values are kept except the passwords, which are never sent back
+pub fn render_page(form: &ProvisioningForm, errors: &[FieldError]) -> String {
+    let field = |name: &'static str, label: &str, kind: &str, value: &str, hint: &str| {
+        let error = errors
+            .iter()
+            .filter(|error| error.field == name)
+            .map(|error| format!("<p class=\"error\">{}</p>", escape_html(&error.message)))
+            .collect::<String>();
+
+        format!(
+            "<label for=\"{name}\">{label}</label><input id=\"{name}\" name=\"{name}\" type=\"{kind}\" value=\"{value}\"><small>{hint}</small>{error}",
+            value = escape_html(value),
+        )
+    };
+
+    let fields = [
+        field("wifi_ssid", "Wi-Fi network", "text", &form.wifi_ssid, ""),
+        field("wifi_password", "Wi-Fi password", "password", "", "Empty for an open network"),
+        field("broker_url", "Broker URL", "text", &form.broker_url, "mqtt://host:1883 or mqtts://host:8883"),
+        field("apiary", "Apiary", "text", &form.apiary, ""),
+        field("hive_id", "Hive ID", "text", &form.hive_id, "Topics are smart-hive/{apiary}/{hive id}/..."),
+        field("mqtt_username", "MQTT username", "text", &form.mqtt_username, "Empty keeps the credentials the firmware was built with"),
+        field("mqtt_password", "MQTT password", "password", "", ""),
+    ]
+    .concat();
+
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>Smart Hive setup</title><style>body{{font-family:sans-serif;max-width:30em;margin:auto;padding:1em}}\
+         label,input,small{{display:block;width:100%}}input{{margin:.3em 0;padding:.4em;box-sizing:border-box}}\
+         small{{color:#666;margin-bottom:1em}}.error{{color:#b00}}</style></head>\
+         <body><h1>Smart Hive setup</h1><form method=\"post\" action=\"/\">{}<button type=\"submit\">Save and restart</button></form></body></html>",
+        fields
+    )
+}
+
+/// Shown once the settings are stored, right before the hive restarts
+pub fn render_saved_page(settings: &ProvisioningSettings) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Smart Hive setup</title></head>\
+         <body><h1>Saved</h1><p>The hive restarts and joins \"{}\" as {}/{}.</p></body></html>",
+        escape_html(&settings.wifi_ssid),
+        escape_html(&settings.apiary),
+        escape_html(&settings.hive_id),
+    )
+}
+
+pub(crate) fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form() -> ProvisioningForm {
+        ProvisioningForm {
+            wifi_ssid: "Apiary".into(),
+            wifi_password: "hunter22".into(),
+            broker_url: "mqtt://broker.local:1883".into(),
+            apiary: "kiambu".into(),
+            hive_id: "hive-01".into(),
+            ..Default::default()
+        }
+    }
+
+    fn invalid_fields(form: &ProvisioningForm) -> Vec<&'static str> {
+        form.validate().err().unwrap_or_default().iter().map(|error| error.field).collect()
+    }
+
+    #[test]
+    fn decodes_the_form() {
+        let form = ProvisioningForm::from_urlencoded(
+            "wifi_ssid=My+Apiary%21&wifi_password=p%40ss%26word&broker_url=mqtts%3A%2F%2Fbroker%3A8883&apiary=kiambu&hive_id=h1&unknown=1",
+        );
+
+        assert_eq!(form.wifi_ssid, "My Apiary!");
+        assert_eq!(form.wifi_password, "p@ss&word");
+        assert_eq!(form.broker_url, "mqtts://broker:8883");
+        assert_eq!(form.hive_id, "h1");
+        assert_eq!(form.mqtt_username, "");
+    }
+
+    #[test]
+    fn decoding_edge_cases() {
+        let pairs = parse_urlencoded("a=%41%4a%4A&b=%&c=%4&d=%zz&e&=f&&g=%2B+%20&h=%C3%A9&i=%FF");
+
+        assert_eq!(
+            pairs,
+            [
+                ("a", "AJJ"),
+                // Malformed escapes are kept as they are
+                ("b", "%"),
+                ("c", "%4"),
+                ("d", "%zz"),
+                // A name without a value, a value without a name
+                ("e", ""),
+                ("", "f"),
+                ("g", "+  "),
+                // UTF-8 over two escapes, an invalid byte is replaced
+                ("h", "é"),
+                ("i", "\u{FFFD}"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert!(parse_urlencoded("").is_empty());
+    }
+
+    #[test]
+    fn a_valid_form() {
+        let settings = ProvisioningForm { apiary: " kiambu ".into(), mqtt_username: "hive".into(), mqtt_password: "secret".into(), ..form() }
+            .validate()
+            .unwrap();
+
+        assert_eq!(settings.apiary, "kiambu");
+        assert_eq!(settings.mqtt_username.as_deref(), Some("hive"));
+        assert_eq!(settings.mqtt_password.as_deref(), Some("secret"));
+
+        // Empty credentials keep the ones the firmware was built with, an empty password is an open network
+        let settings = ProvisioningForm { wifi_password: String::new(), ..form() }.validate().unwrap();
+        assert_eq!(settings.mqtt_username, None);
+        assert_eq!(settings.wifi_password, "");
+    }
+
+    #[test]
+    fn wifi_limits() {
+        assert_eq!(invalid_fields(&ProvisioningForm { wifi_ssid: String::new(), ..form() }), ["wifi_ssid"]);
+        assert_eq!(invalid_fields(&ProvisioningForm { wifi_ssid: "x".repeat(MAX_SSID_LEN + 1), ..form() }), ["wifi_ssid"]);
+        assert!(ProvisioningForm { wifi_ssid: "x".repeat(MAX_SSID_LEN), ..form() }.validate().is_ok());
+
+        assert_eq!(invalid_fields(&ProvisioningForm { wifi_password: "1234567".into(), ..form() }), ["wifi_password"]);
+        assert_eq!(invalid_fields(&ProvisioningForm { wifi_password: "x".repeat(MAX_WIFI_PASSWORD_LEN + 1), ..form() }), ["wifi_password"]);
+        assert!(ProvisioningForm { wifi_password: "x".repeat(MAX_WIFI_PASSWORD_LEN), ..form() }.validate().is_ok());
+    }
+
+    #[test]
+    fn topic_levels() {
+        for id in ["", "broadcast", "a/b", "a+", "#", &"x".repeat(MAX_ID_LEN + 1)] {
+            assert_eq!(invalid_fields(&ProvisioningForm { hive_id: id.into(), ..form() }), ["hive_id"], "{:?}", id);
+        }
+        assert_eq!(invalid_fields(&ProvisioningForm { apiary: "north/east".into(), ..form() }), ["apiary"]);
+    }
+
+    #[test]
+    fn credentials() {
+        assert_eq!(invalid_fields(&ProvisioningForm { mqtt_password: "secret".into(), ..form() }), ["mqtt_username"]);
+        assert_eq!(invalid_fields(&ProvisioningForm { mqtt_username: "x".repeat(MAX_CREDENTIAL_LEN + 1), ..form() }), ["mqtt_username"]);
+    }
+
+    #[test]
+    fn every_error_at_once() {
+        assert_eq!(
+            invalid_fields(&ProvisioningForm::default()),
+            ["wifi_ssid", "broker_url", "apiary", "hive_id"]
+        );
+    }
+
+    #[test]
+    fn broker_urls() {
+        for url in ["mqtt://broker", "mqtts://broker.local:8883", "ws://10.0.0.2:8080/mqtt", "wss://broker:443/", "mqtt://[::1]:1883"] {
+            assert_eq!(validate_broker_url(url), Ok(()), "{}", url);
+        }
+
+        for url in [
+            "",
+            "broker:1883",
+            "http://broker",
+            "mqtt://",
+            "mqtt://:1883",
+            "mqtt://broker:",
+            "mqtt://broker:0",
+            "mqtt://broker:65536",
+            "mqtt://broker:port",
+            "mqtt://bro ker",
+            &format!("mqtt://{}", "x".repeat(MAX_BROKER_URL_LEN)),
+        ] {
+            assert!(validate_broker_url(url).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn the_page_escapes_the_values_and_never_echoes_passwords() {
+        let submitted = ProvisioningForm { wifi_ssid: "<script>\"".into(), wifi_password: "hunter22".into(), ..form() };
+        let page = render_page(&submitted, &[FieldError::new("wifi_ssid", "Must be <32> bytes")]);
+
+        assert!(page.contains("value=\"&lt;script&gt;&quot;\""));
+        assert!(page.contains("<p class=\"error\">Must be &lt;32&gt; bytes</p>"));
+        assert!(!page.contains("hunter22"));
+    }
+}
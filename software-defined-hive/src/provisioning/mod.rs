@@ -0,0 +1,2 @@
+pub mod form;
+pub mod captive_dns;
\ No newline at end of file
@@ -1,5 +1,7 @@
+use serde::{Deserialize, Serialize};
+
 /// These commands are what controls the actuators that displace the honey cells during harvesting
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HoneyCellDisplacerCommand {
     SlideDown,
     SlideUp,
@@ -9,12 +11,47 @@ pub enum HoneyCellDisplacerCommand {
 pub trait HoneyCellDisplacer {
     /// gives instruction to the actuator (honey cell displacer) to execute a command
     fn execute(&mut self, cmd: HoneyCellDisplacerCommand) -> Result<(), HoneyCellDisplacerFault>;
+
+    /// confirms that the honey cells are closed (displacers at the top limit) e.g. after a harvest
+    fn cells_closed(&mut self) -> Result<bool, HoneyCellDisplacerFault>;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum HoneyCellDisplacerFault {
     OverCurrent,
     EndStopHit,
     Timeout,
     Hardware,
 }
+
+/// Records the commands and reports the cells as closed once slid up, for the host tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MockDisplacer {
+    pub commands: Vec<HoneyCellDisplacerCommand>,
+    pub cells_closed: bool,
+    /// Returned by every command when set
+    pub fault: Option<HoneyCellDisplacerFault>,
+}
+
+#[cfg(test)]
+impl HoneyCellDisplacer for MockDisplacer {
+    fn execute(&mut self, cmd: HoneyCellDisplacerCommand) -> Result<(), HoneyCellDisplacerFault> {
+        if let Some(fault) = self.fault {
+            return Err(fault);
+        }
+
+        self.commands.push(cmd);
+        match cmd {
+            HoneyCellDisplacerCommand::SlideUp => self.cells_closed = true,
+            HoneyCellDisplacerCommand::SlideDown => self.cells_closed = false,
+            HoneyCellDisplacerCommand::Stop => {}
+        }
+        Ok(())
+    }
+
+    fn cells_closed(&mut self) -> Result<bool, HoneyCellDisplacerFault> {
+        Ok(self.cells_closed)
+    }
+}
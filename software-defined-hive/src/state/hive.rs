@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::state::actuators::HoneyCellDisplacerFault;
+
 /// The lifecycle of the hive from one harvest to another
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum HiveState {
@@ -13,3 +15,24 @@ pub enum HiveState {
     Verifying,
     Fault,
 }
+
+impl HiveState {
+    /// From the authorization until the harvest is verified, the honey cells may be displaced
+    pub fn harvesting(&self) -> bool {
+        matches!(self, HiveState::Authorized | HiveState::Actuating | HiveState::Draining | HiveState::Closing | HiveState::Verifying)
+    }
+}
+
+/// Why the hive ended up in the Fault state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum FaultReason {
+    /// The honey cell displacer failed to execute a command
+    Actuator { fault: HoneyCellDisplacerFault },
+    /// An operator sent `emergency_stop`
+    EmergencyStop,
+    /// The hive did not get lighter by at least the policy's minimum after draining
+    WeightDidNotDrop { drop_g: u32, min_drop_g: u32 },
+    /// The top limit switch did not confirm that the cells are closed
+    CellsNotClosed,
+}
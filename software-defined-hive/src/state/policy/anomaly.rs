@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// Thresholds for detecting anomalies (swarming, theft and robbing) from the weight trend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnomalyDetectionConfigs {
+    /// Window in which a weight drop is considered sudden (seconds)
+    pub sudden_drop_window_s: u64,
+
+    /// Sudden drop that signals a swarm leaving the hive (grams) - a swarm typically weighs 1-3kg
+    pub swarm_drop_g: u32,
+
+    /// Sudden drop that signals the hive being stolen or knocked over (grams)
+    pub theft_drop_g: u32,
+
+    /// Window over which a steady weight loss is tracked (seconds)
+    pub robbing_window_s: u64,
+
+    /// Steady loss over robbing_window_s that signals robbing (grams)
+    pub robbing_loss_g: u32,
+
+    /// Minimum time between two alerts of the same kind (seconds)
+    pub alert_cooldown_s: u64,
+}
+
+impl Default for AnomalyDetectionConfigs {
+    fn default() -> Self {
+        Self {
+            sudden_drop_window_s: 600,
+            swarm_drop_g: 1000,
+            theft_drop_g: 10000,
+            robbing_window_s: 3 * 3600,
+            robbing_loss_g: 800,
+            alert_cooldown_s: 3600,
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use serde::{Deserialize, Serialize};
+
+/// Thresholds for evaluating colony health from the internal (brood nest) temperature.
+///
+/// A healthy colony holds the brood nest at about 34-35°C regardless of the weather outside
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColonyHealthConfigs {
+    /// Rolling window over which the internal temperature is evaluated (seconds)
+    pub window_s: u64,
+
+    /// Minimum number of readings in the window before any warning is raised
+    pub min_samples: usize,
+
+    /// Lower bound of a healthy brood temperature (°C * 10)
+    pub brood_min_temperature_x10: i16,
+
+    /// Upper bound of a healthy brood temperature (°C * 10)
+    pub brood_max_temperature_x10: i16,
+
+    /// Largest swing (max - min) of the internal temperature over the window that is still considered stable (°C * 10)
+    pub max_temperature_range_x10: i16,
+
+    /// Smallest average gap between the internal and external temperature expected from a colony that regulates its nest (°C * 10)
+    pub min_temperature_gap_x10: i16,
+
+    /// Minimum time between two warnings of the same kind (seconds)
+    pub warning_cooldown_s: u64,
+}
+
+impl Default for ColonyHealthConfigs {
+    fn default() -> Self {
+        Self {
+            window_s: 6 * 3600,
+            min_samples: 12,
+            brood_min_temperature_x10: 330,
+            brood_max_temperature_x10: 365,
+            max_temperature_range_x10: 30,
+            min_temperature_gap_x10: 50,
+            warning_cooldown_s: 6 * 3600,
+        }
+    }
+}
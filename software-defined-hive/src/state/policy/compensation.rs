@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// Load cell output drifts with temperature, this linear model removes the drift using the external temperature
+/// (the load cells sit under the hive, outside).
+///
+/// compensated_g = raw_g - coefficient_g_per_c_x10 * (external_temperature_x10 - reference_temperature_x10) / 100
+///
+/// The coefficient is specific to each device, fit it with the `calibrate_temperature_compensation` command
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemperatureCompensationConfigs {
+    /// Weight drift per degree (g/°C * 10), 0 disables the compensation
+    pub coefficient_g_per_c_x10: i32,
+
+    /// Temperature at which the load cells read true (°C * 10)
+    pub reference_temperature_x10: i16,
+
+    /// Age of the external temperature after which the weight is left uncompensated (seconds), so a sensor that went
+    /// quiet does not keep applying the drift of its last reading
+    #[serde(default = "default_max_temperature_age_s")]
+    pub max_temperature_age_s: u64,
+}
+
+impl Default for TemperatureCompensationConfigs {
+    fn default() -> Self {
+        Self {
+            coefficient_g_per_c_x10: 0,
+            reference_temperature_x10: 0,
+            max_temperature_age_s: default_max_temperature_age_s(),
+        }
+    }
+}
+
+fn default_max_temperature_age_s() -> u64 {
+    1800
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+/// Parameters of the filter chain applied to the weight before it reaches the FSM: outlier rejection -> median -> EMA.
+/// The default chain passes the weight through unchanged. The chain only runs while the hive waits for a harvest, never on the harvest drop
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorFilterConfigs {
+    /// Number of readings the median is taken over, 1 disables the median filter
+    pub median_window: usize,
+
+    /// Weight of the newest reading in the exponential moving average (%), 100 disables the EMA
+    pub ema_alpha_pct: u8,
+
+    /// Readings that change faster than this from the last accepted one are rejected as outliers (grams per second), `None` disables the rejector.
+    /// A change that persists for several readings is accepted e.g. a swarm leaving
+    pub max_rate_g_per_s: Option<u32>,
+}
+
+impl Default for SensorFilterConfigs {
+    fn default() -> Self {
+        Self {
+            median_window: 1,
+            ema_alpha_pct: 100,
+            max_rate_g_per_s: None,
+        }
+    }
+}
@@ -1,5 +1,12 @@
 use serde::{Deserialize, Serialize};
 
+use crate::state::policy::anomaly::AnomalyDetectionConfigs;
+use crate::state::policy::colony_health::ColonyHealthConfigs;
+use crate::state::policy::compensation::TemperatureCompensationConfigs;
+use crate::state::policy::filters::SensorFilterConfigs;
+use crate::state::policy::interlocks::EnvironmentalInterlocks;
+use crate::state::policy::telemetry::TelemetryConfigs;
+
 /// Most of these values can be re-calibrated and delivered as Over the Air (OTA) updates
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +22,43 @@ pub struct HarvestPolicyConfigs {
 
     /// Maximum drain time is the time limit allowed for the Draining state of the hive to consider moving to the Closing state. N/B - I might consider using weight from the sensor because time might be affected by the viscosity of honey.
     pub max_drain_time_s: u64,
+
+    /// Minimum weight the hive has to lose during a harvest for it to be verified as successful (grams).
+    /// A drain cut short by an interlock only has to lose its share, pro rata of max_drain_time_s
+    #[serde(default = "default_min_harvest_drop_g")]
+    pub min_harvest_drop_g: u32,
+
+    /// Time limit for the Verifying state to confirm the harvest before the hive goes to Fault (seconds)
+    #[serde(default = "default_verification_timeout_s")]
+    pub verification_timeout_s: u64,
+
+    /// Rules for harvesting without a human sending `authorize_harvest` (remote apiaries). Disabled by default
+    #[serde(default)]
+    pub auto_harvest: AutoHarvestPolicy,
+
+    /// Environmental conditions that block draining. All disabled by default
+    #[serde(default)]
+    pub interlocks: EnvironmentalInterlocks,
+
+    /// Filter chain applied to the weight before it drives the FSM
+    #[serde(default)]
+    pub filters: SensorFilterConfigs,
+
+    /// Temperature compensation of the load cells, applied before the filter chain
+    #[serde(default)]
+    pub compensation: TemperatureCompensationConfigs,
+
+    /// Thresholds of the swarming, theft and robbing alerts
+    #[serde(default)]
+    pub anomaly: AnomalyDetectionConfigs,
+
+    /// Thresholds of the colony health warnings
+    #[serde(default)]
+    pub colony_health: ColonyHealthConfigs,
+
+    /// Periodic telemetry
+    #[serde(default)]
+    pub telemetry: TelemetryConfigs,
 }
 
 impl Default for HarvestPolicyConfigs {
@@ -24,6 +68,103 @@ impl Default for HarvestPolicyConfigs {
             stable_delta_g: 50,
             stability_window_s: 300,
             max_drain_time_s: 600,
+            min_harvest_drop_g: default_min_harvest_drop_g(),
+            verification_timeout_s: default_verification_timeout_s(),
+            auto_harvest: AutoHarvestPolicy::default(),
+            interlocks: EnvironmentalInterlocks::default(),
+            filters: SensorFilterConfigs::default(),
+            compensation: TemperatureCompensationConfigs::default(),
+            anomaly: AnomalyDetectionConfigs::default(),
+            colony_health: ColonyHealthConfigs::default(),
+            telemetry: TelemetryConfigs::default(),
+        }
+    }
+}
+
+fn default_min_harvest_drop_g() -> u32 {
+    1000
+}
+
+fn default_verification_timeout_s() -> u64 {
+    120
+}
+
+/// A hive in the Ready state is harvested automatically only when ALL of these rules are satisfied.
+///
+/// N/B - The time-of-day windows are evaluated on `SensorReadings::timestamp_s`, Unix time (seconds since epoch, UTC)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoHarvestPolicy {
+    /// Master switch, a human has to authorize every harvest when this is false
+    pub enabled: bool,
+
+    /// Time-of-day windows in which an automatic harvest may start
+    pub windows: Vec<TimeOfDayWindow>,
+
+    /// Minimum internal hive temperature (°C * 10) - honey flows better when it is warm
+    pub min_temperature_x10: i16,
+
+    /// Minimum time between the start of two harvests (seconds)
+    pub min_interval_s: u64,
+
+    /// Offset of the apiary's local time from UTC (seconds) e.g. 10800 for EAT (UTC+3)
+    pub utc_offset_s: i32,
+}
+
+impl Default for AutoHarvestPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            windows: vec![TimeOfDayWindow { start_s: 10 * 3600, end_s: 16 * 3600 }],
+            min_temperature_x10: 250,
+            min_interval_s: 7 * 24 * 3600,
+            utc_offset_s: 0,
         }
     }
 }
+
+/// A daily window in seconds since local midnight. A window with `start_s > end_s` wraps past midnight e.g. 22:00 - 02:00
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeOfDayWindow {
+    pub start_s: u32,
+    pub end_s: u32,
+}
+
+impl TimeOfDayWindow {
+    pub fn contains(&self, second_of_day: u32) -> bool {
+        if self.start_s <= self.end_s {
+            second_of_day >= self.start_s && second_of_day < self.end_s
+        } else {
+            second_of_day >= self.start_s || second_of_day < self.end_s
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u32 = 3600;
+
+    #[test]
+    fn window_includes_its_start_and_excludes_its_end() {
+        let window = TimeOfDayWindow { start_s: 10 * HOUR, end_s: 16 * HOUR };
+
+        assert!(!window.contains(10 * HOUR - 1));
+        assert!(window.contains(10 * HOUR));
+        assert!(window.contains(16 * HOUR - 1));
+        assert!(!window.contains(16 * HOUR));
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let window = TimeOfDayWindow { start_s: 22 * HOUR, end_s: 2 * HOUR };
+
+        assert!(!window.contains(22 * HOUR - 1));
+        assert!(window.contains(22 * HOUR));
+        assert!(window.contains(24 * HOUR - 1));
+        assert!(window.contains(0));
+        assert!(window.contains(2 * HOUR - 1));
+        assert!(!window.contains(2 * HOUR));
+        assert!(!window.contains(12 * HOUR));
+    }
+}
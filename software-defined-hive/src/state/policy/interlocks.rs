@@ -0,0 +1,81 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Interlocks prevent the hive from draining in conditions that are unsafe for the honey or the colony.
+///
+/// Every interlock is optional (`None` disables it) so that hives without temperature/humidity sensors still work
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnvironmentalInterlocks {
+    /// Minimum internal hive temperature (°C * 10) - below this the honey is too viscous to flow
+    pub min_temperature_x10: Option<i16>,
+
+    /// Maximum relative humidity (% * 10) - above this there is a risk of the honey fermenting
+    pub max_humidity_x10: Option<u16>,
+
+    /// Minimum temperature outside the hive (°C * 10) - the honey cools down and stops flowing in the collection line
+    pub min_external_temperature_x10: Option<i16>,
+
+    /// Maximum age of the environmental readings (seconds) before they are considered stale, measured against
+    /// the controller's clock so that sensors that all stop reporting are caught too
+    pub max_sensor_age_s: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnvironmentalSensor {
+    Temperature,
+    Humidity,
+    ExternalTemperature,
+}
+
+/// The reason an interlock blocked (or interrupted) a harvest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "interlock", rename_all = "snake_case")]
+pub enum InterlockTrip {
+    TooCold {
+        temperature_x10: i16,
+        min_temperature_x10: i16,
+    },
+    TooHumid {
+        humidity_x10: u16,
+        max_humidity_x10: u16,
+    },
+    TooColdOutside {
+        external_temperature_x10: i16,
+        min_external_temperature_x10: i16,
+    },
+    /// `age_s` is `None` when the sensor has never reported
+    StaleSensorData {
+        sensor: EnvironmentalSensor,
+        age_s: Option<u64>,
+    },
+}
+
+impl fmt::Display for InterlockTrip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterlockTrip::TooCold { temperature_x10, min_temperature_x10 } => write!(
+                f,
+                "too cold ({}°C * 10 is below the minimum of {})",
+                temperature_x10, min_temperature_x10
+            ),
+            InterlockTrip::TooHumid { humidity_x10, max_humidity_x10 } => write!(
+                f,
+                "too humid ({}% * 10 is above the maximum of {})",
+                humidity_x10, max_humidity_x10
+            ),
+            InterlockTrip::TooColdOutside { external_temperature_x10, min_external_temperature_x10 } => write!(
+                f,
+                "too cold outside ({}°C * 10 is below the minimum of {})",
+                external_temperature_x10, min_external_temperature_x10
+            ),
+            InterlockTrip::StaleSensorData { sensor, age_s: Some(age_s) } => {
+                write!(f, "stale {:?} data (last reading {}s old)", sensor, age_s)
+            }
+            InterlockTrip::StaleSensorData { sensor, age_s: None } => {
+                write!(f, "no {:?} data", sensor)
+            }
+        }
+    }
+}
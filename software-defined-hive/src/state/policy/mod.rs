@@ -1 +1,7 @@
-pub mod harvest;
\ No newline at end of file
+pub mod harvest;
+pub mod interlocks;
+pub mod anomaly;
+pub mod colony_health;
+pub mod filters;
+pub mod compensation;
+pub mod telemetry;
\ No newline at end of file
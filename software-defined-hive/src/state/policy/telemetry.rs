@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// How the readings received within a telemetry interval are reduced to the one that is published
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Downsampling {
+    /// Average of the readings
+    #[default]
+    Average,
+    /// Latest reading
+    Last,
+}
+
+/// Minimum changes since the last published telemetry for a new one to be published
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetryDeadband {
+    pub weight_g: u32,
+    pub temperature_x10: u16,
+    pub humidity_x10: u16,
+    /// Telemetry is published after this long (seconds) even when nothing changed, so that silence means the hive is offline
+    pub max_silence_s: u64,
+}
+
+impl Default for TelemetryDeadband {
+    fn default() -> Self {
+        Self {
+            weight_g: 50,
+            temperature_x10: 5,
+            humidity_x10: 20,
+            max_silence_s: 3600,
+        }
+    }
+}
+
+/// Periodic telemetry for time-series storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetryConfigs {
+    pub enabled: bool,
+
+    /// Minimum time between two telemetry messages (seconds)
+    pub interval_s: u64,
+
+    #[serde(default)]
+    pub downsampling: Downsampling,
+
+    /// Change-only reporting, `None` publishes every interval. A state change or a new fault is published right away,
+    /// without waiting for the interval or the deadband
+    #[serde(default)]
+    pub deadband: Option<TelemetryDeadband>,
+}
+
+impl Default for TelemetryConfigs {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_s: 300,
+            downsampling: Downsampling::Average,
+            deadband: None,
+        }
+    }
+}
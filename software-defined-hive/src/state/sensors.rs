@@ -1,6 +1,26 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Number of frequency bands in `AcousticBands`
+pub const ACOUSTIC_BANDS: usize = 8;
+
+/// Edges of the acoustic bands in Hz, band i covers [ACOUSTIC_BAND_EDGES_HZ[i], ACOUSTIC_BAND_EDGES_HZ[i + 1]).
+/// Most of a colony's sound is between 100Hz and 1kHz, queen piping (a sign of swarming) is around 300-500Hz
+pub const ACOUSTIC_BAND_EDGES_HZ: [u16; ACOUSTIC_BANDS + 1] = [0, 100, 200, 300, 400, 500, 600, 800, 1000];
+
+/// Acoustic signature of the hive as the energy per frequency band (sensor specific units, only comparable between readings of the same sensor)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcousticBands {
+    pub energies: [u16; ACOUSTIC_BANDS],
+}
+
+/// Bees counted at the entrance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BeeTraffic {
+    pub bees_in: u32,
+    pub bees_out: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub struct SensorReadings {
     /// Total calibrated net hive weight in grams
     pub weight_g: u32,
@@ -13,6 +33,15 @@ pub struct SensorReadings {
     /// Relative humidity (% * 10) -  The rationale for this is to stick to int operations in the MCU so if humidity is 42.5%, we treat it as 425, our precision will ALWAYS be 1 decimal place
     #[serde(skip_serializing_if = "Option::is_none")]
     pub humidity_x10: Option<u16>,
-    /// Timestamp (monotonic seconds)
+    /// Barometric pressure (hPa * 10) - e.g. 1013.2 hPa is 10132. Falling pressure signals an approaching weather front
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressure_hpa_x10: Option<u16>,
+    /// Acoustic signature of the colony
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acoustic_bands: Option<AcousticBands>,
+    /// Entrance traffic since the previous reading
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bee_traffic: Option<BeeTraffic>,
+    /// Unix time of the reading (seconds since epoch, UTC) - the auto-harvest time-of-day windows depend on it
     pub timestamp_s: u64,
 }
@@ -1,5 +1,7 @@
 use std::io::Error;
 
+use crate::state::sensors::{AcousticBands, BeeTraffic};
+
 // This might need to be revised
 pub type SensorError = Error;
 
@@ -14,3 +16,17 @@ pub trait TemperatureSensor {
 pub trait HumiditySensor {
     fn read_percent_x10(&mut self) -> Result<u16, SensorError>;
 }
+
+pub trait PressureSensor {
+    fn read_hpa_x10(&mut self) -> Result<u16, SensorError>;
+}
+
+pub trait AcousticSensor {
+    /// energy of the hive's sound in each of the `ACOUSTIC_BAND_EDGES_HZ` bands since the last read
+    fn read_band_energies(&mut self) -> Result<AcousticBands, SensorError>;
+}
+
+pub trait BeeCounter {
+    /// bees that went in and out of the entrance since the last read
+    fn read_counts(&mut self) -> Result<BeeTraffic, SensorError>;
+}
@@ -0,0 +1,70 @@
+use std::time::Instant;
+
+/// Follows the clock of the reading timestamps with the monotonic clock of the device, so time keeps going when no reading arrives
+#[derive(Debug, Default)]
+pub struct ReadingClock {
+    // (latest reading timestamp, when it was seen)
+    anchor: Option<(u64, Instant)>,
+}
+
+impl ReadingClock {
+    pub fn observe(&mut self, last_reading_at: Option<u64>) {
+        self.observe_at(last_reading_at, Instant::now());
+    }
+
+    /// `None` until the first reading
+    pub fn now_s(&self) -> Option<u64> {
+        self.now_s_at(Instant::now())
+    }
+
+    fn observe_at(&mut self, last_reading_at: Option<u64>, seen_at: Instant) {
+        if let Some(timestamp_s) = last_reading_at
+            && self.anchor.is_none_or(|(anchor_s, _)| anchor_s != timestamp_s)
+        {
+            self.anchor = Some((timestamp_s, seen_at));
+        }
+    }
+
+    fn now_s_at(&self, now: Instant) -> Option<u64> {
+        self.anchor.map(|(timestamp_s, seen_at)| timestamp_s + now.saturating_duration_since(seen_at).as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn unknown_until_the_first_reading() {
+        let mut clock = ReadingClock::default();
+        clock.observe(None);
+        assert_eq!(clock.now_s(), None);
+    }
+
+    #[test]
+    fn keeps_going_between_readings() {
+        let booted = Instant::now();
+        let mut clock = ReadingClock::default();
+
+        clock.observe_at(Some(1_000), booted);
+        assert_eq!(clock.now_s_at(booted), Some(1_000));
+        assert_eq!(clock.now_s_at(booted + Duration::from_millis(90_500)), Some(1_090));
+
+        // The same reading seen again does not move the anchor
+        clock.observe_at(Some(1_000), booted + Duration::from_secs(90));
+        assert_eq!(clock.now_s_at(booted + Duration::from_secs(120)), Some(1_120));
+    }
+
+    #[test]
+    fn follows_a_new_reading() {
+        let booted = Instant::now();
+        let mut clock = ReadingClock::default();
+
+        clock.observe_at(Some(1_000), booted);
+        // The sender's clock runs slower than the device's, its readings win
+        clock.observe_at(Some(1_050), booted + Duration::from_secs(60));
+        assert_eq!(clock.now_s_at(booted + Duration::from_secs(70)), Some(1_060));
+    }
+}
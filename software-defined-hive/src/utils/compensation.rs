@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+
+use crate::state::policy::compensation::TemperatureCompensationConfigs;
+
+/// Minimum number of samples needed to fit a compensation coefficient
+pub const MIN_CALIBRATION_SAMPLES: usize = 10;
+
+/// Minimum temperature swing (°C * 10) in the calibration samples - the slope can't be fit from a constant temperature
+pub const MIN_CALIBRATION_TEMPERATURE_SPAN_X10: i16 = 30;
+
+/// A raw weight logged together with the external temperature, used to calibrate the compensation
+#[derive(Debug, Clone, Copy)]
+pub struct CalibrationSample {
+    pub timestamp_s: u64,
+    pub external_temperature_x10: i16,
+    pub raw_weight_g: u32,
+}
+
+/// Readings are averaged over this long (seconds) in the calibration log
+pub const CALIBRATION_LOG_INTERVAL_S: u64 = 300;
+
+/// Running sums of the readings of the current interval
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    started_at: u64,
+    temperature_x10: i64,
+    weight_g: u64,
+    count: u64,
+}
+
+impl Interval {
+    fn average(&self) -> CalibrationSample {
+        CalibrationSample {
+            timestamp_s: self.started_at,
+            external_temperature_x10: (self.temperature_x10 / self.count as i64) as i16,
+            raw_weight_g: (self.weight_g / self.count) as u32,
+        }
+    }
+}
+
+/// The samples to calibrate from, averaged per `CALIBRATION_LOG_INTERVAL_S` so that the log covers the same time
+/// (capacity * interval) whatever the reading rate. The oldest average is dropped first
+#[derive(Debug, Clone)]
+pub struct CalibrationLog {
+    samples: VecDeque<CalibrationSample>,
+    capacity: usize,
+    current: Option<Interval>,
+}
+
+impl CalibrationLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            current: None,
+        }
+    }
+
+    pub fn push(&mut self, sample: CalibrationSample) {
+        let interval = sample.timestamp_s / CALIBRATION_LOG_INTERVAL_S;
+
+        match self.current.as_mut() {
+            Some(current) if current.started_at / CALIBRATION_LOG_INTERVAL_S == interval => {
+                current.temperature_x10 += sample.external_temperature_x10 as i64;
+                current.weight_g += sample.raw_weight_g as u64;
+                current.count += 1;
+                return;
+            }
+            Some(current) => {
+                let average = current.average();
+                if self.samples.len() == self.capacity {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back(average);
+            }
+            None => {}
+        }
+
+        self.current = Some(Interval {
+            started_at: sample.timestamp_s,
+            temperature_x10: sample.external_temperature_x10 as i64,
+            weight_g: sample.raw_weight_g as u64,
+            count: 1,
+        });
+    }
+
+    /// The averages of the intervals that started between start_s and end_s, the current interval included
+    pub fn samples_between(&self, start_s: u64, end_s: u64) -> Vec<CalibrationSample> {
+        self.samples
+            .iter()
+            .copied()
+            .chain(self.current.map(|current| current.average()))
+            .filter(|sample| sample.timestamp_s >= start_s && sample.timestamp_s <= end_s)
+            .collect()
+    }
+}
+
+/// Removes the temperature drift from a raw weight
+pub fn compensate(weight_g: u32, external_temperature_x10: i16, configs: &TemperatureCompensationConfigs) -> u32 {
+    let delta_x10 = external_temperature_x10 as i64 - configs.reference_temperature_x10 as i64;
+    let drift_g = configs.coefficient_g_per_c_x10 as i64 * delta_x10 / 100;
+
+    (weight_g as i64 - drift_g).clamp(0, u32::MAX as i64) as u32
+}
+
+/// Fits the compensation coefficient with least squares from samples logged while the load on the scale did not change,
+/// so every change in weight is drift. The reference temperature is the average temperature of the samples
+pub fn fit_compensation(samples: &[CalibrationSample]) -> Result<TemperatureCompensationConfigs, String> {
+    if samples.len() < MIN_CALIBRATION_SAMPLES {
+        return Err(format!(
+            "At least {} samples are needed for calibration, got {}",
+            MIN_CALIBRATION_SAMPLES,
+            samples.len()
+        ));
+    }
+
+    let min_x10 = samples.iter().map(|sample| sample.external_temperature_x10).min().unwrap_or_default();
+    let max_x10 = samples.iter().map(|sample| sample.external_temperature_x10).max().unwrap_or_default();
+    if max_x10 - min_x10 < MIN_CALIBRATION_TEMPERATURE_SPAN_X10 {
+        return Err(format!(
+            "The temperature has to vary by at least {}°C * 10 during calibration, got {}",
+            MIN_CALIBRATION_TEMPERATURE_SPAN_X10,
+            max_x10 - min_x10
+        ));
+    }
+
+    let n = samples.len() as i128;
+    let mean_temperature_x10 = samples.iter().map(|sample| sample.external_temperature_x10 as i128).sum::<i128>() / n;
+    let mean_weight_g = samples.iter().map(|sample| sample.raw_weight_g as i128).sum::<i128>() / n;
+
+    let (covariance, variance) = samples.iter().fold((0i128, 0i128), |(covariance, variance), sample| {
+        let dt = sample.external_temperature_x10 as i128 - mean_temperature_x10;
+        let dw = sample.raw_weight_g as i128 - mean_weight_g;
+        (covariance + dt * dw, variance + dt * dt)
+    });
+
+    // The slope is in grams per 0.1°C, the coefficient in g/°C * 10
+    let coefficient_g_per_c_x10 = (covariance * 100 / variance)
+        .clamp(i32::MIN as i128, i32::MAX as i128) as i32;
+
+    Ok(TemperatureCompensationConfigs {
+        coefficient_g_per_c_x10,
+        reference_temperature_x10: mean_temperature_x10 as i16,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp_s: u64, external_temperature_x10: i16, raw_weight_g: u32) -> CalibrationSample {
+        CalibrationSample { timestamp_s, external_temperature_x10, raw_weight_g }
+    }
+
+    #[test]
+    fn fit_recovers_a_linear_drift() {
+        // 2 g per 0.1°C around 20°C
+        let samples: Vec<CalibrationSample> = (0..20)
+            .map(|i| {
+                let temperature_x10 = 150 + i * 10;
+                sample(i as u64 * 300, temperature_x10, (5000 + 2 * (temperature_x10 as i32 - 245)) as u32)
+            })
+            .collect();
+
+        let compensation = fit_compensation(&samples).unwrap();
+        assert_eq!(compensation.coefficient_g_per_c_x10, 200);
+        assert_eq!(compensation.reference_temperature_x10, 245);
+        assert_eq!(compensate(5000 + 2 * 100, 345, &compensation), 5000);
+    }
+
+    #[test]
+    fn fit_needs_enough_samples_and_a_temperature_swing() {
+        let few: Vec<CalibrationSample> = (0..5).map(|i| sample(i * 300, 150 + i as i16 * 10, 5000)).collect();
+        assert!(fit_compensation(&few).is_err());
+
+        let constant: Vec<CalibrationSample> = (0..20).map(|i| sample(i * 300, 200, 5000)).collect();
+        assert!(fit_compensation(&constant).is_err());
+    }
+
+    #[test]
+    fn log_averages_the_readings_of_an_interval() {
+        let mut log = CalibrationLog::new(4);
+        for t in 0..10 {
+            log.push(sample(t * 30, 200 + t as i16, 5000 + t as u32));
+        }
+        log.push(sample(300, 300, 6000));
+
+        let samples = log.samples_between(0, u64::MAX);
+        assert_eq!(samples.len(), 2);
+        assert_eq!((samples[0].timestamp_s, samples[0].external_temperature_x10, samples[0].raw_weight_g), (0, 204, 5004));
+        assert_eq!((samples[1].timestamp_s, samples[1].raw_weight_g), (300, 6000));
+    }
+
+    #[test]
+    fn log_covers_its_capacity_in_intervals_whatever_the_reading_rate() {
+        let mut log = CalibrationLog::new(48);
+        // A reading every 10 s for 5 hours
+        for t in (0..5 * 3600).step_by(10) {
+            log.push(sample(t, 200, 5000));
+        }
+
+        let samples = log.samples_between(0, u64::MAX);
+        assert_eq!(samples.len(), 49);
+        assert_eq!(samples[0].timestamp_s, 5 * 3600 - 49 * CALIBRATION_LOG_INTERVAL_S);
+        assert_eq!(log.samples_between(3600, 7200).len(), 13);
+    }
+}
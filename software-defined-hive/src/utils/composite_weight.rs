@@ -0,0 +1,365 @@
+use std::io::ErrorKind;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::state::traits::{SensorError, WeightSensor};
+
+/// Per-corner calibration of a load cell
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LoadCellCalibration {
+    /// Reading of the cell with no hive on the scale (grams)
+    pub tare_g: i32,
+    /// Scale factor applied after removing the tare (* 1000) i.e. 1000 means the cell reads true
+    pub scale_x1000: u32,
+    /// Position of the cell relative to the centre of the scale (millimetres), used for the centre of mass
+    pub position_mm: (i32, i32),
+    /// Rated capacity of the cell (grams), a calibrated reading at or above it is saturated
+    pub capacity_g: u32,
+}
+
+/// One load cell (usually one under each corner of the hive) together with its calibration
+pub struct LoadCell<S: WeightSensor> {
+    pub sensor: S,
+    pub calibration: LoadCellCalibration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadCellHealth {
+    Ok,
+    /// The cell returned an error
+    ReadError,
+    /// The cell reads less than with nothing on it - disconnected, broken or drifted since it was tared
+    BelowTare,
+    /// The cell reads at or above its capacity - overloaded, or its amplifier is saturated
+    Saturated,
+    /// The cell holds the same raw reading while the load on the others changes - a frozen amplifier, or a dead cell
+    /// reading its tare
+    Stuck,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LoadCellStatus {
+    /// Calibrated reading of the cell (grams), `None` when it could not be read
+    pub weight_g: Option<u32>,
+    pub health: LoadCellHealth,
+}
+
+/// Details of the last reading of a `CompositeWeightSensor`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompositeWeightReport {
+    /// Total weight (grams). When cells failed it is estimated from the healthy ones
+    pub total_g: u32,
+    pub cells: Vec<LoadCellStatus>,
+    /// True when one or more cells failed and total_g is an estimate
+    pub degraded: bool,
+    /// Centre of mass relative to the centre of the scale (millimetres), only known when all the cells are healthy.
+    /// An offset from (0, 0) means the hive is tilted or the honey is stored unevenly
+    pub centre_of_mass_mm: Option<(i32, i32)>,
+}
+
+/// Combines N load cells into a single `WeightSensor`, detecting failed cells so that one of them does not silently halve the weight.
+/// Each cell is checked against its own bounds and its own history rather than against the other cells, so a hive that is
+/// tilted or loaded off-centre is not mistaken for failed cells, while a cell that stopped following the load is.
+///
+/// It is registered as the `SensorRole::Weight` driver of the `SensorDataAggregator`, `last_report` has the per-cell details
+pub struct CompositeWeightSensor<S: WeightSensor> {
+    cells: Vec<LoadCell<S>>,
+    /// How far below its tare a cell may read (grams) - a cell carrying part of the hive never reads less than empty,
+    /// the tolerance covers the noise and the drift of an unloaded corner
+    tare_tolerance_g: u32,
+    /// How much the load on the other cells must change (grams) while a cell holds the same raw reading for it to be stuck
+    stuck_load_change_g: u32,
+    history: Vec<CellHistory>,
+    last_report: Option<CompositeWeightReport>,
+}
+
+/// What the other cells carried since the raw reading of a cell last changed
+#[derive(Debug, Clone, Copy, Default)]
+struct CellHistory {
+    last_raw_g: Option<u32>,
+    others_min_g: u64,
+    others_max_g: u64,
+}
+
+impl<S: WeightSensor> CompositeWeightSensor<S> {
+    pub fn new(cells: Vec<LoadCell<S>>, tare_tolerance_g: u32, stuck_load_change_g: u32) -> Self {
+        Self {
+            history: vec![CellHistory::default(); cells.len()],
+            cells,
+            tare_tolerance_g,
+            stuck_load_change_g,
+            last_report: None,
+        }
+    }
+
+    pub fn last_report(&self) -> Option<&CompositeWeightReport> {
+        self.last_report.as_ref()
+    }
+
+    pub fn read_report(&mut self) -> Result<CompositeWeightReport, SensorError> {
+        let raws_g: Vec<Option<u32>> = self.cells.iter_mut().map(|cell| cell.sensor.read_grams().ok()).collect();
+        let mut cells: Vec<LoadCellStatus> = raws_g
+            .iter()
+            .zip(&self.cells)
+            .map(|(raw_g, cell)| match raw_g {
+                Some(raw_g) => check(*raw_g, &cell.calibration, self.tare_tolerance_g),
+                None => LoadCellStatus {
+                    weight_g: None,
+                    health: LoadCellHealth::ReadError,
+                },
+            })
+            .collect();
+        self.flag_stuck_cells(&raws_g, &mut cells);
+
+        let healthy: Vec<(usize, u32)> = cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| cell.health == LoadCellHealth::Ok)
+            .filter_map(|(index, cell)| cell.weight_g.map(|weight_g| (index, weight_g)))
+            .collect();
+
+        if healthy.is_empty() {
+            return Err(SensorError::new(ErrorKind::NotConnected, "All load cells failed"));
+        }
+
+        let healthy_sum_g: u64 = healthy.iter().map(|(_, weight_g)| *weight_g as u64).sum();
+        let degraded = healthy.len() < cells.len();
+
+        // Assume the load is spread evenly, a failed cell is replaced by the average of the healthy ones
+        let total_g = (healthy_sum_g * cells.len() as u64 / healthy.len() as u64).min(u32::MAX as u64) as u32;
+
+        if degraded {
+            warn!("{} of {} load cells failed, the weight is an estimate", cells.len() - healthy.len(), cells.len());
+        }
+
+        let centre_of_mass_mm = match (degraded, healthy_sum_g) {
+            (false, sum_g) if sum_g > 0 => {
+                let (x, y) = healthy.iter().fold((0i64, 0i64), |(x, y), (index, weight_g)| {
+                    let (cell_x, cell_y) = self.cells[*index].calibration.position_mm;
+                    (x + cell_x as i64 * *weight_g as i64, y + cell_y as i64 * *weight_g as i64)
+                });
+                Some(((x / sum_g as i64) as i32, (y / sum_g as i64) as i32))
+            }
+            _ => None,
+        };
+
+        let report = CompositeWeightReport {
+            total_g,
+            cells,
+            degraded,
+            centre_of_mass_mm,
+        };
+        self.last_report = Some(report.clone());
+
+        Ok(report)
+    }
+
+    /// A live cell moves with the load, even a little on a tilted hive. One that holds the same raw reading while the
+    /// others change is stuck, whatever value it is stuck on
+    fn flag_stuck_cells(&mut self, raws_g: &[Option<u32>], cells: &mut [LoadCellStatus]) {
+        let total_g: u64 = cells.iter().filter_map(|cell| cell.weight_g).map(u64::from).sum();
+
+        for ((raw_g, cell), history) in raws_g.iter().zip(cells.iter_mut()).zip(&mut self.history) {
+            let others_g = total_g - cell.weight_g.unwrap_or_default() as u64;
+            if raw_g.is_none() || *raw_g != history.last_raw_g {
+                *history = CellHistory { last_raw_g: *raw_g, others_min_g: others_g, others_max_g: others_g };
+                continue;
+            }
+
+            history.others_min_g = history.others_min_g.min(others_g);
+            history.others_max_g = history.others_max_g.max(others_g);
+            if cell.health == LoadCellHealth::Ok && history.others_max_g - history.others_min_g > self.stuck_load_change_g as u64 {
+                cell.health = LoadCellHealth::Stuck;
+            }
+        }
+    }
+}
+
+impl<S: WeightSensor> WeightSensor for CompositeWeightSensor<S> {
+    fn read_grams(&mut self) -> Result<u32, SensorError> {
+        Ok(self.read_report()?.total_g)
+    }
+}
+
+/// Calibrates a raw reading and checks it against the bounds of its cell
+fn check(raw_g: u32, calibration: &LoadCellCalibration, tare_tolerance_g: u32) -> LoadCellStatus {
+    let net_g = (raw_g as i64 - calibration.tare_g as i64) * calibration.scale_x1000 as i64 / 1000;
+    let weight_g = net_g.clamp(0, u32::MAX as i64) as u32;
+
+    let health = if net_g < -(tare_tolerance_g as i64) {
+        LoadCellHealth::BelowTare
+    } else if weight_g >= calibration.capacity_g {
+        LoadCellHealth::Saturated
+    } else {
+        LoadCellHealth::Ok
+    };
+
+    LoadCellStatus {
+        weight_g: Some(weight_g),
+        health,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::sensors::{SensorDataAggregator, SensorDriver, SensorRegistration, SensorRole, SensorUnit};
+
+    /// Raw readings are offset by the tare, as an amplifier reports them
+    const TARE_G: i32 = 8000;
+
+    struct FixedCell(Option<u32>);
+
+    impl WeightSensor for FixedCell {
+        fn read_grams(&mut self) -> Result<u32, SensorError> {
+            self.0.ok_or_else(|| SensorError::new(ErrorKind::TimedOut, "No answer"))
+        }
+    }
+
+    /// Four corners 200 mm from the centre, the raw readings are the weights on each cell
+    fn scale(weights_g: [Option<u32>; 4]) -> CompositeWeightSensor<FixedCell> {
+        let positions = [(-200, -200), (200, -200), (-200, 200), (200, 200)];
+        let cells = weights_g
+            .into_iter()
+            .zip(positions)
+            .map(|(weight_g, position_mm)| LoadCell {
+                sensor: FixedCell(weight_g.map(|weight_g| weight_g + TARE_G as u32)),
+                calibration: LoadCellCalibration { tare_g: TARE_G, scale_x1000: 1000, position_mm, capacity_g: 50_000 },
+            })
+            .collect();
+
+        CompositeWeightSensor::new(cells, 200, 500)
+    }
+
+    fn set_weights(sensor: &mut CompositeWeightSensor<FixedCell>, weights_g: [u32; 4]) {
+        for (cell, weight_g) in sensor.cells.iter_mut().zip(weights_g) {
+            cell.sensor = FixedCell(Some(weight_g + TARE_G as u32));
+        }
+    }
+
+    fn health(report: &CompositeWeightReport) -> Vec<LoadCellHealth> {
+        report.cells.iter().map(|cell| cell.health).collect()
+    }
+
+    #[test]
+    fn healthy_cells_add_up() {
+        let report = scale([Some(5000), Some(5000), Some(5000), Some(5000)]).read_report().unwrap();
+
+        assert_eq!(report.total_g, 20_000);
+        assert!(!report.degraded);
+        assert_eq!(report.centre_of_mass_mm, Some((0, 0)));
+    }
+
+    #[test]
+    fn tilted_hive_is_not_a_failed_cell() {
+        // Nearly all of the load on the right side
+        let report = scale([Some(100), Some(9900), Some(100), Some(9900)]).read_report().unwrap();
+
+        assert_eq!(health(&report), vec![LoadCellHealth::Ok; 4]);
+        assert_eq!(report.total_g, 20_000);
+        assert_eq!(report.centre_of_mass_mm, Some((196, 0)));
+    }
+
+    #[test]
+    fn disconnected_cell_is_replaced_by_the_average_of_the_others() {
+        let mut sensor = scale([Some(5000), Some(5000), Some(5000), None]);
+        // A disconnected amplifier reads 0, far below the tare
+        sensor.cells[3].sensor = FixedCell(Some(0));
+
+        let report = sensor.read_report().unwrap();
+
+        assert_eq!(health(&report)[3], LoadCellHealth::BelowTare);
+        assert_eq!(report.total_g, 20_000);
+        assert!(report.degraded);
+        assert_eq!(report.centre_of_mass_mm, None);
+    }
+
+    #[test]
+    fn unloaded_corner_within_the_tolerance_is_healthy() {
+        let mut sensor = scale([Some(6000), Some(6000), Some(6000), Some(0)]);
+        sensor.cells[3].sensor = FixedCell(Some(TARE_G as u32 - 150));
+
+        let report = sensor.read_report().unwrap();
+
+        assert_eq!(health(&report), vec![LoadCellHealth::Ok; 4]);
+        assert_eq!(report.total_g, 18_000);
+    }
+
+    #[test]
+    fn saturated_and_unreadable_cells_are_flagged() {
+        let report = scale([Some(5000), Some(60_000), None, Some(5000)]).read_report().unwrap();
+
+        assert_eq!(
+            health(&report),
+            vec![LoadCellHealth::Ok, LoadCellHealth::Saturated, LoadCellHealth::ReadError, LoadCellHealth::Ok]
+        );
+        assert_eq!(report.total_g, 20_000);
+    }
+
+    #[test]
+    fn cell_frozen_while_the_load_changes_is_stuck() {
+        let mut sensor = scale([Some(5000); 4]);
+        sensor.read_report().unwrap();
+
+        // The amplifier of the last cell froze, the honey flow only shows on the others
+        set_weights(&mut sensor, [5100, 5100, 5100, 5000]);
+        assert_eq!(health(&sensor.read_report().unwrap()), vec![LoadCellHealth::Ok; 4]);
+
+        set_weights(&mut sensor, [5300, 5300, 5300, 5000]);
+        let report = sensor.read_report().unwrap();
+
+        assert_eq!(health(&report)[3], LoadCellHealth::Stuck);
+        assert!(report.degraded);
+        assert_eq!(report.total_g, 21_200);
+    }
+
+    #[test]
+    fn dead_cell_reading_its_tare_is_stuck() {
+        // A cell tared at 0 whose amplifier died reads 0, which is neither below its tare nor saturated
+        let mut sensor = scale([Some(5000); 4]);
+        sensor.cells[0].calibration.tare_g = 0;
+        sensor.cells[0].sensor = FixedCell(Some(0));
+        assert_eq!(health(&sensor.read_report().unwrap())[0], LoadCellHealth::Ok);
+
+        for weights_g in [[0, 5500, 5500, 5500], [0, 6000, 6000, 6000]] {
+            set_weights(&mut sensor, weights_g);
+            sensor.cells[0].sensor = FixedCell(Some(0));
+            sensor.read_report().unwrap();
+        }
+
+        assert_eq!(health(sensor.last_report().unwrap())[0], LoadCellHealth::Stuck);
+    }
+
+    #[test]
+    fn steady_hive_is_not_stuck_and_a_stuck_cell_recovers() {
+        let mut sensor = scale([Some(5000); 4]);
+        for _ in 0..10 {
+            assert_eq!(health(&sensor.read_report().unwrap()), vec![LoadCellHealth::Ok; 4]);
+        }
+
+        set_weights(&mut sensor, [6000, 6000, 6000, 5000]);
+        assert_eq!(health(&sensor.read_report().unwrap())[3], LoadCellHealth::Stuck);
+
+        set_weights(&mut sensor, [6010, 6020, 6030, 6040]);
+        assert_eq!(health(&sensor.read_report().unwrap()), vec![LoadCellHealth::Ok; 4]);
+    }
+
+    #[test]
+    fn all_cells_failed_is_an_error() {
+        assert!(scale([None, None, None, None]).read_report().is_err());
+    }
+
+    #[test]
+    fn registers_as_the_weight_sensor() {
+        let mut aggregator = SensorDataAggregator::new();
+        let registration = SensorRegistration { role: SensorRole::Weight, unit: SensorUnit::Grams, interval_s: 0, max_age_s: 0 };
+        aggregator
+            .register(registration, SensorDriver::Weight(Box::new(scale([Some(5000); 4]))))
+            .unwrap();
+
+        let readings = aggregator.aggregate_sensor_readings(60).to_sensor_readings().unwrap();
+        assert_eq!(readings.weight_g, 20_000);
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+
+use crate::state::policy::filters::SensorFilterConfigs;
+
+/// Number of consecutive outliers after which the new level is accepted as a real change
+const MAX_CONSECUTIVE_REJECTIONS: u8 = 3;
+
+/// Smooths the load cell output (wind, bees landing) before the FSM compares consecutive readings
+#[derive(Debug, Clone)]
+pub struct WeightFilterChain {
+    configs: SensorFilterConfigs,
+    // (timestamp_s, weight_g) of the last reading that was not rejected
+    last_accepted: Option<(u64, u32)>,
+    consecutive_rejections: u8,
+    median_window: VecDeque<u32>,
+    // Hundredths of a gram, so that small steps still move the average
+    ema_g_x100: Option<i64>,
+}
+
+impl WeightFilterChain {
+    pub fn new(configs: SensorFilterConfigs) -> Self {
+        Self {
+            median_window: VecDeque::with_capacity(configs.median_window),
+            configs,
+            last_accepted: None,
+            consecutive_rejections: 0,
+            ema_g_x100: None,
+        }
+    }
+
+    pub fn configs(&self) -> &SensorFilterConfigs {
+        &self.configs
+    }
+
+    /// Forgets the history, the next reading passes through unchanged
+    pub fn reset(&mut self) {
+        self.last_accepted = None;
+        self.consecutive_rejections = 0;
+        self.median_window.clear();
+        self.ema_g_x100 = None;
+    }
+
+    /// Runs a raw weight through the chain and returns the filtered weight
+    pub fn apply(&mut self, weight_g: u32, timestamp_s: u64) -> u32 {
+        let accepted_g = self.reject_outlier(weight_g, timestamp_s);
+        let median_g = self.median(accepted_g);
+        self.ema(median_g)
+    }
+
+    /// Replaces an outlier with the last accepted reading
+    fn reject_outlier(&mut self, weight_g: u32, timestamp_s: u64) -> u32 {
+        let (Some(max_rate_g_per_s), Some((last_timestamp_s, last_weight_g))) = (self.configs.max_rate_g_per_s, self.last_accepted) else {
+            self.last_accepted = Some((timestamp_s, weight_g));
+            return weight_g;
+        };
+
+        let elapsed_s = timestamp_s.saturating_sub(last_timestamp_s).max(1);
+        let max_change_g = max_rate_g_per_s as u64 * elapsed_s;
+
+        if last_weight_g.abs_diff(weight_g) as u64 > max_change_g
+            && self.consecutive_rejections < MAX_CONSECUTIVE_REJECTIONS
+        {
+            self.consecutive_rejections += 1;
+            return last_weight_g;
+        }
+
+        self.consecutive_rejections = 0;
+        self.last_accepted = Some((timestamp_s, weight_g));
+        weight_g
+    }
+
+    fn median(&mut self, weight_g: u32) -> u32 {
+        if self.configs.median_window <= 1 {
+            return weight_g;
+        }
+
+        if self.median_window.len() == self.configs.median_window {
+            self.median_window.pop_front();
+        }
+        self.median_window.push_back(weight_g);
+
+        let mut sorted: Vec<u32> = self.median_window.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted[sorted.len() / 2]
+    }
+
+    fn ema(&mut self, weight_g: u32) -> u32 {
+        let alpha_pct = self.configs.ema_alpha_pct.min(100) as i64;
+        let weight_g_x100 = weight_g as i64 * 100;
+        let ema_g_x100 = match self.ema_g_x100 {
+            Some(ema_g_x100) => ema_g_x100 + (alpha_pct * (weight_g_x100 - ema_g_x100)) / 100,
+            None => weight_g_x100,
+        };
+
+        self.ema_g_x100 = Some(ema_g_x100);
+        ((ema_g_x100 + 50) / 100) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(median_window: usize, ema_alpha_pct: u8, max_rate_g_per_s: Option<u32>) -> WeightFilterChain {
+        WeightFilterChain::new(SensorFilterConfigs { median_window, ema_alpha_pct, max_rate_g_per_s })
+    }
+
+    #[test]
+    fn default_chain_passes_the_weight_through() {
+        let mut filter = WeightFilterChain::new(SensorFilterConfigs::default());
+
+        for (t, weight_g) in [5000, 5300, 4100, 4100, 9000].into_iter().enumerate() {
+            assert_eq!(filter.apply(weight_g, t as u64 * 60), weight_g);
+        }
+    }
+
+    #[test]
+    fn median_drops_a_single_spike() {
+        let mut filter = chain(3, 100, None);
+
+        assert_eq!(filter.apply(5000, 0), 5000);
+        assert_eq!(filter.apply(5010, 60), 5010);
+        assert_eq!(filter.apply(7000, 120), 5010);
+        assert_eq!(filter.apply(5020, 180), 5020);
+    }
+
+    #[test]
+    fn ema_follows_steps_smaller_than_its_resolution() {
+        let mut filter = chain(1, 10, None);
+        filter.apply(5000, 0);
+
+        // 10% of a 5 g step is half a gram, it has to add up instead of being truncated away
+        let filtered: Vec<u32> = (1..=30).map(|t| filter.apply(5005, t * 60)).collect();
+        assert_eq!(filtered[0], 5001);
+        assert_eq!(*filtered.last().unwrap(), 5005);
+    }
+
+    #[test]
+    fn rejector_holds_outliers_until_the_change_persists() {
+        let mut filter = chain(1, 100, Some(10));
+
+        assert_eq!(filter.apply(5000, 0), 5000);
+        // 60 s at 10 g/s allows 600 g
+        assert_eq!(filter.apply(5500, 60), 5500);
+        assert_eq!(filter.apply(2000, 120), 5500);
+        assert_eq!(filter.apply(2000, 180), 5500);
+        assert_eq!(filter.apply(2000, 240), 5500);
+        assert_eq!(filter.apply(2000, 300), 2000);
+    }
+
+    #[test]
+    fn reset_forgets_the_history() {
+        let mut filter = chain(3, 10, Some(10));
+        filter.apply(5000, 0);
+        filter.apply(5000, 60);
+
+        filter.reset();
+        assert_eq!(filter.apply(1000, 120), 1000);
+    }
+}
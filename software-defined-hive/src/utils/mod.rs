@@ -1 +1,9 @@
-pub mod sensors;
\ No newline at end of file
+pub mod sensors;
+pub mod filters;
+pub mod compensation;
+pub mod composite_weight;
+pub mod reading_merger;
+pub mod telemetry;
+pub mod backoff;
+pub mod outbox;
+pub mod clock;
\ No newline at end of file
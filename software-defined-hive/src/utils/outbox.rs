@@ -0,0 +1,400 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+/// Which messages go first when the outbox is full: the least important, then the oldest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessagePriority {
+    /// e.g. telemetry, the next interval makes up for a lost one
+    Low,
+    /// e.g. state changes
+    Normal,
+    /// Harvest-ready alerts, harvest reports and anomaly alerts must not be lost
+    High,
+}
+
+/// A message waiting for the broker, the payload is serialized when the event happens so it keeps its timestamps
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    /// Identifies the event, a message with the ID of one already queued or recently sent is dropped
+    pub id: String,
+    pub topic: String,
+    pub payload: String,
+    /// MQTT QoS level (0, 1 or 2)
+    pub qos: u8,
+    pub priority: MessagePriority,
+    /// When the event happened (seconds)
+    pub timestamp_s: u64,
+}
+
+impl QueuedMessage {
+    /// What the message takes in the persisted outbox, roughly
+    fn size(&self) -> usize {
+        const OVERHEAD: usize = 64;
+        self.id.len() + self.topic.len() + self.payload.len() + OVERHEAD
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushOutcome {
+    /// The less important messages that were evicted to make room, oldest first
+    Queued { evicted: Vec<QueuedMessage> },
+    /// A message with the same ID is queued or was sent recently
+    Duplicate,
+    /// The outbox is full of messages at least as important, or the message alone exceeds it
+    Dropped,
+}
+
+/// How many IDs of sent messages are remembered to drop replays of the same event
+const RECENTLY_SENT: usize = 32;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    message: QueuedMessage,
+    /// The MQTT message ID while the client holds the message and the broker has not acknowledged it
+    in_flight: Option<u32>,
+}
+
+/// Bounded store-and-forward queue for the messages published while the hive is offline, replayed in order.
+/// Messages with a QoS above 0 stay in flight, and count against the bounds, until the broker acknowledges them
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    messages: VecDeque<Entry>,
+    max_messages: usize,
+    max_bytes: usize,
+    bytes: usize,
+    recently_sent: VecDeque<String>,
+}
+
+impl Outbox {
+    pub fn new(max_messages: usize, max_bytes: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            max_messages,
+            max_bytes,
+            bytes: 0,
+            recently_sent: VecDeque::with_capacity(RECENTLY_SENT),
+        }
+    }
+
+    /// Queues a message behind the others. When full, the least important messages waiting are evicted (the oldest
+    /// first), but only ones that are not more important than the new message
+    pub fn push(&mut self, message: QueuedMessage) -> PushOutcome {
+        if self.messages.iter().any(|queued| queued.message.id == message.id) || self.recently_sent.contains(&message.id) {
+            return PushOutcome::Duplicate;
+        }
+
+        let size = message.size();
+        if size > self.max_bytes || self.max_messages == 0 {
+            return PushOutcome::Dropped;
+        }
+
+        // Pick the victims before evicting anything, nothing changes when the message is dropped.
+        // The client holds the messages in flight, they cannot be taken back
+        let mut candidates: Vec<usize> = (0..self.messages.len())
+            .filter(|&i| self.messages[i].in_flight.is_none() && self.messages[i].message.priority <= message.priority)
+            .collect();
+        candidates.sort_by_key(|&i| (self.messages[i].message.priority, i));
+
+        let mut victims = Vec::new();
+        let (mut count, mut bytes) = (self.messages.len(), self.bytes);
+        for i in candidates {
+            if count < self.max_messages && bytes + size <= self.max_bytes {
+                break;
+            }
+            victims.push(i);
+            count -= 1;
+            bytes -= self.messages[i].message.size();
+        }
+
+        if count >= self.max_messages || bytes + size > self.max_bytes {
+            return PushOutcome::Dropped;
+        }
+
+        // From the back so that the indices stay valid, then back to the queue order
+        victims.sort_unstable();
+        let mut evicted: Vec<QueuedMessage> =
+            victims.iter().rev().filter_map(|&i| self.messages.remove(i)).map(|entry| entry.message).collect();
+        evicted.reverse();
+
+        self.bytes = bytes + size;
+        self.messages.push_back(Entry { message, in_flight: None });
+        PushOutcome::Queued { evicted }
+    }
+
+    /// The oldest message waiting, the next to send
+    pub fn front(&self) -> Option<&QueuedMessage> {
+        self.messages.iter().find(|entry| entry.in_flight.is_none()).map(|entry| &entry.message)
+    }
+
+    /// Removes the oldest message waiting once it is handed to the broker, for QoS 0 where nothing acknowledges it
+    pub fn pop_sent(&mut self) -> Option<QueuedMessage> {
+        let index = self.messages.iter().position(|entry| entry.in_flight.is_none())?;
+        self.remove_sent(index)
+    }
+
+    /// The oldest message waiting was handed to the client as `msg_id`, it is kept until acknowledged
+    pub fn set_in_flight(&mut self, msg_id: u32) {
+        if let Some(entry) = self.messages.iter_mut().find(|entry| entry.in_flight.is_none()) {
+            entry.in_flight = Some(msg_id);
+        }
+    }
+
+    /// Removes the message the broker acknowledged, `None` for a message the outbox did not send
+    pub fn acknowledge(&mut self, msg_id: u32) -> Option<QueuedMessage> {
+        let index = self.messages.iter().position(|entry| entry.in_flight == Some(msg_id))?;
+        self.remove_sent(index)
+    }
+
+    /// The client dropped the message, it waits to be sent again in its place in the queue
+    pub fn requeue(&mut self, msg_id: u32) -> bool {
+        match self.messages.iter_mut().find(|entry| entry.in_flight == Some(msg_id)) {
+            Some(entry) => {
+                entry.in_flight = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The client is gone with the messages it held, they wait to be sent again. Returns how many
+    pub fn requeue_in_flight(&mut self) -> usize {
+        self.messages.iter_mut().filter_map(|entry| entry.in_flight.take()).count()
+    }
+
+    /// How many messages wait for an acknowledgement
+    pub fn in_flight(&self) -> usize {
+        self.messages.iter().filter(|entry| entry.in_flight.is_some()).count()
+    }
+
+    fn remove_sent(&mut self, index: usize) -> Option<QueuedMessage> {
+        let Entry { message, .. } = self.messages.remove(index)?;
+        self.bytes -= message.size();
+        self.mark_sent(&message.id);
+        Some(message)
+    }
+
+    /// Remembers the ID of a message sent without going through the outbox
+    pub fn mark_sent(&mut self, id: &str) {
+        if self.recently_sent.len() == RECENTLY_SENT {
+            self.recently_sent.pop_front();
+        }
+        self.recently_sent.push_back(id.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// The queued messages, oldest first and those in flight included, to persist them
+    pub fn messages(&self) -> impl Iterator<Item = &QueuedMessage> {
+        self.messages.iter().map(|entry| &entry.message)
+    }
+
+    /// Queues persisted messages again, in their order and within the bounds
+    pub fn restore(&mut self, messages: Vec<QueuedMessage>) {
+        for message in messages {
+            self.push(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, priority: MessagePriority) -> QueuedMessage {
+        QueuedMessage {
+            id: id.to_string(),
+            topic: "apiary/hive-1/notifications/state-change".to_string(),
+            payload: "{}".to_string(),
+            qos: 1,
+            priority,
+            timestamp_s: 1000,
+        }
+    }
+
+    fn ids(outbox: &Outbox) -> Vec<&str> {
+        outbox.messages().map(|message| message.id.as_str()).collect()
+    }
+
+    fn queued(outcome: PushOutcome) -> Vec<String> {
+        match outcome {
+            PushOutcome::Queued { evicted } => evicted.into_iter().map(|message| message.id).collect(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn sends_in_order() {
+        let mut outbox = Outbox::new(8, 4096);
+        queued(outbox.push(message("a", MessagePriority::Low)));
+        queued(outbox.push(message("b", MessagePriority::High)));
+
+        assert_eq!(outbox.front().unwrap().id, "a");
+        assert_eq!(outbox.pop_sent().unwrap().id, "a");
+        assert_eq!(outbox.pop_sent().unwrap().id, "b");
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.pop_sent(), None);
+    }
+
+    #[test]
+    fn evicts_the_least_important_oldest_first() {
+        let mut outbox = Outbox::new(3, 4096);
+        queued(outbox.push(message("telemetry-1", MessagePriority::Low)));
+        queued(outbox.push(message("state-1", MessagePriority::Normal)));
+        queued(outbox.push(message("telemetry-2", MessagePriority::Low)));
+
+        assert_eq!(queued(outbox.push(message("report-1", MessagePriority::High))), vec!["telemetry-1"]);
+        assert_eq!(queued(outbox.push(message("state-2", MessagePriority::Normal))), vec!["telemetry-2"]);
+        assert_eq!(ids(&outbox), vec!["state-1", "report-1", "state-2"]);
+
+        // Nothing less important is left to evict
+        assert_eq!(outbox.push(message("telemetry-3", MessagePriority::Low)), PushOutcome::Dropped);
+        assert_eq!(queued(outbox.push(message("report-2", MessagePriority::High))), vec!["state-1"]);
+        assert_eq!(ids(&outbox), vec!["report-1", "state-2", "report-2"]);
+    }
+
+    #[test]
+    fn evicts_to_fit_the_bytes() {
+        let size = message("a", MessagePriority::Low).size();
+        let mut outbox = Outbox::new(64, 3 * size);
+        queued(outbox.push(message("a", MessagePriority::Low)));
+        queued(outbox.push(message("b", MessagePriority::Low)));
+        queued(outbox.push(message("c", MessagePriority::Normal)));
+
+        // Twice the size of the others, two of them make room
+        let mut large = message("d", MessagePriority::Normal);
+        large.payload = "x".repeat(size);
+        assert_eq!(queued(outbox.push(large)), vec!["a", "b"]);
+        assert_eq!(ids(&outbox), vec!["c", "d"]);
+    }
+
+    #[test]
+    fn a_dropped_message_changes_nothing() {
+        let mut outbox = Outbox::new(2, 4096);
+        queued(outbox.push(message("a", MessagePriority::Low)));
+        queued(outbox.push(message("b", MessagePriority::High)));
+
+        // Evicting "a" would not be enough
+        let mut large = message("c", MessagePriority::Normal);
+        large.payload = "x".repeat(4000);
+        assert_eq!(outbox.push(large), PushOutcome::Dropped);
+        assert_eq!(ids(&outbox), vec!["a", "b"]);
+
+        let mut too_large = message("d", MessagePriority::High);
+        too_large.payload = "x".repeat(4096);
+        assert_eq!(outbox.push(too_large), PushOutcome::Dropped);
+        assert_eq!(Outbox::new(0, 4096).push(message("e", MessagePriority::High)), PushOutcome::Dropped);
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut outbox = Outbox::new(8, 4096);
+        queued(outbox.push(message("a", MessagePriority::Normal)));
+        assert_eq!(outbox.push(message("a", MessagePriority::Normal)), PushOutcome::Duplicate);
+
+        // Still a duplicate once sent, until enough other messages were sent
+        outbox.pop_sent();
+        assert_eq!(outbox.push(message("a", MessagePriority::Normal)), PushOutcome::Duplicate);
+
+        outbox.mark_sent("b");
+        assert_eq!(outbox.push(message("b", MessagePriority::Normal)), PushOutcome::Duplicate);
+
+        for i in 0..RECENTLY_SENT {
+            outbox.mark_sent(&format!("other-{}", i));
+        }
+        queued(outbox.push(message("a", MessagePriority::Normal)));
+    }
+
+    #[test]
+    fn keeps_the_messages_in_flight_until_acknowledged() {
+        let mut outbox = Outbox::new(8, 4096);
+        queued(outbox.push(message("a", MessagePriority::Normal)));
+        queued(outbox.push(message("b", MessagePriority::Normal)));
+
+        outbox.set_in_flight(7);
+        assert_eq!(outbox.front().unwrap().id, "b");
+        outbox.set_in_flight(8);
+        assert_eq!(outbox.front(), None);
+        assert_eq!((outbox.len(), outbox.in_flight()), (2, 2));
+
+        // Acknowledged out of order, and only once
+        assert_eq!(outbox.acknowledge(8).unwrap().id, "b");
+        assert_eq!(outbox.acknowledge(8), None);
+        assert_eq!(outbox.acknowledge(42), None);
+        assert_eq!(ids(&outbox), vec!["a"]);
+
+        assert_eq!(outbox.acknowledge(7).unwrap().id, "a");
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.push(message("a", MessagePriority::Normal)), PushOutcome::Duplicate);
+    }
+
+    #[test]
+    fn requeues_the_messages_in_flight_in_their_place() {
+        let mut outbox = Outbox::new(8, 4096);
+        for id in ["a", "b", "c"] {
+            queued(outbox.push(message(id, MessagePriority::Normal)));
+        }
+        outbox.set_in_flight(1);
+        outbox.set_in_flight(2);
+
+        // The client dropped "b"
+        assert!(outbox.requeue(2));
+        assert!(!outbox.requeue(2));
+        assert_eq!(outbox.front().unwrap().id, "b");
+
+        // The client is gone
+        outbox.set_in_flight(3);
+        assert_eq!(outbox.requeue_in_flight(), 2);
+        assert_eq!(outbox.in_flight(), 0);
+        assert_eq!(outbox.front().unwrap().id, "a");
+        assert_eq!(outbox.acknowledge(1), None);
+        assert_eq!(ids(&outbox), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn does_not_evict_the_messages_in_flight() {
+        let mut outbox = Outbox::new(2, 4096);
+        queued(outbox.push(message("telemetry-1", MessagePriority::Low)));
+        queued(outbox.push(message("telemetry-2", MessagePriority::Low)));
+        outbox.set_in_flight(1);
+
+        assert_eq!(queued(outbox.push(message("state-1", MessagePriority::Normal))), vec!["telemetry-2"]);
+        outbox.set_in_flight(2);
+        assert_eq!(outbox.push(message("report-1", MessagePriority::High)), PushOutcome::Dropped);
+
+        outbox.acknowledge(1);
+        queued(outbox.push(message("report-1", MessagePriority::High)));
+        assert_eq!(ids(&outbox), vec!["state-1", "report-1"]);
+    }
+
+    #[test]
+    fn restores_the_persisted_messages() {
+        let mut outbox = Outbox::new(8, 4096);
+        for (id, priority) in [("a", MessagePriority::Low), ("b", MessagePriority::High), ("c", MessagePriority::Normal)] {
+            queued(outbox.push(message(id, priority)));
+        }
+        // The messages in flight are persisted as well, the broker may never have received them
+        outbox.set_in_flight(1);
+
+        let persisted = serde_json::to_vec(&outbox.messages().collect::<Vec<_>>()).unwrap();
+        let messages: Vec<QueuedMessage> = serde_json::from_slice(&persisted).unwrap();
+
+        let mut restored = Outbox::new(8, 4096);
+        restored.restore(messages.clone());
+        assert_eq!(restored.messages().cloned().collect::<Vec<_>>(), messages);
+        assert_eq!(restored.in_flight(), 0);
+        assert_eq!(restored.front().unwrap().id, "a");
+
+        // Within the bounds of the new outbox, duplicates dropped
+        let mut smaller = Outbox::new(2, 4096);
+        smaller.restore([messages.clone(), messages].concat());
+        assert_eq!(ids(&smaller), vec!["b", "c"]);
+    }
+}
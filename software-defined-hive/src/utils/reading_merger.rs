@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::state::sensors::SensorReadings;
+
+/// Upper bound of the samples buffered per quantity while waiting for a weight reading
+const MAX_BUFFERED_SAMPLES: usize = 256;
+
+/// A payload with either a single sample or a batch of them, e.g. `{...}` or `[{...}, {...}]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Batch<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Batch<T> {
+    pub fn into_vec(self) -> Vec<T> {
+        match self {
+            Batch::One(sample) => vec![sample],
+            Batch::Many(samples) => samples,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemperatureLocation {
+    /// Inside the hive - `SensorReadings::temperature_x10`
+    #[default]
+    Internal,
+    /// Outside the hive - `SensorReadings::external_temperature_x10`
+    External,
+}
+
+/// Temperature reported on its own by an external sensor node
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TemperatureSample {
+    #[serde(default)]
+    pub location: TemperatureLocation,
+    pub temperature_x10: i16,
+    pub timestamp_s: u64,
+}
+
+/// Humidity reported on its own by an external sensor node
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct HumiditySample {
+    pub humidity_x10: u16,
+    pub timestamp_s: u64,
+}
+
+/// Merges the temperature and humidity samples reported separately (e.g. by the nodes behind a LoRa or BLE gateway) into the weight readings.
+///
+/// The weight drives the controller so samples are buffered until a weight reading arrives. A reading gets the sample closest
+/// to its timestamp that is within the tolerance, the quantities the weight sensor reported itself are kept as they are
+pub struct SensorReadingMerger {
+    tolerance_s: u64,
+    internal_temperatures: VecDeque<(u64, i16)>,
+    external_temperatures: VecDeque<(u64, i16)>,
+    humidities: VecDeque<(u64, u16)>,
+    /// Timestamp of the latest reading merged, the batches must not go back in time
+    last_merged_s: Option<u64>,
+}
+
+impl SensorReadingMerger {
+    pub fn new(tolerance_s: u64) -> Self {
+        Self {
+            tolerance_s,
+            internal_temperatures: VecDeque::new(),
+            external_temperatures: VecDeque::new(),
+            humidities: VecDeque::new(),
+            last_merged_s: None,
+        }
+    }
+
+    pub fn push_temperatures(&mut self, samples: impl IntoIterator<Item = TemperatureSample>) {
+        for sample in samples {
+            let buffer = match sample.location {
+                TemperatureLocation::Internal => &mut self.internal_temperatures,
+                TemperatureLocation::External => &mut self.external_temperatures,
+            };
+            push_bounded(buffer, (sample.timestamp_s, sample.temperature_x10));
+        }
+    }
+
+    pub fn push_humidities(&mut self, samples: impl IntoIterator<Item = HumiditySample>) {
+        for sample in samples {
+            push_bounded(&mut self.humidities, (sample.timestamp_s, sample.humidity_x10));
+        }
+    }
+
+    /// Fills the readings in with the buffered samples, returns them oldest first.
+    /// Readings older than the ones already merged (a batch delayed by the gateway) are dropped, the controller would reject them
+    pub fn merge(&mut self, readings: impl IntoIterator<Item = SensorReadings>) -> Vec<SensorReadings> {
+        let mut readings: Vec<SensorReadings> = readings.into_iter().collect();
+        readings.sort_by_key(|reading| reading.timestamp_s);
+
+        if let Some(last_merged_s) = self.last_merged_s {
+            let received = readings.len();
+            readings.retain(|reading| reading.timestamp_s >= last_merged_s);
+            if readings.len() < received {
+                warn!("Dropped {} sensor readings older than {}s", received - readings.len(), last_merged_s);
+            }
+        }
+
+        for reading in readings.iter_mut() {
+            let timestamp_s = reading.timestamp_s;
+
+            reading.temperature_x10 = reading.temperature_x10.or_else(|| self.closest(&self.internal_temperatures, timestamp_s));
+            reading.external_temperature_x10 = reading.external_temperature_x10.or_else(|| self.closest(&self.external_temperatures, timestamp_s));
+            reading.humidity_x10 = reading.humidity_x10.or_else(|| self.closest(&self.humidities, timestamp_s));
+        }
+
+        // Samples too old for the latest reading cannot match any later one
+        if let Some(latest) = readings.last() {
+            let oldest_s = latest.timestamp_s.saturating_sub(self.tolerance_s);
+            self.internal_temperatures.retain(|(timestamp_s, _)| *timestamp_s >= oldest_s);
+            self.external_temperatures.retain(|(timestamp_s, _)| *timestamp_s >= oldest_s);
+            self.humidities.retain(|(timestamp_s, _)| *timestamp_s >= oldest_s);
+            self.last_merged_s = Some(latest.timestamp_s);
+        }
+
+        readings
+    }
+
+    fn closest<T: Copy>(&self, samples: &VecDeque<(u64, T)>, timestamp_s: u64) -> Option<T> {
+        samples
+            .iter()
+            .map(|(sample_s, value)| (sample_s.abs_diff(timestamp_s), *value))
+            .filter(|(distance_s, _)| *distance_s <= self.tolerance_s)
+            .min_by_key(|(distance_s, _)| *distance_s)
+            .map(|(_, value)| value)
+    }
+}
+
+fn push_bounded<T>(buffer: &mut VecDeque<T>, sample: T) {
+    if buffer.len() >= MAX_BUFFERED_SAMPLES {
+        buffer.pop_front();
+    }
+    buffer.push_back(sample);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: u64 = 1_738_281_600;
+
+    fn weight(timestamp_s: u64) -> SensorReadings {
+        SensorReadings { weight_g: 6000, timestamp_s, ..Default::default() }
+    }
+
+    fn temperature(location: TemperatureLocation, temperature_x10: i16, timestamp_s: u64) -> TemperatureSample {
+        TemperatureSample { location, temperature_x10, timestamp_s }
+    }
+
+    #[test]
+    fn readings_get_the_closest_samples_within_the_tolerance() {
+        let mut merger = SensorReadingMerger::new(60);
+        merger.push_temperatures([
+            temperature(TemperatureLocation::Internal, 340, T - 20),
+            temperature(TemperatureLocation::Internal, 345, T + 5),
+            temperature(TemperatureLocation::External, 120, T + 61),
+        ]);
+        merger.push_humidities([HumiditySample { humidity_x10: 550, timestamp_s: T - 60 }]);
+
+        let merged = merger.merge([weight(T)]);
+
+        assert_eq!(merged[0].temperature_x10, Some(345));
+        assert_eq!(merged[0].external_temperature_x10, None);
+        assert_eq!(merged[0].humidity_x10, Some(550));
+    }
+
+    #[test]
+    fn quantities_of_the_weight_sensor_are_kept() {
+        let mut merger = SensorReadingMerger::new(60);
+        merger.push_temperatures([temperature(TemperatureLocation::Internal, 345, T)]);
+
+        let merged = merger.merge([SensorReadings { temperature_x10: Some(300), ..weight(T) }]);
+
+        assert_eq!(merged[0].temperature_x10, Some(300));
+    }
+
+    #[test]
+    fn unmatched_samples_wait_for_a_later_reading() {
+        let mut merger = SensorReadingMerger::new(60);
+        merger.push_temperatures([temperature(TemperatureLocation::External, 120, T + 100)]);
+
+        assert_eq!(merger.merge([weight(T)])[0].external_temperature_x10, None);
+        assert_eq!(merger.merge([weight(T + 90)])[0].external_temperature_x10, Some(120));
+
+        // Too old for the latest reading, it cannot match any later one
+        merger.merge([weight(T + 161)]);
+        assert!(merger.external_temperatures.is_empty());
+    }
+
+    #[test]
+    fn single_samples_and_batches_are_accepted() {
+        let one: Batch<TemperatureSample> = serde_json::from_str(r#"{"temperature_x10": 345, "timestamp_s": 1738281600}"#).unwrap();
+        let many: Batch<HumiditySample> =
+            serde_json::from_str(r#"[{"humidity_x10": 550, "timestamp_s": 1738281600}, {"humidity_x10": 560, "timestamp_s": 1738281630}]"#).unwrap();
+
+        let one = one.into_vec();
+        assert_eq!(one.len(), 1);
+        assert_eq!(one[0].location, TemperatureLocation::Internal);
+        assert_eq!(many.into_vec().len(), 2);
+    }
+
+    #[test]
+    fn batches_are_merged_oldest_first_and_delayed_ones_are_dropped() {
+        let mut merger = SensorReadingMerger::new(60);
+        let merged = merger.merge([weight(T + 60), weight(T), weight(T + 30)]);
+        assert_eq!(merged.iter().map(|reading| reading.timestamp_s).collect::<Vec<_>>(), vec![T, T + 30, T + 60]);
+
+        // The gateway delivers a batch late, only its readings after the ones already merged go through
+        let merged = merger.merge([weight(T + 90), weight(T + 10), weight(T + 60)]);
+        assert_eq!(merged.iter().map(|reading| reading.timestamp_s).collect::<Vec<_>>(), vec![T + 60, T + 90]);
+    }
+}
@@ -1,27 +1,346 @@
-use crate::state::sensors::SensorReadings;
-use crate::state::traits::{HumiditySensor, SensorError, TemperatureSensor, WeightSensor};
-
-pub struct SensorDataAggregator<Weight, InternalTemperature, ExternalTemperature, Humidity> {
-    weight: Weight,
-    internal_temp: InternalTemperature,
-    external_temp: ExternalTemperature,
-    humidity: Humidity,
-}
-
-impl<Weight, InternalTemperature, ExternalTemperature, Humidity> SensorDataAggregator<Weight, InternalTemperature, ExternalTemperature, Humidity>
-where
-    Weight: WeightSensor,
-    InternalTemperature: TemperatureSensor,
-    ExternalTemperature: TemperatureSensor,
-    Humidity: HumiditySensor,
-{
-    pub fn aggregate_sensor_readings(&mut self, timestamp_s: u64) -> Result<SensorReadings, SensorError> {
-        Ok(SensorReadings {
-            weight_g: self.weight.read_grams()?,
-            temperature_x10: Some(self.internal_temp.read_celsius_x10()?),
-            external_temperature_x10: Some(self.external_temp.read_celsius_x10()?),
-            humidity_x10: Some(self.humidity.read_percent_x10()?),
-            timestamp_s,
+use std::collections::BTreeMap;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::state::sensors::{AcousticBands, BeeTraffic, SensorReadings};
+use crate::state::traits::{AcousticSensor, BeeCounter, HumiditySensor, PressureSensor, SensorError, TemperatureSensor, WeightSensor};
+
+/// What a sensor measures in the hive. A hive has at most one sensor per role
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorRole {
+    Weight,
+    InternalTemperature,
+    ExternalTemperature,
+    Humidity,
+    Pressure,
+    Acoustic,
+    BeeTraffic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorUnit {
+    Grams,
+    CelsiusX10,
+    PercentX10,
+    HpaX10,
+    /// Energy per band of `ACOUSTIC_BAND_EDGES_HZ`
+    BandEnergy,
+    /// Bees in/out
+    BeeCount,
+}
+
+/// A driver of any of the sensor traits
+pub enum SensorDriver {
+    Weight(Box<dyn WeightSensor + Send>),
+    Temperature(Box<dyn TemperatureSensor + Send>),
+    Humidity(Box<dyn HumiditySensor + Send>),
+    Pressure(Box<dyn PressureSensor + Send>),
+    Acoustic(Box<dyn AcousticSensor + Send>),
+    BeeCounter(Box<dyn BeeCounter + Send>),
+}
+
+impl SensorDriver {
+    /// The unit the driver reports in
+    pub fn unit(&self) -> SensorUnit {
+        match self {
+            SensorDriver::Weight(_) => SensorUnit::Grams,
+            SensorDriver::Temperature(_) => SensorUnit::CelsiusX10,
+            SensorDriver::Humidity(_) => SensorUnit::PercentX10,
+            SensorDriver::Pressure(_) => SensorUnit::HpaX10,
+            SensorDriver::Acoustic(_) => SensorUnit::BandEnergy,
+            SensorDriver::BeeCounter(_) => SensorUnit::BeeCount,
+        }
+    }
+
+    fn read(&mut self) -> Result<SensorValue, SensorError> {
+        Ok(match self {
+            SensorDriver::Weight(sensor) => SensorValue::Scalar(sensor.read_grams()? as i64),
+            SensorDriver::Temperature(sensor) => SensorValue::Scalar(sensor.read_celsius_x10()? as i64),
+            SensorDriver::Humidity(sensor) => SensorValue::Scalar(sensor.read_percent_x10()? as i64),
+            SensorDriver::Pressure(sensor) => SensorValue::Scalar(sensor.read_hpa_x10()? as i64),
+            SensorDriver::Acoustic(sensor) => SensorValue::Acoustic(sensor.read_band_energies()?),
+            SensorDriver::BeeCounter(sensor) => SensorValue::BeeTraffic(sensor.read_counts()?),
         })
     }
 }
+
+/// How a sensor is registered: its role in the hive, the unit it reports in and how often it is sampled
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SensorRegistration {
+    pub role: SensorRole,
+    pub unit: SensorUnit,
+    /// The sensor is read at most once per interval (seconds), 0 reads it every time
+    pub interval_s: u64,
+    /// A sample older than this (seconds) is left out of the map, e.g. a few intervals, so that a sensor that stopped
+    /// reading goes missing instead of repeating its last value
+    pub max_age_s: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SensorValue {
+    Scalar(i64),
+    Acoustic(AcousticBands),
+    BeeTraffic(BeeTraffic),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SensorSample {
+    pub value: SensorValue,
+    pub unit: SensorUnit,
+    /// When the sensor was actually read - it can be older than the map's timestamp_s for slowly sampled sensors
+    pub timestamp_s: u64,
+}
+
+/// The latest sample of every registered sensor that has been read successfully within its `max_age_s`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SensorReadingMap {
+    pub timestamp_s: u64,
+    pub readings: BTreeMap<SensorRole, SensorSample>,
+}
+
+impl SensorReadingMap {
+    /// The map as the `SensorReadings` the controller works with, `None` without a weight sample
+    pub fn to_sensor_readings(&self) -> Option<SensorReadings> {
+        let scalar = |role: SensorRole| match self.readings.get(&role).map(|sample| sample.value) {
+            Some(SensorValue::Scalar(value)) => Some(value),
+            _ => None,
+        };
+
+        Some(SensorReadings {
+            weight_g: scalar(SensorRole::Weight)? as u32,
+            temperature_x10: scalar(SensorRole::InternalTemperature).map(|value| value as i16),
+            external_temperature_x10: scalar(SensorRole::ExternalTemperature).map(|value| value as i16),
+            humidity_x10: scalar(SensorRole::Humidity).map(|value| value as u16),
+            pressure_hpa_x10: scalar(SensorRole::Pressure).map(|value| value as u16),
+            acoustic_bands: match self.readings.get(&SensorRole::Acoustic).map(|sample| sample.value) {
+                Some(SensorValue::Acoustic(bands)) => Some(bands),
+                _ => None,
+            },
+            bee_traffic: match self.readings.get(&SensorRole::BeeTraffic).map(|sample| sample.value) {
+                Some(SensorValue::BeeTraffic(traffic)) => Some(traffic),
+                _ => None,
+            },
+            timestamp_s: self.timestamp_s,
+        })
+    }
+}
+
+struct RegisteredSensor {
+    registration: SensorRegistration,
+    driver: SensorDriver,
+    last_sample: Option<SensorSample>,
+}
+
+/// Reads the hive's sensors into a `SensorReadingMap`.
+///
+/// Sensors are registered at runtime so a hive can have any subset of them (e.g. no humidity sensor) and each is sampled at its own interval.
+/// A sensor that fails to read is logged and its previous sample is reported until it is older than `max_age_s`
+#[derive(Default)]
+pub struct SensorDataAggregator {
+    sensors: BTreeMap<SensorRole, RegisteredSensor>,
+}
+
+impl SensorDataAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, registration: SensorRegistration, driver: SensorDriver) -> Result<(), String> {
+        let expected_unit = match registration.role {
+            SensorRole::Weight => SensorUnit::Grams,
+            SensorRole::InternalTemperature | SensorRole::ExternalTemperature => SensorUnit::CelsiusX10,
+            SensorRole::Humidity => SensorUnit::PercentX10,
+            SensorRole::Pressure => SensorUnit::HpaX10,
+            SensorRole::Acoustic => SensorUnit::BandEnergy,
+            SensorRole::BeeTraffic => SensorUnit::BeeCount,
+        };
+
+        if registration.unit != expected_unit || driver.unit() != expected_unit {
+            return Err(format!(
+                "A {:?} sensor has to report in {:?}, got {:?} from a driver reporting in {:?}",
+                registration.role, expected_unit, registration.unit, driver.unit()
+            ));
+        }
+
+        if self.sensors.contains_key(&registration.role) {
+            return Err(format!("A {:?} sensor is already registered", registration.role));
+        }
+
+        self.sensors.insert(registration.role, RegisteredSensor {
+            registration,
+            driver,
+            last_sample: None,
+        });
+        Ok(())
+    }
+
+    pub fn unregister(&mut self, role: SensorRole) -> Option<SensorDriver> {
+        self.sensors.remove(&role).map(|sensor| sensor.driver)
+    }
+
+    pub fn registrations(&self) -> impl Iterator<Item = &SensorRegistration> {
+        self.sensors.values().map(|sensor| &sensor.registration)
+    }
+
+    /// Reads the sensors that are due at `timestamp_s` and returns the latest sample of every sensor, unless it is too old
+    pub fn aggregate_sensor_readings(&mut self, timestamp_s: u64) -> SensorReadingMap {
+        let mut map = SensorReadingMap {
+            timestamp_s,
+            readings: BTreeMap::new(),
+        };
+
+        for (role, sensor) in self.sensors.iter_mut() {
+            let due = sensor.last_sample.is_none_or(|sample| {
+                timestamp_s.saturating_sub(sample.timestamp_s) >= sensor.registration.interval_s
+            });
+
+            if due {
+                match sensor.driver.read() {
+                    Ok(value) => {
+                        sensor.last_sample = Some(SensorSample {
+                            value,
+                            unit: sensor.registration.unit,
+                            timestamp_s,
+                        });
+                    }
+                    Err(e) => warn!("Failed to read the {:?} sensor: {}", role, e),
+                }
+            }
+
+            let fresh = sensor.last_sample.filter(|sample| {
+                timestamp_s.saturating_sub(sample.timestamp_s) <= sensor.registration.max_age_s
+            });
+            if let Some(sample) = fresh {
+                map.readings.insert(*role, sample);
+            }
+        }
+
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    struct Scale(u32);
+
+    impl WeightSensor for Scale {
+        fn read_grams(&mut self) -> Result<u32, SensorError> {
+            Ok(self.0)
+        }
+    }
+
+    struct Barometer(u16);
+
+    impl PressureSensor for Barometer {
+        fn read_hpa_x10(&mut self) -> Result<u16, SensorError> {
+            Ok(self.0)
+        }
+    }
+
+    struct Counter(BeeTraffic);
+
+    impl BeeCounter for Counter {
+        fn read_counts(&mut self) -> Result<BeeTraffic, SensorError> {
+            Ok(self.0)
+        }
+    }
+
+    /// A scale whose load cell can be disconnected while it is registered
+    struct SharedScale(Arc<Mutex<Option<u32>>>);
+
+    impl WeightSensor for SharedScale {
+        fn read_grams(&mut self) -> Result<u32, SensorError> {
+            self.0.lock().unwrap().ok_or_else(|| SensorError::new(ErrorKind::NotConnected, "disconnected"))
+        }
+    }
+
+    fn registration(role: SensorRole, unit: SensorUnit) -> SensorRegistration {
+        SensorRegistration { role, unit, interval_s: 0, max_age_s: 120 }
+    }
+
+    #[test]
+    fn any_subset_of_the_sensors() {
+        let traffic = BeeTraffic { bees_in: 412, bees_out: 389 };
+
+        let mut aggregator = SensorDataAggregator::new();
+        aggregator.register(registration(SensorRole::Weight, SensorUnit::Grams), SensorDriver::Weight(Box::new(Scale(4200)))).unwrap();
+        aggregator.register(registration(SensorRole::Pressure, SensorUnit::HpaX10), SensorDriver::Pressure(Box::new(Barometer(10132)))).unwrap();
+        aggregator.register(registration(SensorRole::BeeTraffic, SensorUnit::BeeCount), SensorDriver::BeeCounter(Box::new(Counter(traffic)))).unwrap();
+
+        let readings = aggregator.aggregate_sensor_readings(60).to_sensor_readings().unwrap();
+        assert_eq!(readings.weight_g, 4200);
+        assert_eq!(readings.pressure_hpa_x10, Some(10132));
+        assert_eq!(readings.bee_traffic, Some(traffic));
+        assert_eq!(readings.temperature_x10, None);
+        assert_eq!(readings.humidity_x10, None);
+        assert_eq!(readings.acoustic_bands, None);
+    }
+
+    #[test]
+    fn no_readings_without_a_weight_sensor() {
+        let mut aggregator = SensorDataAggregator::new();
+        aggregator.register(registration(SensorRole::Pressure, SensorUnit::HpaX10), SensorDriver::Pressure(Box::new(Barometer(10132)))).unwrap();
+
+        assert!(aggregator.aggregate_sensor_readings(60).to_sensor_readings().is_none());
+    }
+
+    #[test]
+    fn rejects_mismatched_units_and_duplicate_roles() {
+        let mut aggregator = SensorDataAggregator::new();
+        assert!(aggregator.register(registration(SensorRole::Pressure, SensorUnit::Grams), SensorDriver::Weight(Box::new(Scale(4200)))).is_err());
+        assert!(aggregator.register(registration(SensorRole::Pressure, SensorUnit::HpaX10), SensorDriver::Weight(Box::new(Scale(4200)))).is_err());
+
+        aggregator.register(registration(SensorRole::Weight, SensorUnit::Grams), SensorDriver::Weight(Box::new(Scale(4200)))).unwrap();
+        assert!(aggregator.register(registration(SensorRole::Weight, SensorUnit::Grams), SensorDriver::Weight(Box::new(Scale(4300)))).is_err());
+    }
+
+    #[test]
+    fn a_failed_sensor_goes_missing_after_its_max_age() {
+        let grams = Arc::new(Mutex::new(Some(4200)));
+        let mut aggregator = SensorDataAggregator::new();
+        let registration = SensorRegistration { role: SensorRole::Weight, unit: SensorUnit::Grams, interval_s: 30, max_age_s: 90 };
+        aggregator.register(registration, SensorDriver::Weight(Box::new(SharedScale(Arc::clone(&grams))))).unwrap();
+
+        assert_eq!(aggregator.aggregate_sensor_readings(0).to_sensor_readings().unwrap().weight_g, 4200);
+
+        *grams.lock().unwrap() = None;
+        let map = aggregator.aggregate_sensor_readings(60);
+        assert_eq!(map.readings[&SensorRole::Weight].timestamp_s, 0);
+        assert!(map.to_sensor_readings().is_some());
+        assert!(aggregator.aggregate_sensor_readings(90).to_sensor_readings().is_some());
+
+        // A dead load cell must not keep the hive looking fresh
+        assert!(aggregator.aggregate_sensor_readings(91).to_sensor_readings().is_none());
+
+        *grams.lock().unwrap() = Some(4300);
+        assert_eq!(aggregator.aggregate_sensor_readings(120).to_sensor_readings().unwrap().weight_g, 4300);
+    }
+
+    #[test]
+    fn samples_slow_sensors_at_their_interval() {
+        let grams = Arc::new(Mutex::new(Some(4200)));
+        let mut aggregator = SensorDataAggregator::new();
+        let registration = SensorRegistration { role: SensorRole::Weight, unit: SensorUnit::Grams, interval_s: 60, max_age_s: 180 };
+        aggregator.register(registration, SensorDriver::Weight(Box::new(SharedScale(Arc::clone(&grams))))).unwrap();
+
+        aggregator.aggregate_sensor_readings(0);
+        *grams.lock().unwrap() = Some(4300);
+
+        // Not due yet, the sample of the previous read is kept
+        let map = aggregator.aggregate_sensor_readings(30);
+        assert_eq!(map.readings[&SensorRole::Weight].value, SensorValue::Scalar(4200));
+        assert_eq!(map.readings[&SensorRole::Weight].timestamp_s, 0);
+
+        let map = aggregator.aggregate_sensor_readings(60);
+        assert_eq!(map.readings[&SensorRole::Weight].value, SensorValue::Scalar(4300));
+    }
+}
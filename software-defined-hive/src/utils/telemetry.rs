@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+
+use crate::controller::controller::HiveStatus;
+use crate::state::actuators::HoneyCellDisplacerFault;
+use crate::state::hive::{FaultReason, HiveState};
+use crate::state::policy::interlocks::InterlockTrip;
+use crate::state::policy::telemetry::{Downsampling, TelemetryConfigs, TelemetryDeadband};
+use crate::state::sensors::SensorReadings;
+
+/// Health of the device itself, each value is `None` where the platform cannot tell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemStats {
+    /// Signal strength of the Wi-Fi access point (dBm)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi_dbm: Option<i8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_heap_bytes: Option<u32>,
+    /// Lowest free heap since boot - a steadily falling value is a leak
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_free_heap_bytes: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connectivity: Option<ConnectivityStatus>,
+}
+
+/// Connection state of the hive as tracked by its connectivity supervisor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectivityStatus {
+    pub wifi_connected: bool,
+    pub mqtt_connected: bool,
+    /// Reconnections since boot
+    pub wifi_reconnects: u32,
+    pub mqtt_reconnects: u32,
+    /// How long the last outage lasted (seconds)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_outage_s: Option<u64>,
+}
+
+/// Sensor readings of a telemetry interval reduced according to the `Downsampling`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TelemetryReadings {
+    /// Weight as reported by the sensor (grams)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weight_g: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_x10: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_temperature_x10: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity_x10: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pressure_hpa_x10: Option<u16>,
+    /// Number of readings received in the interval
+    pub samples: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryMessage {
+    pub timestamp_s: u64,
+    pub state: HiveState,
+    pub readings: TelemetryReadings,
+    /// Latest weight after the filter chain (grams)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filtered_weight_g: Option<u32>,
+    /// `None` while the honey cell displacer is healthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actuator_fault: Option<HoneyCellDisplacerFault>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fault_reason: Option<FaultReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interlock: Option<InterlockTrip>,
+    pub system: SystemStats,
+}
+
+/// Running (sum, count, last) of one quantity over the interval
+#[derive(Debug, Clone, Copy, Default)]
+struct Accumulator {
+    sum: i64,
+    count: i64,
+    last: Option<i64>,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: Option<i64>) {
+        if let Some(value) = value {
+            self.sum += value;
+            self.count += 1;
+            self.last = Some(value);
+        }
+    }
+
+    fn reduce(&self, downsampling: Downsampling) -> Option<i64> {
+        match downsampling {
+            Downsampling::Average => (self.count > 0).then(|| self.sum / self.count),
+            Downsampling::Last => self.last,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ReadingWindow {
+    weight_g: Accumulator,
+    temperature_x10: Accumulator,
+    external_temperature_x10: Accumulator,
+    humidity_x10: Accumulator,
+    pressure_hpa_x10: Accumulator,
+    samples: u32,
+}
+
+/// Decides what telemetry to publish and when, the caller sends the messages over its transport.
+///
+/// Time is taken from the callers (the reading timestamps on the hive), like the controller does
+pub struct TelemetryPublisher {
+    configs: TelemetryConfigs,
+    window: ReadingWindow,
+    last_interval_at: Option<u64>,
+    last_published: Option<TelemetryMessage>,
+}
+
+impl TelemetryPublisher {
+    pub fn new(configs: TelemetryConfigs) -> Self {
+        Self {
+            configs,
+            window: ReadingWindow::default(),
+            last_interval_at: None,
+            last_published: None,
+        }
+    }
+
+    pub fn configs(&self) -> &TelemetryConfigs {
+        &self.configs
+    }
+
+    /// Applies the configs of an updated policy, the current interval carries on
+    pub fn configure(&mut self, configs: &TelemetryConfigs) {
+        self.configs = *configs;
+    }
+
+    /// Adds a reading to the current interval
+    pub fn observe(&mut self, reading: &SensorReadings) {
+        self.window.weight_g.add(Some(reading.weight_g as i64));
+        self.window.temperature_x10.add(reading.temperature_x10.map(i64::from));
+        self.window.external_temperature_x10.add(reading.external_temperature_x10.map(i64::from));
+        self.window.humidity_x10.add(reading.humidity_x10.map(i64::from));
+        self.window.pressure_hpa_x10.add(reading.pressure_hpa_x10.map(i64::from));
+        self.window.samples += 1;
+    }
+
+    /// Closes the interval once it has elapsed, returns the message to publish unless the deadband suppresses it.
+    /// A state change or a new fault closes it early, so it is meant to be polled more often than the interval
+    pub fn poll(&mut self, now_s: u64, status: &HiveStatus, system: SystemStats) -> Option<TelemetryMessage> {
+        if !self.configs.enabled {
+            return None;
+        }
+
+        let news = self.last_published.as_ref().is_some_and(|last| {
+            last.state != status.state || (status.fault_reason.is_some() && last.fault_reason != status.fault_reason)
+        });
+        if !news && self.last_interval_at.is_some_and(|last| now_s.saturating_sub(last) < self.configs.interval_s) {
+            return None;
+        }
+        self.last_interval_at = Some(now_s);
+
+        let downsampling = self.configs.downsampling;
+        let window = std::mem::take(&mut self.window);
+        let readings = TelemetryReadings {
+            weight_g: window.weight_g.reduce(downsampling).map(|value| value as u32),
+            temperature_x10: window.temperature_x10.reduce(downsampling).map(|value| value as i16),
+            external_temperature_x10: window.external_temperature_x10.reduce(downsampling).map(|value| value as i16),
+            humidity_x10: window.humidity_x10.reduce(downsampling).map(|value| value as u16),
+            pressure_hpa_x10: window.pressure_hpa_x10.reduce(downsampling).map(|value| value as u16),
+            samples: window.samples,
+        };
+
+        let message = TelemetryMessage {
+            timestamp_s: now_s,
+            state: status.state,
+            readings,
+            filtered_weight_g: status.last_weight_g,
+            actuator_fault: match status.fault_reason {
+                Some(FaultReason::Actuator { fault }) => Some(fault),
+                _ => None,
+            },
+            fault_reason: status.fault_reason,
+            interlock: status.interlock,
+            system,
+        };
+
+        if let (Some(deadband), Some(last)) = (&self.configs.deadband, &self.last_published)
+            && !changed(deadband, last, &message)
+        {
+            return None;
+        }
+
+        self.last_published = Some(message.clone());
+        Some(message)
+    }
+}
+
+fn changed(deadband: &TelemetryDeadband, last: &TelemetryMessage, message: &TelemetryMessage) -> bool {
+    let beyond = |previous: Option<i64>, current: Option<i64>, threshold: u64| match (previous, current) {
+        (Some(previous), Some(current)) => previous.abs_diff(current) > threshold,
+        (previous, current) => previous.is_some() != current.is_some(),
+    };
+    let (previous, current) = (&last.readings, &message.readings);
+
+    message.state != last.state
+        || message.fault_reason != last.fault_reason
+        // A reconnection since the last telemetry is news even if the readings did not change
+        || reconnects(message) != reconnects(last)
+        || message.timestamp_s.saturating_sub(last.timestamp_s) >= deadband.max_silence_s
+        || beyond(previous.weight_g.map(i64::from), current.weight_g.map(i64::from), deadband.weight_g as u64)
+        || beyond(previous.temperature_x10.map(i64::from), current.temperature_x10.map(i64::from), deadband.temperature_x10 as u64)
+        || beyond(previous.external_temperature_x10.map(i64::from), current.external_temperature_x10.map(i64::from), deadband.temperature_x10 as u64)
+        || beyond(previous.humidity_x10.map(i64::from), current.humidity_x10.map(i64::from), deadband.humidity_x10 as u64)
+}
+
+fn reconnects(message: &TelemetryMessage) -> Option<(u32, u32)> {
+    message.system.connectivity.map(|connectivity| (connectivity.wifi_reconnects, connectivity.mqtt_reconnects))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::policy::harvest::HarvestPolicyConfigs;
+
+    use super::*;
+
+    fn status(state: HiveState) -> HiveStatus {
+        HiveStatus {
+            state,
+            last_weight_g: Some(5200),
+            last_raw_weight_g: Some(5210),
+            stable_since: None,
+            drain_started_at: None,
+            verification_started_at: None,
+            fault_reason: None,
+            last_harvest_at: None,
+            interlock: None,
+            policy: HarvestPolicyConfigs::default(),
+        }
+    }
+
+    fn reading(weight_g: u32, timestamp_s: u64) -> SensorReadings {
+        SensorReadings { weight_g, temperature_x10: Some(340), timestamp_s, ..Default::default() }
+    }
+
+    fn publisher(deadband: Option<TelemetryDeadband>) -> TelemetryPublisher {
+        TelemetryPublisher::new(TelemetryConfigs { interval_s: 300, deadband, ..Default::default() })
+    }
+
+    #[test]
+    fn publishes_once_per_interval() {
+        let mut telemetry = publisher(None);
+        let monitoring = status(HiveState::Monitoring);
+
+        telemetry.observe(&reading(5000, 0));
+        assert_eq!(telemetry.poll(0, &monitoring, SystemStats::default()).unwrap().readings.weight_g, Some(5000));
+
+        telemetry.observe(&reading(5100, 100));
+        telemetry.observe(&reading(5300, 200));
+        assert!(telemetry.poll(299, &monitoring, SystemStats::default()).is_none());
+
+        let message = telemetry.poll(300, &monitoring, SystemStats::default()).unwrap();
+        assert_eq!(message.readings.weight_g, Some(5200));
+        assert_eq!(message.readings.samples, 2);
+    }
+
+    #[test]
+    fn downsamples_to_the_last_reading() {
+        let mut telemetry = TelemetryPublisher::new(TelemetryConfigs { downsampling: Downsampling::Last, ..Default::default() });
+        telemetry.observe(&reading(5100, 100));
+        telemetry.observe(&reading(5300, 200));
+
+        let message = telemetry.poll(300, &status(HiveState::Monitoring), SystemStats::default()).unwrap();
+        assert_eq!(message.readings.weight_g, Some(5300));
+    }
+
+    #[test]
+    fn a_state_change_is_published_right_away() {
+        let mut telemetry = publisher(Some(TelemetryDeadband::default()));
+        telemetry.poll(0, &status(HiveState::Monitoring), SystemStats::default()).unwrap();
+
+        assert!(telemetry.poll(10, &status(HiveState::Monitoring), SystemStats::default()).is_none());
+        assert_eq!(telemetry.poll(20, &status(HiveState::Candidate), SystemStats::default()).unwrap().state, HiveState::Candidate);
+
+        // The interval starts over
+        assert!(telemetry.poll(300, &status(HiveState::Candidate), SystemStats::default()).is_none());
+    }
+
+    #[test]
+    fn a_new_fault_is_published_right_away() {
+        let mut telemetry = publisher(None);
+        let mut faulted = status(HiveState::Fault);
+        faulted.fault_reason = Some(FaultReason::EmergencyStop);
+        telemetry.poll(0, &faulted, SystemStats::default()).unwrap();
+
+        faulted.fault_reason = Some(FaultReason::CellsNotClosed);
+        assert_eq!(telemetry.poll(5, &faulted, SystemStats::default()).unwrap().fault_reason, Some(FaultReason::CellsNotClosed));
+        assert!(telemetry.poll(10, &faulted, SystemStats::default()).is_none());
+    }
+
+    #[test]
+    fn the_deadband_suppresses_small_changes() {
+        let mut telemetry = publisher(Some(TelemetryDeadband::default()));
+        let monitoring = status(HiveState::Monitoring);
+
+        telemetry.observe(&reading(5000, 0));
+        telemetry.poll(0, &monitoring, SystemStats::default()).unwrap();
+
+        telemetry.observe(&reading(5040, 300));
+        assert!(telemetry.poll(300, &monitoring, SystemStats::default()).is_none());
+
+        telemetry.observe(&reading(5100, 600));
+        assert!(telemetry.poll(600, &monitoring, SystemStats::default()).is_some());
+
+        // Published after max_silence_s even when nothing changed
+        telemetry.observe(&reading(5100, 4200));
+        assert!(telemetry.poll(4200, &monitoring, SystemStats::default()).is_some());
+    }
+
+    #[test]
+    fn configure_applies_the_policy() {
+        let mut telemetry = publisher(None);
+        telemetry.configure(&TelemetryConfigs { enabled: false, ..Default::default() });
+
+        assert!(telemetry.poll(0, &status(HiveState::Monitoring), SystemStats::default()).is_none());
+    }
+}